@@ -1,4 +1,5 @@
-use common::queues::{BrokerClient, TelegramMessageTask};
+use common::queues::{BrokerClient, NotificationUpdateTask, TelegramMessageTask, Topic};
+use futures::FutureExt;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -6,19 +7,48 @@ use teloxide::{
     dispatching::UpdateFilterExt,
     prelude::*,
     types::ParseMode::MarkdownV2,
-    types::{KeyboardButton, KeyboardMarkup, Message},
+    types::{KeyboardButton, KeyboardMarkup, Message, MessageId},
+    utils::command::BotCommands,
+    utils::markdown::escape,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 
-use common::retry;
-use common::storage::Storage;
+use common::cfg::{WebhookCfg, WebhookMode};
+use common::i18n::{self, Language};
+use common::storage::{ChatRoute, Cipher, MailChange, Storage};
+use common::templates;
 use common::types::Error;
 
 use crate::cfg::TelegramBotCfg;
 
+use super::commands::{self, Command, CommandContext};
 use super::handlers;
+use super::sender::{self, Delivery, Outcome};
+use super::webhook;
 use std::pin::Pin;
 
+/// Consumer group shared by all bot instances
+const CONSUMER_GROUP: &str = "telegram_bot";
+/// How long ids of sent messages are kept to drop their redeliveries
+const DEDUPE_WINDOW: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// The send loop looks for new tasks at least this often
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Whether MarkdownV2 text has `~` markup, escaped ones do not count
+fn has_strikethrough(text: &str) -> bool {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '~' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
 #[derive(Clone)]
 pub struct TelegramBot {
     bot: Bot,
@@ -26,6 +56,13 @@ pub struct TelegramBot {
     running: Arc<AtomicBool>,
     broker: BrokerClient,
     tasks: Arc<RwLock<HashMap<uuid::Uuid, TelegramMessageTask>>>,
+    sent: Arc<RwLock<HashMap<uuid::Uuid, std::time::Instant>>>,
+    /// Wakes the send loop up when important task arrives
+    important_arrived: Arc<Notify>,
+    delivery: Delivery,
+    commands: CommandContext,
+    /// Updates come through it instead of long polling when set
+    webhook: Option<WebhookCfg>,
 }
 
 impl TelegramBot {
@@ -38,6 +75,20 @@ impl TelegramBot {
     ) -> TelegramBot {
         let token = cfg.bot.token.clone();
         let bot = Bot::new(token);
+        let sent: Arc<RwLock<_>> = Default::default();
+        let delivery = Delivery::new(
+            bot.clone(),
+            storage.clone(),
+            broker.clone(),
+            tasks.clone(),
+            sent.clone(),
+        );
+        let commands = CommandContext {
+            storage: storage.clone(),
+            cipher: Arc::new(Cipher::new(&cfg.storage)),
+            web_app_url: cfg.web_app_url.clone(),
+            tasks: tasks.clone(),
+        };
 
         TelegramBot {
             bot,
@@ -45,33 +96,69 @@ impl TelegramBot {
             running,
             broker,
             tasks,
+            sent,
+            important_arrived: Default::default(),
+            delivery,
+            commands,
+            webhook: cfg.bot.webhook.clone(),
         }
     }
 
     async fn fetch_all_endpoint(
         bot: Bot,
         msg: Message,
-        broker_client: BrokerClient,
+        storage: Pin<Arc<Storage>>,
         tasks: Arc<RwLock<HashMap<uuid::Uuid, TelegramMessageTask>>>,
+        delivery: Delivery,
     ) -> Result<(), Error> {
-        handlers::process_fetch_all_emails(bot, msg, broker_client, tasks).await?;
+        handlers::process_fetch_all_emails(bot, msg, storage, tasks, delivery).await?;
         Ok(())
     }
 
     pub async fn start_listener_thread(&self) {
-        let messages_handler = Update::filter_message().branch(
-            dptree::filter(|msg: Message| msg.text().eq(&Some("Fetch all emails")))
+        let messages_handler = Update::filter_message()
+            .branch(
+                dptree::entry()
+                    .filter_command::<Command>()
+                    .endpoint(commands::process_command),
+            )
+            .branch(
+                dptree::filter(|msg: Message| {
+                    msg.text()
+                        .is_some_and(|text| i18n::is_any("keyboard.fetch_all", text))
+                })
                 .endpoint(TelegramBot::fetch_all_endpoint),
-        );
+            );
+
+        if let Err(e) = self.bot.set_my_commands(Command::bot_commands()).await {
+            tracing::warn!("Failed to register bot commands: {}", e);
+        }
+        for lang in Language::ALL {
+            if let Err(e) = self
+                .bot
+                .set_my_commands(Command::localized(*lang))
+                .language_code(lang.as_str())
+                .await
+            {
+                tracing::warn!("Failed to register {} bot commands: {}", lang.as_str(), e);
+            }
+        }
 
         let storage = self.storage.clone();
         let bot_name = String::from("");
         let bot = self.bot.clone();
-        let broker = self.broker.clone();
         let tasks = self.tasks.clone();
+        let delivery = self.delivery.clone();
+        let commands = self.commands.clone();
 
-        Dispatcher::builder(bot, messages_handler)
-            .dependencies(dptree::deps![storage, broker, tasks, bot_name])
+        let mut dispatcher = Dispatcher::builder(bot, messages_handler)
+            .dependencies(dptree::deps![
+                storage.clone(),
+                tasks,
+                delivery,
+                bot_name,
+                commands
+            ])
             .default_handler(|upd| async move {
                 tracing::warn!("Unhandled update: {:?}", upd);
             })
@@ -80,17 +167,48 @@ impl TelegramBot {
                 "An error has occurred in the dispatcher",
             ))
             .enable_ctrlc_handler()
-            .build()
-            .dispatch()
-            .await;
+            .build();
+
+        let listener_error_handler =
+            LoggingErrorHandler::with_custom_text("An error from the update listener");
+        match &self.webhook {
+            None => dispatcher.dispatch().await,
+            Some(cfg) if cfg.mode == WebhookMode::Standalone => {
+                match webhook::standalone(self.bot.clone(), cfg).await {
+                    Ok(listener) => {
+                        dispatcher
+                            .dispatch_with_listener(listener, listener_error_handler)
+                            .await
+                    }
+                    Err(e) => tracing::error!("Failed to set up the webhook: {}", e),
+                }
+            }
+            Some(cfg) => match webhook::from_web_server(&self.bot, cfg, storage).await {
+                Ok(listener) => {
+                    dispatcher
+                        .dispatch_with_listener(listener, listener_error_handler)
+                        .await
+                }
+                Err(e) => tracing::error!("Failed to set up the webhook: {}", e),
+            },
+        }
     }
 
     pub async fn start_message_queue_listener_thread(&self) {
         let tm = self.tasks.clone();
+        let sent = self.sent.clone();
         let broker = self.broker.clone();
+        let important_arrived = self.important_arrived.clone();
+        let delivery = self.delivery.clone();
         tokio::spawn(async move {
             loop {
-                let mut rx = match broker.subscribe().await {
+                let mut rx = match broker
+                    .subscribe(
+                        CONSUMER_GROUP,
+                        &[Topic::TelegramMessage, Topic::TelegramEdit],
+                    )
+                    .await
+                {
                     Ok(rx) => rx,
                     Err(_) => return,
                 };
@@ -103,7 +221,36 @@ impl TelegramBot {
                         Some(msg) => match msg.payload {
                             common::queues::BrokerMessagePayload::Tasks(t) => match t {
                                 common::queues::Tasks::TelegramMessageTask(task) => {
-                                    tm.write().await.insert(msg.message_id, task);
+                                    if msg.attempt > 1 {
+                                        tracing::info!(
+                                            "Message {} delivered {} times",
+                                            msg.message_id,
+                                            msg.attempt
+                                        );
+                                    }
+                                    if sent.read().await.contains_key(&msg.message_id) {
+                                        // Ack was lost, the message must not be sent twice
+                                        if let Err(e) = broker.ack(msg.message_id).await {
+                                            tracing::error!(
+                                                "Failed to ack message with id {}: {}",
+                                                msg.message_id,
+                                                e
+                                            );
+                                        }
+                                        continue;
+                                    }
+                                    let important = task.important;
+                                    tm.write().await.entry(msg.message_id).or_insert(task);
+                                    if important {
+                                        important_arrived.notify_one();
+                                    }
+                                }
+                                common::queues::Tasks::NotificationUpdateTask(task) => {
+                                    // Waits for its chat slot on its own, new tasks keep coming meanwhile
+                                    let delivery = delivery.clone();
+                                    tokio::spawn(async move {
+                                        delivery.update(msg.message_id, &task).await
+                                    });
                                 }
                             },
                         },
@@ -117,67 +264,247 @@ impl TelegramBot {
         });
 
         loop {
-            let mut to_remove = Vec::new();
+            let mut preempted = false;
+            // When a chat skipped in this round may be sent to
+            let mut next_round: Option<tokio::time::Instant> = None;
 
             {
-                let lock = self.tasks.read().await;
-                let tasks: Vec<(uuid::Uuid, TelegramMessageTask)> = lock
+                // Not locked while sending, so tasks arriving meanwhile are not held up
+                let tasks: Vec<(uuid::Uuid, TelegramMessageTask)> = self
+                    .tasks
+                    .read()
+                    .await
                     .iter()
+                    .filter(|(_, task)| task.important || task.can_send_now())
                     .map(|(msg_id, task)| (*msg_id, task.clone()))
                     .collect();
-                for (msg_id, task) in tasks {
-                    if !task.important && !task.can_send_now() {
-                        continue;
+                // Important ones go first, routine mail waits behind them, chats take turns
+                for (msg_id, task) in sender::fair_order(tasks) {
+                    if !task.important && self.important_arrived.notified().now_or_never().is_some()
+                    {
+                        // Start over to send the important task ahead of the remaining routine ones
+                        preempted = true;
+                        break;
+                    }
+                    if let Outcome::NotReady(at) = self.delivery.send(msg_id, false).await {
+                        // Left for a later round, other chats are not held up waiting for it
+                        next_round = Some(next_round.map_or(at, |next| next.min(at)));
                     }
-
-                    match TelegramBot::send_markdown(&self.bot, task.to, &task.text).await {
-                        Err(e) => {
-                            tracing::error!("{}", e);
-                        }
-                        Ok(_) => {
-                            match retry! { self.broker.ack(msg_id).await } {
-                                Err(e) => {
-                                    tracing::error!(
-                                        "Failed to ack message with id {}: {}",
-                                        msg_id,
-                                        e
-                                    );
-                                }
-                                _ => {}
-                            };
-                            to_remove.push(msg_id);
-                        }
-                    };
                 }
             }
 
-            if !to_remove.is_empty() {
-                let mut map = self.tasks.write().await;
-                for delivery_tag in to_remove {
-                    map.remove(&delivery_tag);
-                }
-            }
+            self.sent
+                .write()
+                .await
+                .retain(|_, sent_at| sent_at.elapsed() < DEDUPE_WINDOW);
 
             if !self.running.load(Ordering::Relaxed) {
                 break;
             }
 
-            let _ = tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            if !preempted {
+                let wait = next_round.map_or(POLL_INTERVAL, |at| {
+                    at.saturating_duration_since(tokio::time::Instant::now())
+                        .min(POLL_INTERVAL)
+                });
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = self.important_arrived.notified() => {}
+                }
+            }
         }
     }
 
-    pub async fn send_markdown(bot: &Bot, user_id: UserId, text: &String) -> Result<(), Error> {
-        let reply_markup = KeyboardMarkup::new(vec![vec![KeyboardButton {
-            text: "Fetch all emails".into(),
-            request: None,
-        }]])
-        .resize_keyboard();
-        let chat_id: ChatId = user_id.into();
-        bot.send_message(chat_id, text)
+    /// Sends the text to the chat, or to the forum topic of the route
+    pub async fn send_markdown(
+        bot: &Bot,
+        chat: ChatRoute,
+        text: &String,
+        lang: Language,
+    ) -> Result<(), Error> {
+        TelegramBot::markdown_message(bot, chat, text, lang)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Sends the notification to its chat, without sound if the task is silent
+    pub async fn send_notification(
+        bot: &Bot,
+        task: &TelegramMessageTask,
+        lang: Language,
+    ) -> Result<Message, Error> {
+        let message = TelegramBot::markdown_message(bot, task.destination(), &task.text, lang)
+            .disable_notification(task.silent)
+            .send()
+            .await?;
+        Ok(message)
+    }
+
+    /// Deletes the notification of the removed mail, or marks it when the mail was read.
+    /// The removed one is marked too if Telegram does not let to delete it anymore
+    pub async fn update_notification(
+        bot: &Bot,
+        task: &NotificationUpdateTask,
+        lang: Language,
+    ) -> Result<(), Error> {
+        let notified = &task.notified;
+        let chat = notified.chat.chat();
+        let message_id = MessageId(notified.message_id);
+        if task.change == MailChange::Gone {
+            match bot.delete_message(chat, message_id).send().await {
+                Ok(_) => return Ok(()),
+                Err(e) => tracing::debug!("Could not delete notification, marking it: {}", e),
+            }
+        }
+
+        let mark = match task.change {
+            MailChange::Seen => i18n::tr(lang, "notification.read"),
+            MailChange::Gone => i18n::tr(lang, "notification.removed"),
+        };
+        // Struck through unless the text has strikethrough of its own, which can not nest
+        let struck = format!("~{}~", notified.text);
+        let text = match !has_strikethrough(&notified.text)
+            && templates::validate_markdown(&struck).is_ok()
+        {
+            true => format!("{}\n\n{}", struck, escape(mark)),
+            false => format!("{}\n\n{}", notified.text, escape(mark)),
+        };
+        bot.edit_message_text(chat, message_id, text)
             .parse_mode(MarkdownV2)
-            .reply_markup(reply_markup)
             .send()
             .await?;
         Ok(())
     }
+
+    /// The keyboard is shown in private chats only, in groups it would pop up for every member
+    fn markdown_message(
+        bot: &Bot,
+        chat: ChatRoute,
+        text: &String,
+        lang: Language,
+    ) -> <Bot as Requester>::SendMessage {
+        let mut request = bot.send_message(chat.chat(), text).parse_mode(MarkdownV2);
+        if chat.is_private() {
+            let reply_markup = KeyboardMarkup::new(vec![vec![KeyboardButton {
+                text: i18n::tr(lang, "keyboard.fetch_all").into(),
+                request: None,
+            }]])
+            .resize_keyboard();
+            request = request.reply_markup(reply_markup);
+        }
+        if let Some(thread) = chat.thread() {
+            request = request.message_thread_id(thread);
+        }
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_telegram::{self, Calls};
+    use common::storage::NotifiedMail;
+
+    /// Telegram Bot API recording the calls, deleting messages succeeds if `deletable`
+    async fn telegram(deletable: bool) -> (Bot, Calls) {
+        fake_telegram::start(move |method, body| match method {
+            "deletemessage" if !deletable => serde_json::json!({
+                "ok": false,
+                "error_code": 400,
+                "description": "Bad Request: message can't be deleted for everyone"
+            }),
+            "deletemessage" => fake_telegram::ok(serde_json::json!(true)),
+            _ => fake_telegram::ok(serde_json::json!({
+                "message_id": body["message_id"],
+                "date": 0,
+                "chat": { "id": body["chat_id"], "type": "private", "first_name": "Jane" },
+                "text": "edited"
+            })),
+        })
+        .await
+    }
+
+    fn update(text: &str, change: MailChange) -> NotificationUpdateTask {
+        NotificationUpdateTask {
+            to: UserId(42),
+            notified: NotifiedMail {
+                mail: common::storage::MailRef {
+                    account: "jane@example.com".into(),
+                    folder: "INBOX".into(),
+                    id: "7".into(),
+                    uid_validity: Some(1),
+                },
+                chat: ChatRoute {
+                    chat_id: 42,
+                    thread_id: None,
+                },
+                message_id: 100,
+                text: text.into(),
+                sent_at: 0,
+            },
+            change,
+        }
+    }
+
+    #[tokio::test]
+    async fn removed_mail_notification_is_deleted() {
+        let (bot, calls) = telegram(true).await;
+        let task = update("*Jane Doe*\nReport", MailChange::Gone);
+        TelegramBot::update_notification(&bot, &task, Language::En)
+            .await
+            .unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, "deletemessage");
+        assert_eq!(calls[0].1["chat_id"], 42);
+        assert_eq!(calls[0].1["message_id"], 100);
+    }
+
+    #[tokio::test]
+    async fn notification_is_struck_through_if_it_can_not_be_deleted() {
+        let (bot, calls) = telegram(false).await;
+        let task = update("*Jane Doe*\nReport", MailChange::Gone);
+        TelegramBot::update_notification(&bot, &task, Language::En)
+            .await
+            .unwrap();
+
+        let calls = calls.lock().unwrap();
+        let methods: Vec<&str> = calls.iter().map(|(method, _)| method.as_str()).collect();
+        assert_eq!(methods, ["deletemessage", "editmessagetext"]);
+        let edit = &calls[1].1;
+        assert_eq!(edit["message_id"], 100);
+        assert_eq!(edit["parse_mode"], "MarkdownV2");
+        assert_eq!(edit["text"], "~*Jane Doe*\nReport~\n\n🗑 Deleted");
+    }
+
+    #[tokio::test]
+    async fn read_mail_notification_is_marked() {
+        let (bot, calls) = telegram(true).await;
+        let task = update("*Jane Doe*\nReport", MailChange::Seen);
+        TelegramBot::update_notification(&bot, &task, Language::En)
+            .await
+            .unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, "editmessagetext");
+        assert_eq!(calls[0].1["text"], "~*Jane Doe*\nReport~\n\n✓ Read");
+    }
+
+    #[tokio::test]
+    async fn own_strikethrough_is_not_nested() {
+        assert!(!has_strikethrough("Jane\\~Doe"));
+
+        let (bot, calls) = telegram(true).await;
+        let task = update("~Jane Doe~\nReport", MailChange::Seen);
+        TelegramBot::update_notification(&bot, &task, Language::En)
+            .await
+            .unwrap();
+        assert_eq!(
+            calls.lock().unwrap()[0].1["text"],
+            "~Jane Doe~\nReport\n\n✓ Read"
+        );
+    }
 }
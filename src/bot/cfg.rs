@@ -6,6 +6,8 @@ pub struct TelegramBotCfg {
     pub storage: StorageCfg,
     pub bot: BotCfg,
     pub broker: BrokerCfg,
+    /// Linked from the bot messages so users can change their settings
+    pub web_app_url: Option<String>,
 }
 
 impl TryFrom<Config> for TelegramBotCfg {
@@ -15,10 +17,12 @@ impl TryFrom<Config> for TelegramBotCfg {
         let storage = StorageCfg::try_from(&cfg)?;
         let bot = BotCfg::try_from(&cfg)?;
         let broker = BrokerCfg::try_from(&cfg)?;
+        let web_app_url = cfg.get_string("web.public_url").ok();
         Ok(TelegramBotCfg {
             storage,
             bot,
             broker,
+            web_app_url,
         })
     }
 }
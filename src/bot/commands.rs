@@ -0,0 +1,581 @@
+use common::i18n::{self, plural, tr, trf, Language};
+use common::queues::TelegramMessageTask;
+use common::sessions::WebAppUser;
+use common::storage::{Cipher, LinkedChat, MailboxProblem, RouteScope, Storage};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::BotCommand;
+use teloxide::utils::command::BotCommands;
+use teloxide::utils::markdown::{escape, link};
+use tokio::sync::RwLock;
+
+use crate::bot::TelegramBot;
+use crate::handlers::reply_route;
+use common::types::{Error, InternalError};
+
+/// Longest quiet period the user can ask for
+const MAX_QUIET_HOURS: i64 = 7 * 24;
+
+#[derive(BotCommands, Clone)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+pub enum Command {
+    #[command(description = "get started")]
+    Start,
+    #[command(description = "list the commands")]
+    Help,
+    #[command(description = "show the mailbox state")]
+    Status,
+    #[command(description = "stop checking mail")]
+    Pause,
+    #[command(description = "start checking mail again")]
+    Resume,
+    #[command(description = "hold routine notifications for N hours, /quiet off to stop")]
+    Quiet(String),
+    #[command(description = "show notification settings")]
+    Settings,
+    #[command(description = "choose the language: en, ru or auto")]
+    Language(String),
+    #[command(description = "send mail to this group or topic, /link important for important one")]
+    Link(String),
+    #[command(description = "send mail to the private chat again")]
+    Unlink(String),
+}
+
+impl Command {
+    /// Command list shown by Telegram, with descriptions in the language
+    pub fn localized(lang: Language) -> Vec<BotCommand> {
+        [
+            "start", "help", "status", "pause", "resume", "quiet", "settings", "language", "link",
+            "unlink",
+        ]
+        .into_iter()
+        .map(|command| BotCommand {
+            command: command.into(),
+            description: tr(lang, &format!("command.{}", command)).into(),
+        })
+        .collect()
+    }
+}
+
+/// Hours of quiet asked for by `/quiet` arguments, 0 ends it, `None` if they make no sense
+fn quiet_hours(args: &str) -> Option<i64> {
+    match args.trim() {
+        "off" | "0" => Some(0),
+        "" => Some(1),
+        args => match args.parse::<i64>() {
+            Ok(hours) if hours > 0 => Some(hours.min(MAX_QUIET_HOURS)),
+            _ => None,
+        },
+    }
+}
+
+/// Route changed by `/link` and `/unlink` arguments, `None` if they make no sense
+fn route_scope(args: &str) -> Option<RouteScope> {
+    match args.trim() {
+        "" => Some(RouteScope::Account),
+        "important" => Some(RouteScope::Important),
+        _ => None,
+    }
+}
+
+/// What command handlers need besides the bot
+#[derive(Clone)]
+pub struct CommandContext {
+    pub storage: Pin<Arc<Storage>>,
+    pub cipher: Arc<Cipher>,
+    pub web_app_url: Option<String>,
+    pub tasks: Arc<RwLock<HashMap<uuid::Uuid, TelegramMessageTask>>>,
+}
+
+impl CommandContext {
+    fn settings_link(&self, lang: Language) -> String {
+        match &self.web_app_url {
+            Some(url) => format!("\n{}", link(url, &escape(tr(lang, "settings.link")))),
+            None => String::new(),
+        }
+    }
+
+    /// Language to answer in, the Telegram app one is remembered for notifications
+    async fn language(&self, user: &WebAppUser, msg: &Message) -> Result<Language, Error> {
+        let code = msg
+            .from
+            .as_ref()
+            .and_then(|from| from.language_code.as_deref());
+        if code.is_some() {
+            self.storage
+                .set_telegram_language(user, Language::from_code(code))
+                .await?;
+        }
+        Ok(self.storage.get_user_language(user).await?)
+    }
+
+    async fn start(&self, user: &WebAppUser, lang: Language) -> Result<String, Error> {
+        let mut text = escape(tr(lang, "start.greeting"));
+        text.push_str("\n\n");
+        if !self.storage.is_user_registed(user).await? {
+            text.push_str(&escape(tr(lang, "start.sign_up")));
+        } else {
+            text.push_str(&escape(tr(lang, "start.ready")));
+        }
+        text.push_str(&self.settings_link(lang));
+        Ok(text)
+    }
+
+    fn help(&self, lang: Language) -> String {
+        let mut lines = vec![tr(lang, "help.header").to_string()];
+        for command in Command::localized(lang) {
+            lines.push(format!("/{} — {}", command.command, command.description));
+        }
+        escape(&lines.join("\n"))
+    }
+
+    async fn status(&self, user: &WebAppUser, lang: Language) -> Result<String, Error> {
+        let mut lines = vec![];
+        match self.storage.get_mail_account(user, &self.cipher).await? {
+            Some(account) => lines.push(trf(lang, "status.account", &[("email", &account.email)])),
+            None => lines.push(tr(lang, "status.no_account").into()),
+        }
+
+        let checking = self.storage.is_checking_enabled(user).await?;
+        lines.push(match checking {
+            true => tr(lang, "status.checking_on").into(),
+            false => tr(lang, "status.checking_paused").into(),
+        });
+
+        let health = self.storage.get_mailbox_health(user).await?;
+        match health.problem {
+            Some(problem) if health.is_failing() => {
+                let problem = match problem {
+                    MailboxProblem::AuthFailed => tr(lang, "problem.short.auth_failed"),
+                    MailboxProblem::Unreachable => tr(lang, "problem.short.unreachable"),
+                    MailboxProblem::Other => tr(lang, "problem.short.other"),
+                };
+                lines.push(plural(
+                    lang,
+                    "status.health_failing",
+                    health.failures as u64,
+                    &[("problem", &problem)],
+                ));
+            }
+            _ => lines.push(tr(lang, "status.health_ok").into()),
+        }
+
+        match self.storage.get_last_check(user).await? {
+            Some(timestamp) => lines.push(trf(
+                lang,
+                "status.last_check",
+                &[("time", &i18n::format_time(lang, timestamp))],
+            )),
+            None => lines.push(tr(lang, "status.never_checked").into()),
+        }
+
+        let to = UserId(user.id as u64);
+        let queued = self
+            .tasks
+            .read()
+            .await
+            .values()
+            .filter(|task| task.to == to)
+            .count();
+        lines.push(plural(lang, "status.waiting", queued as u64, &[]));
+
+        if let Some(until) = self.storage.get_quiet_until(user).await? {
+            lines.push(trf(
+                lang,
+                "status.quiet_until",
+                &[("time", &i18n::format_time(lang, until))],
+            ));
+        }
+
+        Ok(escape(&lines.join("\n")) + &self.settings_link(lang))
+    }
+
+    async fn pause(&self, user: &WebAppUser, lang: Language) -> Result<String, Error> {
+        self.storage.disable_checking(user).await?;
+        Ok(escape(tr(lang, "pause.done")))
+    }
+
+    async fn resume(&self, user: &WebAppUser, lang: Language) -> Result<String, Error> {
+        if self
+            .storage
+            .get_mail_account(user, &self.cipher)
+            .await?
+            .is_none()
+        {
+            let text = escape(tr(lang, "resume.no_account"));
+            return Ok(text + &self.settings_link(lang));
+        }
+        self.storage.enable_checking(user).await?;
+        Ok(escape(tr(lang, "resume.done")))
+    }
+
+    async fn quiet(&self, user: &WebAppUser, lang: Language, args: &str) -> Result<String, Error> {
+        let hours = match quiet_hours(args) {
+            Some(0) => {
+                self.storage.reset_quiet(user).await?;
+                return Ok(escape(tr(lang, "quiet.off")));
+            }
+            Some(hours) => hours,
+            None => return Ok(escape(tr(lang, "quiet.usage"))),
+        };
+        let until = chrono::Utc::now().timestamp() + hours * 3600;
+        self.storage.set_quiet_until(user, until).await?;
+        Ok(escape(&trf(
+            lang,
+            "quiet.on",
+            &[("time", &i18n::format_time(lang, until))],
+        )))
+    }
+
+    async fn settings(&self, user: &WebAppUser, lang: Language) -> Result<String, Error> {
+        let hours = self.storage.get_user_working_hours(user).await?;
+        let offset = self.storage.get_user_utc_offset(user).await?;
+        let emails = self.storage.get_important_emails(user).await?;
+        let tags = self.storage.get_important_tags(user).await?;
+        let or_none = |values: Vec<String>| match values.is_empty() {
+            true => tr(lang, "settings.none").to_string(),
+            false => values.join(", "),
+        };
+        let delivery = self.storage.get_delivery_settings(user).await?;
+        let mut lines = vec![
+            trf(
+                lang,
+                "settings.working_hours",
+                &[
+                    ("start", &hours[0]),
+                    ("end", &hours[1]),
+                    ("offset", &offset),
+                ],
+            ),
+            match delivery.silent_routine {
+                true => tr(lang, "settings.routine_silent").to_string(),
+                false => tr(lang, "settings.routine_audible").to_string(),
+            },
+            match delivery.deliver_off_hours {
+                true => tr(lang, "settings.off_hours_silent").to_string(),
+                false => tr(lang, "settings.off_hours_held").to_string(),
+            },
+            trf(
+                lang,
+                "settings.important_senders",
+                &[("list", &or_none(emails))],
+            ),
+            trf(lang, "settings.important_tags", &[("list", &or_none(tags))]),
+            trf(
+                lang,
+                "settings.language",
+                &[("language", &tr(lang, "language.name"))],
+            ),
+        ];
+        if let Some([start, end]) = delivery.soft_hours {
+            lines.push(trf(
+                lang,
+                "settings.soft_hours",
+                &[("start", &start), ("end", &end)],
+            ));
+        }
+        let routes = self.storage.get_chat_routes(user).await?;
+        let chat = |linked: Option<&LinkedChat>| match linked {
+            Some(linked) => linked.title.clone(),
+            None => tr(lang, "settings.private_chat").to_string(),
+        };
+        lines.push(trf(
+            lang,
+            "settings.route",
+            &[("chat", &chat(routes.account.as_ref()))],
+        ));
+        if routes.important.is_some() {
+            lines.push(trf(
+                lang,
+                "settings.route_important",
+                &[("chat", &chat(routes.important.as_ref()))],
+            ));
+        }
+        Ok(escape(&lines.join("\n")) + &self.settings_link(lang))
+    }
+
+    /// Sets the language chosen by the user, `auto` goes back to the one of the Telegram app
+    async fn language_command(
+        &self,
+        user: &WebAppUser,
+        msg: &Message,
+        args: &str,
+    ) -> Result<String, Error> {
+        let text = match args.trim() {
+            "auto" => {
+                self.storage.set_language_override(user, None).await?;
+                let lang = self.language(user, msg).await?;
+                tr(lang, "language.auto")
+            }
+            args => match args.parse::<Language>() {
+                Ok(lang) => {
+                    self.storage.set_language_override(user, Some(lang)).await?;
+                    tr(lang, "language.set")
+                }
+                Err(_) => tr(self.language(user, msg).await?, "language.usage"),
+            },
+        };
+        Ok(escape(text))
+    }
+
+    /// Routes mail of the user to the group or forum topic the command is sent in.
+    /// The bot must be a member there and the user an admin
+    async fn link(
+        &self,
+        bot: &Bot,
+        user: &WebAppUser,
+        msg: &Message,
+        lang: Language,
+        args: &str,
+    ) -> Result<String, Error> {
+        let Some(scope) = route_scope(args) else {
+            return Ok(escape(tr(lang, "link.usage")));
+        };
+        if msg.chat.is_private() {
+            return Ok(escape(tr(lang, "link.private")));
+        }
+
+        if let Some(refusal) = link_refusal(bot, msg.chat.id, UserId(user.id as u64)).await? {
+            return Ok(escape(tr(lang, refusal)));
+        }
+
+        let linked = LinkedChat {
+            route: reply_route(msg),
+            title: msg.chat.title().unwrap_or_default().to_string(),
+        };
+        self.storage
+            .set_chat_route(user, scope, Some(&linked))
+            .await?;
+        let key = match scope {
+            RouteScope::Account => "link.done",
+            RouteScope::Important => "link.done_important",
+        };
+        let name = msg
+            .from
+            .as_ref()
+            .map(|from| from.full_name())
+            .unwrap_or_default();
+        Ok(escape(&trf(lang, key, &[("name", &name)])))
+    }
+
+    async fn unlink(&self, user: &WebAppUser, lang: Language, args: &str) -> Result<String, Error> {
+        let Some(scope) = route_scope(args) else {
+            return Ok(escape(tr(lang, "unlink.usage")));
+        };
+        let key = match scope {
+            RouteScope::Account => "unlink.done",
+            RouteScope::Important => "unlink.done_important",
+        };
+        self.storage.set_chat_route(user, scope, None).await?;
+        Ok(escape(tr(lang, key)))
+    }
+}
+
+/// Why the user may not route mail to the chat, as a message key. `None` when the bot
+/// is a member there and the user an admin
+async fn link_refusal(
+    bot: &Bot,
+    chat: ChatId,
+    user: UserId,
+) -> Result<Option<&'static str>, Error> {
+    let me = bot.get_me().await?;
+    if !bot.get_chat_member(chat, me.id).await?.is_present() {
+        return Ok(Some("link.bot_absent"));
+    }
+    if !bot.get_chat_member(chat, user).await?.is_privileged() {
+        return Ok(Some("link.not_admin"));
+    }
+    Ok(None)
+}
+
+pub async fn process_command(
+    bot: Bot,
+    msg: Message,
+    command: Command,
+    context: CommandContext,
+) -> Result<(), Error> {
+    let user_id = match msg.from.as_ref() {
+        Some(from) => from.id,
+        None => {
+            return Err(Error::InternalError(InternalError::RuntimeError(format!(
+                "Command in chat {} has no sender",
+                msg.chat.id
+            ))))
+        }
+    };
+    let user = WebAppUser::from(user_id.0 as i64);
+    let reply_to = reply_route(&msg);
+    let lang = context.language(&user, &msg).await?;
+
+    // Everyone in a group sees the answers, only routing is done there
+    if !msg.chat.is_private() && !matches!(command, Command::Link(_) | Command::Unlink(_)) {
+        let text = escape(tr(lang, "group.private_only"));
+        return TelegramBot::send_markdown(&bot, reply_to, &text, lang).await;
+    }
+
+    if !matches!(command, Command::Start | Command::Help)
+        && !context.storage.is_user_registed(&user).await?
+    {
+        let text = escape(tr(lang, "not_registered")) + &context.settings_link(lang);
+        return TelegramBot::send_markdown(&bot, reply_to, &text, lang).await;
+    }
+
+    let text = match command {
+        Command::Start => context.start(&user, lang).await?,
+        Command::Help => context.help(lang),
+        Command::Status => context.status(&user, lang).await?,
+        Command::Pause => context.pause(&user, lang).await?,
+        Command::Resume => context.resume(&user, lang).await?,
+        Command::Quiet(args) => context.quiet(&user, lang, &args).await?,
+        Command::Settings => context.settings(&user, lang).await?,
+        Command::Language(args) => {
+            let text = context.language_command(&user, &msg, &args).await?;
+            // Answered in the language just chosen, the keyboard too
+            let lang = context.storage.get_user_language(&user).await?;
+            return TelegramBot::send_markdown(&bot, reply_to, &text, lang).await;
+        }
+        Command::Link(args) => context.link(&bot, &user, &msg, lang, &args).await?,
+        Command::Unlink(args) => context.unlink(&user, lang, &args).await?,
+    };
+    TelegramBot::send_markdown(&bot, reply_to, &text, lang).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_telegram;
+
+    fn parse(text: &str) -> Option<Command> {
+        Command::parse(text, "mega_mailer_bot").ok()
+    }
+
+    #[test]
+    fn parses_commands_with_arguments() {
+        assert!(matches!(parse("/start"), Some(Command::Start)));
+        assert!(matches!(
+            parse("/status@mega_mailer_bot"),
+            Some(Command::Status)
+        ));
+        assert!(matches!(parse("/quiet 3"), Some(Command::Quiet(args)) if args == "3"));
+        assert!(matches!(parse("/quiet"), Some(Command::Quiet(args)) if args.is_empty()));
+        assert!(
+            matches!(parse("/link important"), Some(Command::Link(args)) if args == "important")
+        );
+        assert!(parse("/status@other_bot").is_none());
+        assert!(parse("/fetch").is_none());
+        assert!(parse("Fetch all emails").is_none());
+    }
+
+    #[test]
+    fn every_command_is_described_in_every_language() {
+        let commands: Vec<String> = Command::bot_commands()
+            .into_iter()
+            .map(|command| command.command.trim_start_matches('/').to_owned())
+            .collect();
+        for lang in [Language::En, Language::Ru] {
+            let localized = Command::localized(lang);
+            let names: Vec<&str> = localized.iter().map(|c| c.command.as_str()).collect();
+            assert_eq!(names, commands);
+            assert!(localized
+                .iter()
+                .all(|command| !command.description.is_empty()));
+        }
+    }
+
+    #[test]
+    fn quiet_takes_hours_or_off() {
+        assert_eq!(quiet_hours(""), Some(1));
+        assert_eq!(quiet_hours(" 8 "), Some(8));
+        assert_eq!(quiet_hours("off"), Some(0));
+        assert_eq!(quiet_hours("0"), Some(0));
+        assert_eq!(quiet_hours("100000"), Some(MAX_QUIET_HOURS));
+        assert_eq!(quiet_hours("-2"), None);
+        assert_eq!(quiet_hours("soon"), None);
+    }
+
+    const BOT_ID: u64 = 1;
+    const USER_ID: u64 = 42;
+
+    fn member(status: &str) -> serde_json::Value {
+        let mut member = serde_json::json!({
+            "user": { "id": USER_ID, "is_bot": false, "first_name": "Jane" },
+            "status": status,
+        });
+        let rights = match status {
+            "creator" => serde_json::json!({ "is_anonymous": false }),
+            "kicked" => serde_json::json!({ "until_date": 0 }),
+            "administrator" => serde_json::json!({
+                "can_be_edited": false,
+                "is_anonymous": false,
+                "can_manage_chat": true,
+                "can_change_info": false,
+                "can_delete_messages": false,
+                "can_manage_video_chats": false,
+                "can_invite_users": true,
+                "can_restrict_members": false,
+                "can_promote_members": false,
+            }),
+            _ => serde_json::json!({}),
+        };
+        member
+            .as_object_mut()
+            .unwrap()
+            .extend(rights.as_object().unwrap().clone());
+        member
+    }
+
+    /// Telegram Bot API answering getMe and getChatMember with the given statuses of the bot
+    /// and the user
+    async fn telegram(bot_status: &'static str, user_status: &'static str) -> Bot {
+        let (bot, _) = fake_telegram::start(move |method, body| {
+            fake_telegram::ok(match method {
+                "getme" => serde_json::json!({
+                    "id": BOT_ID,
+                    "is_bot": true,
+                    "first_name": "Mega Mailer",
+                    "username": "mega_mailer_bot",
+                    "can_join_groups": true,
+                    "can_read_all_group_messages": false,
+                    "supports_inline_queries": false,
+                }),
+                "getchatmember" if body["user_id"] == BOT_ID => member(bot_status),
+                "getchatmember" => member(user_status),
+                _ => serde_json::Value::Null,
+            })
+        })
+        .await;
+        bot
+    }
+
+    async fn refusal(bot_status: &'static str, user_status: &'static str) -> Option<&'static str> {
+        let bot = telegram(bot_status, user_status).await;
+        link_refusal(&bot, ChatId(-100), UserId(USER_ID))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_admins_link_chats_the_bot_is_in() {
+        assert_eq!(refusal("member", "administrator").await, None);
+        assert_eq!(refusal("administrator", "creator").await, None);
+        assert_eq!(refusal("member", "member").await, Some("link.not_admin"));
+        assert_eq!(refusal("member", "left").await, Some("link.not_admin"));
+        assert_eq!(refusal("left", "creator").await, Some("link.bot_absent"));
+        assert_eq!(
+            refusal("kicked", "administrator").await,
+            Some("link.bot_absent")
+        );
+    }
+
+    #[test]
+    fn link_takes_route_scope() {
+        assert_eq!(route_scope(""), Some(RouteScope::Account));
+        assert_eq!(route_scope("important"), Some(RouteScope::Important));
+        assert_eq!(route_scope("everything"), None);
+    }
+}
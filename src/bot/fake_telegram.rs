@@ -0,0 +1,41 @@
+//! Telegram Bot API for tests, serving on a local port and recording the calls
+
+use std::sync::{Arc, Mutex};
+use teloxide::Bot;
+
+/// Methods called, lowercased, with their parameters
+pub type Calls = Arc<Mutex<Vec<(String, serde_json::Value)>>>;
+
+/// Successful reply of the method
+pub fn ok(result: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({ "ok": true, "result": result })
+}
+
+/// Starts the API answering every call with what `respond` makes of the lowercased method
+/// and its parameters, and a bot talking to it
+pub async fn start<F>(respond: F) -> (Bot, Calls)
+where
+    F: Fn(&str, &serde_json::Value) -> serde_json::Value + Clone + Send + Sync + 'static,
+{
+    use axum::{extract::Path, routing::post, Json, Router};
+
+    let calls: Calls = Default::default();
+    let recorded = calls.clone();
+    let app = Router::new().route(
+        "/:token/:method",
+        post(
+            move |Path((_, method)): Path<(String, String)>,
+                  Json(body): Json<serde_json::Value>| async move {
+                let method = method.to_lowercase();
+                let reply = respond(&method, &body);
+                recorded.lock().unwrap().push((method, body));
+                Json(reply)
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let bot = Bot::new("1:TOKEN").set_api_url(url.parse().unwrap());
+    (bot, calls)
+}
@@ -1,51 +1,135 @@
-use common::queues::{BrokerClient, TelegramMessageTask};
-use std::collections::{BTreeMap, HashMap};
+use common::i18n;
+use common::queues::TelegramMessageTask;
+use common::sessions::WebAppUser;
+use common::storage::{ChatRoute, Storage};
+use std::collections::HashMap;
+use std::pin::Pin;
 use std::sync::Arc;
 use teloxide::prelude::*;
+use teloxide::utils::markdown::escape;
 use tokio::sync::RwLock;
 
 use crate::bot::TelegramBot;
+use crate::sender::{Delivery, Outcome};
 use common::types::{Error, InternalError};
 
+/// Pending notifications delivered per "Fetch all emails" press, the rest waits for the next one
+const PAGE_SIZE: usize = 20;
+
+/// Chat the message came from, together with the forum topic it was sent in
+pub fn reply_route(msg: &Message) -> ChatRoute {
+    ChatRoute {
+        chat_id: msg.chat.id.0,
+        // Replies outside of forums have a thread too, messages can not be sent into it
+        thread_id: match msg.is_topic_message {
+            true => msg.thread_id.map(|thread| thread.0 .0),
+            false => None,
+        },
+    }
+}
+
+/// Pending tasks of the user routed to the chat, the oldest first
+fn pending_of(
+    tasks: &HashMap<uuid::Uuid, TelegramMessageTask>,
+    user: UserId,
+    chat_id: i64,
+) -> Vec<uuid::Uuid> {
+    let mut pending: Vec<(&uuid::Uuid, &TelegramMessageTask)> = tasks
+        .iter()
+        .filter(|(_, task)| task.to == user && task.destination().chat_id == chat_id)
+        .collect();
+    pending.sort_by_key(|(_, task)| task.send_after);
+    pending.into_iter().map(|(task_id, _)| *task_id).collect()
+}
+
+/// Delivers pending notifications of the user routed to the chat right away, on the path of the send loop.
+/// In a group other members' notifications are left alone, each one goes to its topic
 pub async fn process_fetch_all_emails(
     bot: Bot,
     msg: Message,
-    _broker_client: BrokerClient,
+    storage: Pin<Arc<Storage>>,
     tasks: Arc<RwLock<HashMap<uuid::Uuid, TelegramMessageTask>>>,
+    delivery: Delivery,
 ) -> Result<(), Error> {
-    let chat_id = msg.chat.id;
-    let user_id: UserId = match chat_id.is_user() {
-        true => UserId(chat_id.0 as u64),
-        _ => {
+    let user_id = match msg.from.as_ref() {
+        Some(from) => from.id,
+        None => {
             return Err(Error::InternalError(InternalError::RuntimeError(format!(
-                "ChatId is not belongs to user: {}",
-                chat_id
+                "Message in chat {} has no sender",
+                msg.chat.id
             ))))
         }
     };
-    let tasks = tasks.read().await;
-    let tasks = tasks
-        .iter()
-        .filter(|(ref _key, ref task)| task.to == user_id)
-        .collect::<BTreeMap<_, _>>();
-
-    if tasks.len() == 0 {
-        TelegramBot::send_markdown(
-            &bot,
-            user_id,
-            &String::from("There are no messages for you now"),
-        )
-        .await?;
-    } else {
-        for (_task_id, task) in tasks {
-            if task.to != user_id {
-                continue;
-            }
-            TelegramBot::send_markdown(&bot, user_id, &task.text).await?;
-            // if let Err(e) = queue.ack(*delivery_tag, *channel_id).await {
-            //    tracing::warn!("queue.ack() finished with error: {e}");
-            //}
+    let reply_to = reply_route(&msg);
+    let lang = storage
+        .get_user_language(&WebAppUser::from(user_id.0 as i64))
+        .await
+        .unwrap_or_default();
+    let pending = pending_of(&*tasks.read().await, user_id, reply_to.chat_id);
+    let left = pending.len().saturating_sub(PAGE_SIZE);
+
+    if pending.is_empty() {
+        let text = escape(i18n::tr(lang, "fetch.empty"));
+        TelegramBot::send_markdown(&bot, reply_to, &text, lang).await?;
+        return Ok(());
+    }
+
+    for task_id in pending.into_iter().take(PAGE_SIZE) {
+        // The send loop delivers the rest
+        if let Outcome::Failed(e) = delivery.send(task_id, true).await {
+            return Err(e);
         }
     }
+
+    if left > 0 {
+        let text = i18n::plural(
+            lang,
+            "fetch.more",
+            left as u64,
+            &[("button", &i18n::tr(lang, "keyboard.fetch_all"))],
+        );
+        TelegramBot::send_markdown(&bot, reply_to, &escape(&text), lang).await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(to: u64, chat_id: i64, minutes_ago: i64) -> (uuid::Uuid, TelegramMessageTask) {
+        let task = TelegramMessageTask {
+            to: UserId(to),
+            text: "*Jane Doe*\nReport".into(),
+            send_after: chrono::Utc::now() - chrono::Duration::minutes(minutes_ago),
+            important: false,
+            chat: Some(ChatRoute {
+                chat_id,
+                thread_id: None,
+            }),
+            silent: false,
+            mail: None,
+            recheck: false,
+        };
+        (uuid::Uuid::new_v4(), task)
+    }
+
+    #[test]
+    fn only_own_notifications_are_fetched_in_a_group() {
+        let (own_new, task) = pending(1, -100, 1);
+        let (own_old, old_task) = pending(1, -100, 5);
+        let (_, other) = pending(2, -100, 10);
+        let (_, elsewhere) = pending(1, 1, 10);
+        let tasks: HashMap<_, _> = [
+            (own_new, task),
+            (own_old, old_task),
+            (uuid::Uuid::new_v4(), other),
+            (uuid::Uuid::new_v4(), elsewhere),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(pending_of(&tasks, UserId(1), -100), [own_old, own_new]);
+        assert!(pending_of(&tasks, UserId(3), -100).is_empty());
+    }
+}
@@ -1,6 +1,11 @@
 mod bot;
 mod cfg;
+mod commands;
+#[cfg(test)]
+mod fake_telegram;
 mod handlers;
+mod sender;
+mod webhook;
 
 use cfg::TelegramBotCfg;
 use common::cfg::build_config;
@@ -25,7 +30,7 @@ async fn main_impl() -> Result<()> {
     set_ctrlc_handler(r)?;
 
     let storage: Pin<Arc<Storage>> = Arc::pin(Storage::new(&cfg.storage).await?);
-    let broker_client = BrokerClient::new(cfg.broker.clone())?;
+    let broker_client = BrokerClient::new(cfg.broker.clone(), Storage::clone(&storage))?;
     let tasks = Default::default();
     let bot = Arc::new(bot::TelegramBot::new(
         storage.clone(),
@@ -0,0 +1,595 @@
+use common::queues::{BrokerClient, NotificationUpdateTask, TelegramMessageTask};
+use common::retry;
+use common::sessions::WebAppUser;
+use common::storage::{NotifiedMail, Storage};
+use common::types::{Error, TelegramBotError};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use teloxide::types::ChatId;
+use teloxide::Bot;
+use tokio::sync::RwLock;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::bot::TelegramBot;
+
+/// Telegram allows about 30 messages per second in total
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(1000 / 30);
+/// and about one message per second to the same chat
+const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Groups take no more than 20 messages per minute
+const GROUP_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How long a due deferred task waits for the checker to look its mail up.
+/// The checker runs every minute, so this leaves room for a few failed runs
+const RECHECK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Transient send failures after which the task is moved to dead letters
+const MAX_SEND_ATTEMPTS: u32 = 5;
+/// How long the chat is not sent to after a transient failure
+const RETRY_INTERVAL: std::time::Duration = Duration::from_secs(10);
+/// Errors which will not go away on retry: the bot is blocked, the chat is gone or the text is malformed
+fn is_permanent_error(error: &Error) -> bool {
+    use teloxide::{ApiError, RequestError};
+
+    match error {
+        Error::TelegramBotError(TelegramBotError::RequestError(RequestError::Api(api_error))) => {
+            matches!(
+                api_error,
+                ApiError::BotBlocked
+                    | ApiError::BotKicked
+                    | ApiError::BotKickedFromSupergroup
+                    | ApiError::UserDeactivated
+                    | ApiError::ChatNotFound
+                    | ApiError::UserNotFound
+                    | ApiError::GroupDeactivated
+                    | ApiError::CantInitiateConversation
+                    | ApiError::CantTalkWithBots
+                    | ApiError::CantParseEntities(_)
+                    | ApiError::MessageTextIsEmpty
+                    | ApiError::MessageIsTooLong
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Whether a task which failed to send `failures` times, the last one with `error`, is given up
+fn should_dead_letter(error: &Error, failures: u32) -> bool {
+    is_permanent_error(error) || failures >= MAX_SEND_ATTEMPTS
+}
+
+fn chat_interval(chat: ChatId) -> Duration {
+    match chat.is_user() {
+        true => CHAT_INTERVAL,
+        false => GROUP_INTERVAL,
+    }
+}
+
+/// How long Telegram asks to wait before sending again, if that is the error
+pub fn retry_after(error: &Error) -> Option<Duration> {
+    use teloxide::RequestError;
+
+    match error {
+        Error::TelegramBotError(TelegramBotError::RequestError(RequestError::RetryAfter(
+            seconds,
+        ))) => Some(seconds.duration()),
+        _ => None,
+    }
+}
+
+/// Whether the due task is held until the checker confirms its mail is still unread.
+/// Past the timeout it is sent anyway, a needless notification is better than a lost one
+pub fn awaits_recheck(task: &TelegramMessageTask, now: chrono::DateTime<chrono::Utc>) -> bool {
+    let timeout = chrono::Duration::seconds(RECHECK_TIMEOUT.as_secs() as i64);
+    task.recheck && now < task.send_after + timeout
+}
+
+/// Spaces sends out to stay within Telegram limits, globally and for every chat
+#[derive(Default)]
+pub struct RateLimiter {
+    next_send: Option<Instant>,
+    /// Moments before which the chats must not be sent to
+    chats: HashMap<ChatId, Instant>,
+}
+
+impl RateLimiter {
+    /// When the chat may be sent to again, if not right now
+    pub fn ready_at(&self, chat: ChatId) -> Option<Instant> {
+        self.chats
+            .get(&chat)
+            .copied()
+            .filter(|at| *at > Instant::now())
+    }
+
+    /// Books the next slot of the chat and returns when the message may be sent.
+    /// Does not wait itself, so the limiter is not held by a sender waiting for its slot
+    pub fn reserve(&mut self, chat: ChatId) -> Instant {
+        let now = Instant::now();
+        let mut at = self.next_send.unwrap_or(now).max(now);
+        if let Some(chat_at) = self.chats.get(&chat) {
+            at = at.max(*chat_at);
+        }
+
+        self.next_send = Some(at + GLOBAL_INTERVAL);
+        self.chats.retain(|_, chat_at| *chat_at > now);
+        self.chats.insert(chat, at + chat_interval(chat));
+        at
+    }
+
+    /// Holds off the chat for the given time, the one Telegram asked for or a pause after a failure
+    pub fn retry_after(&mut self, chat: ChatId, wait: Duration) {
+        self.chats.insert(chat, Instant::now() + wait);
+    }
+}
+
+/// What became of the task handed to [`Delivery::send`]
+pub enum Outcome {
+    /// Sent and acked
+    Sent,
+    /// Acked without sending: its mail was read or removed meanwhile
+    Dropped,
+    /// Kept for later, the chat may be sent to at the given moment
+    NotReady(Instant),
+    /// Kept until the checker looks its mail up
+    AwaitsRecheck,
+    /// Kept to retry, or dead lettered once retries are used up
+    Failed(Error),
+    /// Sent, or being sent, by someone else
+    Taken,
+}
+
+/// The send path of pending tasks, shared by the send loop and "Fetch all emails":
+/// checks whether the task is still wanted, keeps to rate limits, sends it and acks it
+#[derive(Clone)]
+pub struct Delivery {
+    bot: Bot,
+    storage: Pin<Arc<Storage>>,
+    broker: BrokerClient,
+    tasks: Arc<RwLock<HashMap<uuid::Uuid, TelegramMessageTask>>>,
+    /// Ids of sent tasks, to drop their redeliveries
+    sent: Arc<RwLock<HashMap<uuid::Uuid, std::time::Instant>>>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    /// Tasks being sent, they stay pending until that is done
+    in_flight: Arc<Mutex<HashSet<uuid::Uuid>>>,
+    send_failures: Arc<Mutex<HashMap<uuid::Uuid, u32>>>,
+    /// Deferred tasks whose mail the checker confirmed unread, kept for resends after failures
+    confirmed: Arc<Mutex<HashSet<uuid::Uuid>>>,
+}
+
+impl Delivery {
+    pub fn new(
+        bot: Bot,
+        storage: Pin<Arc<Storage>>,
+        broker: BrokerClient,
+        tasks: Arc<RwLock<HashMap<uuid::Uuid, TelegramMessageTask>>>,
+        sent: Arc<RwLock<HashMap<uuid::Uuid, std::time::Instant>>>,
+    ) -> Delivery {
+        Delivery {
+            bot,
+            storage,
+            broker,
+            tasks,
+            sent,
+            limiter: Default::default(),
+            in_flight: Default::default(),
+            send_failures: Default::default(),
+            confirmed: Default::default(),
+        }
+    }
+
+    /// Sends the pending task. The send loop does not wait for the chat to be free,
+    /// tasks sent `on_request` of the user wait for it and are not held for the recheck
+    pub async fn send(&self, msg_id: uuid::Uuid, on_request: bool) -> Outcome {
+        let task = match self.tasks.read().await.get(&msg_id) {
+            Some(task) => task.clone(),
+            None => return Outcome::Taken,
+        };
+        let chat = task.destination().chat();
+        let ready_at = self.limiter.lock().unwrap().ready_at(chat);
+        if let (Some(at), false) = (ready_at, on_request) {
+            return Outcome::NotReady(at);
+        }
+        if !self.in_flight.lock().unwrap().insert(msg_id) {
+            return Outcome::Taken;
+        }
+        let outcome = self.send_claimed(msg_id, &task, on_request).await;
+        self.in_flight.lock().unwrap().remove(&msg_id);
+        outcome
+    }
+
+    async fn send_claimed(
+        &self,
+        msg_id: uuid::Uuid,
+        task: &TelegramMessageTask,
+        on_request: bool,
+    ) -> Outcome {
+        let user = WebAppUser::from(task.to.0 as i64);
+        if let Some(mail) = &task.mail {
+            match self.storage.take_cancelled_mail(&user, mail).await {
+                Ok(true) => {
+                    tracing::info!(
+                        "Dropping message {}, its mail was read or removed meanwhile",
+                        msg_id
+                    );
+                    self.ack(msg_id).await;
+                    self.forget(msg_id).await;
+                    return Outcome::Dropped;
+                }
+                Ok(false) => {}
+                // Sent anyway, a needless notification is better than a lost one
+                Err(e) => tracing::warn!(
+                    "Failed to check whether message {} is cancelled: {}",
+                    msg_id,
+                    e
+                ),
+            }
+
+            let confirmed = self.confirmed.lock().unwrap().contains(&msg_id);
+            if task.recheck && !on_request && !confirmed {
+                match self.storage.take_confirmed_mail(&user, mail).await {
+                    Ok(true) => {
+                        self.confirmed.lock().unwrap().insert(msg_id);
+                    }
+                    // The checker has yet to look the mail up
+                    Ok(false) if awaits_recheck(task, chrono::Utc::now()) => {
+                        return Outcome::AwaitsRecheck;
+                    }
+                    Ok(false) => tracing::warn!(
+                        "Sending message {}, its mail was not looked up in time",
+                        msg_id
+                    ),
+                    Err(e) => tracing::warn!(
+                        "Failed to check whether message {} is confirmed: {}",
+                        msg_id,
+                        e
+                    ),
+                }
+            }
+        }
+
+        let lang = self
+            .storage
+            .get_user_language(&user)
+            .await
+            .unwrap_or_default();
+        let chat = task.destination();
+        let at = self.limiter.lock().unwrap().reserve(chat.chat());
+        sleep_until(at).await;
+
+        let message = match TelegramBot::send_notification(&self.bot, task, lang).await {
+            Ok(message) => message,
+            Err(e) => {
+                if let Some(wait) = retry_after(&e) {
+                    // Flood control is not a failure of the message, it is sent later
+                    tracing::warn!(
+                        "Telegram asked to hold off chat {} for {:?}",
+                        chat.chat_id,
+                        wait
+                    );
+                    self.limiter.lock().unwrap().retry_after(chat.chat(), wait);
+                    return Outcome::Failed(e);
+                }
+                self.limiter
+                    .lock()
+                    .unwrap()
+                    .retry_after(chat.chat(), RETRY_INTERVAL);
+                let failures = {
+                    let mut send_failures = self.send_failures.lock().unwrap();
+                    let failures = send_failures.entry(msg_id).or_insert(0);
+                    *failures += 1;
+                    *failures
+                };
+                tracing::error!(
+                    "Failed to send message {} (attempt {}, permanent: {}): {}",
+                    msg_id,
+                    failures,
+                    is_permanent_error(&e),
+                    e
+                );
+                if should_dead_letter(&e, failures) {
+                    match self.broker.dead_letter(msg_id, e.to_string()).await {
+                        Err(e) => tracing::error!(
+                            "Failed to dead letter message with id {}: {}",
+                            msg_id,
+                            e
+                        ),
+                        _ => self.forget(msg_id).await,
+                    }
+                }
+                return Outcome::Failed(e);
+            }
+        };
+
+        if let Some(mail) = &task.mail {
+            let notified = NotifiedMail {
+                mail: mail.clone(),
+                chat,
+                message_id: message.id.0,
+                text: task.text.clone(),
+                sent_at: chrono::Utc::now().timestamp(),
+            };
+            if let Err(e) = self.storage.add_notified_mail(&user, &notified).await {
+                tracing::error!("Failed to remember notification {}: {}", msg_id, e);
+            }
+        }
+        self.ack(msg_id).await;
+        self.sent
+            .write()
+            .await
+            .insert(msg_id, std::time::Instant::now());
+        self.forget(msg_id).await;
+        Outcome::Sent
+    }
+
+    /// Edits or deletes the notification of the changed mail, keeping to rate limits, and acks the update.
+    /// Left unacked if Telegram asks to hold off, the broker delivers it again later
+    pub async fn update(&self, msg_id: uuid::Uuid, task: &NotificationUpdateTask) {
+        let user = WebAppUser::from(task.to.0 as i64);
+        let lang = self
+            .storage
+            .get_user_language(&user)
+            .await
+            .unwrap_or_default();
+        let chat = task.notified.chat.chat();
+        let at = self.limiter.lock().unwrap().reserve(chat);
+        sleep_until(at).await;
+
+        if let Err(e) = TelegramBot::update_notification(&self.bot, task, lang).await {
+            if let Some(wait) = retry_after(&e) {
+                tracing::warn!("Telegram asked to hold off chat {} for {:?}", chat, wait);
+                self.limiter.lock().unwrap().retry_after(chat, wait);
+                return;
+            }
+            // Not retried otherwise, the notification is merely outdated
+            tracing::warn!(
+                "Failed to update notification {} in chat {}: {}",
+                task.notified.message_id,
+                task.notified.chat.chat_id,
+                e
+            );
+        }
+        self.ack(msg_id).await;
+    }
+
+    async fn ack(&self, msg_id: uuid::Uuid) {
+        if let Err(e) = retry! { self.broker.ack(msg_id).await } {
+            tracing::error!("Failed to ack message with id {}: {}", msg_id, e);
+        }
+    }
+
+    /// Drops the task, which is done with, from the pending ones
+    async fn forget(&self, msg_id: uuid::Uuid) {
+        self.tasks.write().await.remove(&msg_id);
+        self.send_failures.lock().unwrap().remove(&msg_id);
+        self.confirmed.lock().unwrap().remove(&msg_id);
+    }
+}
+
+/// Orders tasks so that chats take turns: first task of every chat, then the second ones and so on.
+/// Chats with important tasks go first, one flooded inbox does not hold the others back
+pub fn fair_order(
+    mut tasks: Vec<(uuid::Uuid, TelegramMessageTask)>,
+) -> Vec<(uuid::Uuid, TelegramMessageTask)> {
+    tasks.sort_by_key(|(_, task)| (!task.important, task.send_after));
+
+    let mut chats: Vec<Vec<(uuid::Uuid, TelegramMessageTask)>> = vec![];
+    let mut index: HashMap<ChatId, usize> = HashMap::new();
+    for (id, task) in tasks {
+        let chat = *index.entry(task.destination().chat()).or_insert_with(|| {
+            chats.push(vec![]);
+            chats.len() - 1
+        });
+        chats[chat].push((id, task));
+    }
+
+    let mut ordered = vec![];
+    let mut chats: Vec<_> = chats.into_iter().map(|chat| chat.into_iter()).collect();
+    loop {
+        let mut taken = false;
+        for chat in chats.iter_mut() {
+            if let Some(task) = chat.next() {
+                ordered.push(task);
+                taken = true;
+            }
+        }
+        if !taken {
+            break;
+        }
+    }
+    // Important tasks of later rounds still go ahead of routine ones
+    ordered.sort_by_key(|(_, task)| !task.important);
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::types::UserId;
+    use teloxide::{ApiError, RequestError};
+
+    fn api_error(error: ApiError) -> Error {
+        Error::TelegramBotError(TelegramBotError::RequestError(RequestError::Api(error)))
+    }
+
+    #[test]
+    fn unreachable_chats_and_malformed_texts_are_permanent() {
+        for error in [
+            ApiError::BotBlocked,
+            ApiError::BotKicked,
+            ApiError::ChatNotFound,
+            ApiError::UserDeactivated,
+            ApiError::CantParseEntities("Can't find end of the entity".into()),
+            ApiError::MessageIsTooLong,
+        ] {
+            assert!(is_permanent_error(&api_error(error)));
+        }
+    }
+
+    #[test]
+    fn other_errors_are_transient() {
+        let unknown = api_error(ApiError::Unknown("Internal Server Error".into()));
+        assert!(!is_permanent_error(&unknown));
+
+        let io = Error::TelegramBotError(TelegramBotError::RequestError(RequestError::Io(
+            std::io::Error::other("connection reset"),
+        )));
+        assert!(!is_permanent_error(&io));
+
+        let storage = Error::IoError(std::io::Error::other("redis is down"));
+        assert!(!is_permanent_error(&storage));
+    }
+
+    #[test]
+    fn transient_failures_are_dead_lettered_after_max_attempts() {
+        let transient = api_error(ApiError::Unknown("Bad Gateway".into()));
+        for failures in 1..MAX_SEND_ATTEMPTS {
+            assert!(!should_dead_letter(&transient, failures));
+        }
+        assert!(should_dead_letter(&transient, MAX_SEND_ATTEMPTS));
+
+        assert!(should_dead_letter(&api_error(ApiError::BotBlocked), 1));
+    }
+
+    fn task(
+        to: u64,
+        text: &str,
+        important: bool,
+        minutes_ago: i64,
+    ) -> (uuid::Uuid, TelegramMessageTask) {
+        let task = TelegramMessageTask {
+            to: UserId(to),
+            text: text.into(),
+            send_after: chrono::Utc::now() - chrono::Duration::minutes(minutes_ago),
+            important,
+            chat: None,
+            silent: false,
+            mail: None,
+            recheck: false,
+        };
+        (uuid::Uuid::new_v4(), task)
+    }
+
+    fn texts(tasks: Vec<(uuid::Uuid, TelegramMessageTask)>) -> Vec<String> {
+        tasks.into_iter().map(|(_, task)| task.text).collect()
+    }
+
+    #[test]
+    fn due_tasks_wait_for_recheck_for_a_while() {
+        let now = chrono::Utc::now();
+        let (_, mut recent) = task(1, "recent", false, 1);
+        assert!(!awaits_recheck(&recent, now));
+        recent.recheck = true;
+        assert!(awaits_recheck(&recent, now));
+
+        let (_, mut stale) = task(1, "stale", false, 10);
+        stale.recheck = true;
+        assert!(!awaits_recheck(&stale, now));
+    }
+
+    #[test]
+    fn chats_take_turns() {
+        let tasks = vec![
+            task(1, "a1", false, 30),
+            task(1, "a2", false, 20),
+            task(1, "a3", false, 10),
+            task(2, "b1", false, 5),
+            task(3, "c1", false, 1),
+        ];
+        assert_eq!(texts(fair_order(tasks)), ["a1", "b1", "c1", "a2", "a3"]);
+    }
+
+    #[test]
+    fn important_tasks_go_first() {
+        let tasks = vec![
+            task(1, "a1", false, 30),
+            task(1, "a2", true, 20),
+            task(2, "b1", false, 10),
+            task(2, "b2", true, 5),
+        ];
+        assert_eq!(texts(fair_order(tasks)), ["a2", "b2", "a1", "b1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_messages_to_the_same_chat() {
+        let mut limiter = RateLimiter::default();
+        let started = Instant::now();
+        assert_eq!(limiter.reserve(ChatId(1)), started);
+        assert_eq!(limiter.ready_at(ChatId(1)), Some(started + CHAT_INTERVAL));
+        assert_eq!(limiter.reserve(ChatId(1)), started + CHAT_INTERVAL);
+
+        let mut limiter = RateLimiter::default();
+        limiter.reserve(ChatId(-100));
+        assert_eq!(limiter.reserve(ChatId(-100)), started + GROUP_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_messages_to_different_chats_globally() {
+        let mut limiter = RateLimiter::default();
+        let started = Instant::now();
+        for chat in 1..=4 {
+            assert!(limiter.ready_at(ChatId(chat)).is_none());
+            limiter.reserve(ChatId(chat));
+        }
+        assert_eq!(limiter.reserve(ChatId(5)), started + GLOBAL_INTERVAL * 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_off_chat_when_asked() {
+        let mut limiter = RateLimiter::default();
+        let started = Instant::now();
+        limiter.retry_after(ChatId(1), Duration::from_secs(30));
+        assert_eq!(
+            limiter.ready_at(ChatId(1)),
+            Some(started + Duration::from_secs(30))
+        );
+        assert!(limiter.ready_at(ChatId(2)).is_none());
+        assert_eq!(
+            limiter.reserve(ChatId(1)),
+            started + Duration::from_secs(30)
+        );
+    }
+
+    /// A round of the send loop: chats whose slot is not free are skipped until the next one
+    async fn send_round(
+        limiter: &mut RateLimiter,
+        queued: &mut Vec<(uuid::Uuid, TelegramMessageTask)>,
+        sent: &mut Vec<(String, Instant)>,
+    ) -> Option<Instant> {
+        let mut next_round: Option<Instant> = None;
+        for (id, task) in fair_order(queued.clone()) {
+            let chat = task.destination().chat();
+            if let Some(at) = limiter.ready_at(chat) {
+                next_round = Some(next_round.map_or(at, |next| next.min(at)));
+                continue;
+            }
+            tokio::time::sleep_until(limiter.reserve(chat)).await;
+            sent.push((task.text, Instant::now()));
+            queued.retain(|(queued_id, _)| *queued_id != id);
+        }
+        next_round
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooded_chat_does_not_hold_back_later_tasks_of_others() {
+        let mut limiter = RateLimiter::default();
+        let mut queued: Vec<_> = (0..20)
+            .map(|i| task(1, &format!("a{}", i), false, 100 - i))
+            .collect();
+        let mut sent = vec![];
+        let started = Instant::now();
+
+        let next_round = send_round(&mut limiter, &mut queued, &mut sent).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "a0");
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        queued.push(task(2, "b0", false, 0));
+        tokio::time::sleep_until(next_round.unwrap()).await;
+        send_round(&mut limiter, &mut queued, &mut sent).await;
+
+        let (_, b0_sent) = sent.iter().find(|(text, _)| text == "b0").unwrap();
+        assert!(*b0_sent < started + CHAT_INTERVAL * 2);
+        assert_eq!(queued.len(), 18);
+    }
+}
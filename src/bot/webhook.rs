@@ -0,0 +1,82 @@
+use common::cfg::WebhookCfg;
+use common::storage::Storage;
+use common::types::{Error, InternalError};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::stop::{mk_stop_token, StopToken};
+use teloxide::types::Update;
+use teloxide::update_listeners::{webhooks, StatefulListener, UpdateListener};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// How long a queued update is waited for before the listener checks whether it is stopped
+const POP_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn url(webhook: &WebhookCfg) -> Result<reqwest::Url, Error> {
+    reqwest::Url::parse(&webhook.url).map_err(|e| {
+        Error::InternalError(InternalError::RuntimeError(format!(
+            "invalid webhook url: {}",
+            e
+        )))
+    })
+}
+
+fn stream_of<S, T>(state: &mut (S, T)) -> &mut S {
+    &mut state.0
+}
+
+/// Registers the webhook and serves it from this process, it is removed when the bot stops
+pub async fn standalone(
+    bot: Bot,
+    webhook: &WebhookCfg,
+) -> Result<impl UpdateListener<Err = Infallible>, Error> {
+    let options = webhooks::Options::new(webhook.listen, url(webhook)?)
+        .path(webhook.path.clone())
+        .secret_token(webhook.secret_token.clone());
+    tracing::info!("Serving webhook on {}{}", webhook.listen, webhook.path);
+    Ok(webhooks::axum(bot, options).await?)
+}
+
+/// Registers the webhook served by the web server and takes the updates it queued.
+/// Any number of bot instances can run, each update goes to one of them
+pub async fn from_web_server(
+    bot: &Bot,
+    webhook: &WebhookCfg,
+    storage: Pin<Arc<Storage>>,
+) -> Result<impl UpdateListener<Err = Infallible>, Error> {
+    // Left in place on stop, other instances may still be running
+    bot.set_webhook(url(webhook)?)
+        .secret_token(webhook.secret_token.clone())
+        .send()
+        .await?;
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (stop_token, stop_flag) = mk_stop_token();
+    tokio::spawn(async move {
+        while !stop_flag.is_stopped() {
+            match storage.pop_telegram_update(POP_TIMEOUT).await {
+                Ok(Some(update)) => match serde_json::from_str::<Update>(&update) {
+                    Ok(update) => {
+                        if tx.send(Ok(update)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => tracing::error!("Failed to parse Telegram update: {}", e),
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!("Failed to take Telegram update: {}", e);
+                    tokio::time::sleep(POP_TIMEOUT).await;
+                }
+            }
+        }
+    });
+
+    Ok(StatefulListener::new(
+        (UnboundedReceiverStream::new(rx), stop_token),
+        stream_of,
+        |state: &mut (_, StopToken)| state.1.clone(),
+    ))
+}
@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Result};
+use async_zmq::zmq;
+use common::cfg::BrokerCfg;
+use common::queues::{
+    admin_socket, server_socket,
+    wire::{self, WIRE_VERSION},
+    BrokerMessage,
+};
+use common::storage::{BrokerQueueStats, StoredBrokerMessage};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+use crate::consumers::SubscriberInfo;
+use crate::report::{print_message, timestamp};
+use crate::Broker;
+
+const USAGE: &str = "usage: broker admin <stats | message <id> | pause | resume | drain>\n\
+                     pause is kept across broker restarts, drain is not";
+const ADMIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// What the broker does with messages, changed for maintenance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryState {
+    /// Tasks are accepted and delivered
+    Running,
+    /// Tasks are accepted and kept until delivery is resumed, also after a restart
+    Paused,
+    /// New tasks are turned away while queued ones are delivered, the broker can be stopped once
+    /// they are acked
+    Draining,
+}
+
+impl DeliveryState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryState::Running => "running",
+            DeliveryState::Paused => "paused",
+            DeliveryState::Draining => "draining",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminRequest {
+    Stats,
+    Message(Uuid),
+    Pause,
+    Resume,
+    Drain,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MessageLookup {
+    Unacked(StoredBrokerMessage),
+    DeadLetter {
+        message: BrokerMessage,
+        reason: String,
+        failed_at: i64,
+    },
+    NotFound,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminReply {
+    Stats {
+        state: DeliveryState,
+        queues: Vec<BrokerQueueStats>,
+        subscribers: Vec<SubscriberInfo>,
+    },
+    Message(MessageLookup),
+    State(DeliveryState),
+    Error(String),
+}
+
+impl Broker {
+    async fn handle_admin_request(&self, request: AdminRequest) -> Result<AdminReply> {
+        let reply = match request {
+            AdminRequest::Stats => {
+                let now = chrono::Utc::now().timestamp();
+                AdminReply::Stats {
+                    state: self.delivery_state(),
+                    queues: self.storage.get_broker_queue_stats(now).await?,
+                    subscribers: self.consumers.lock().unwrap().list(),
+                }
+            }
+            AdminRequest::Message(id) => {
+                let lookup = match self.storage.get_broker_message(&id).await? {
+                    Some(stored) => MessageLookup::Unacked(stored),
+                    None => match self.storage.get_dead_letter(&id).await? {
+                        Some(dead_letter) => MessageLookup::DeadLetter {
+                            message: dead_letter.message,
+                            reason: dead_letter.reason,
+                            failed_at: dead_letter.failed_at,
+                        },
+                        None => MessageLookup::NotFound,
+                    },
+                };
+                AdminReply::Message(lookup)
+            }
+            AdminRequest::Pause => self.set_delivery_state(DeliveryState::Paused).await?,
+            AdminRequest::Resume => self.set_delivery_state(DeliveryState::Running).await?,
+            AdminRequest::Drain => self.set_delivery_state(DeliveryState::Draining).await?,
+        };
+        Ok(reply)
+    }
+
+    async fn set_delivery_state(&self, state: DeliveryState) -> Result<AdminReply> {
+        self.storage
+            .set_broker_paused(state == DeliveryState::Paused)
+            .await?;
+        tracing::warn!("Broker delivery is {}", state.as_str());
+        *self.state.lock().unwrap() = state;
+        Ok(AdminReply::State(state))
+    }
+
+    pub fn delivery_state(&self) -> DeliveryState {
+        *self.state.lock().unwrap()
+    }
+
+    pub async fn admin_thread(&self, run: Arc<AtomicBool>) -> Result<()> {
+        let cfg = &self.cfg.broker;
+        let endpoint = format!("tcp://{}:{}", cfg.admin_address, cfg.admin_port);
+        let mut admin_sock: async_zmq::Reply<std::vec::IntoIter<Vec<u8>>, Vec<u8>> =
+            async_zmq::Reply::from(server_socket(&self.context, cfg, zmq::REP, &endpoint)?);
+
+        loop {
+            if !run.load(Ordering::Relaxed) {
+                break;
+            }
+
+            tokio::select! {
+                Some(Ok(messages)) = admin_sock.next() => {
+                    let frame = messages.last().map(|msg| msg.to_vec()).unwrap_or_default();
+                    let (version, reply) = match wire::decode_versioned::<AdminRequest>(&frame) {
+                        Ok((version, request)) => {
+                            tracing::info!("Admin request: {:?}", request);
+                            let reply = self
+                                .handle_admin_request(request)
+                                .await
+                                .unwrap_or_else(|e| AdminReply::Error(e.to_string()));
+                            (version, reply)
+                        }
+                        Err(e) => (WIRE_VERSION, AdminReply::Error(e.to_string())),
+                    };
+                    admin_sock.send(vec![wire::encode(&reply, version)?]).await?;
+                }
+                _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn request(cfg: &BrokerCfg, request: &AdminRequest) -> Result<AdminReply> {
+    let endpoint = format!("tcp://{}:{}", cfg.admin_address, cfg.admin_port);
+    let socket = admin_socket(cfg, zmq::REQ, &endpoint)?;
+    socket.set_rcvtimeo(ADMIN_TIMEOUT.as_millis() as i32)?;
+    socket.send(wire::encode(request, WIRE_VERSION)?, 0)?;
+    let reply = socket
+        .recv_bytes(0)
+        .map_err(|e| anyhow!("Broker did not reply: {}", e))?;
+    match wire::decode(&reply)? {
+        AdminReply::Error(error) => Err(anyhow!("Broker rejected request: {}", error)),
+        reply => Ok(reply),
+    }
+}
+
+/// Handles `broker admin ...` commands, `args` are the ones after `admin`
+pub fn run(cfg: &BrokerCfg, args: &[String]) -> Result<()> {
+    let admin_request = match args.first().map(|arg| arg.as_str()) {
+        Some("stats") => AdminRequest::Stats,
+        Some("message") => {
+            let id = args.get(1).ok_or(anyhow!(USAGE))?;
+            AdminRequest::Message(Uuid::parse_str(id)?)
+        }
+        Some("pause") => AdminRequest::Pause,
+        Some("resume") => AdminRequest::Resume,
+        Some("drain") => AdminRequest::Drain,
+        _ => return Err(anyhow!(USAGE)),
+    };
+
+    match request(cfg, &admin_request)? {
+        AdminReply::Stats {
+            state,
+            queues,
+            subscribers,
+        } => {
+            println!("delivery: {}", state.as_str());
+            for queue in &queues {
+                println!(
+                    "{}\t{}\tqueued: {}\tin flight: {}\toldest: {}s",
+                    queue.topic,
+                    queue.priority.as_str(),
+                    queue.queued,
+                    queue.in_flight,
+                    queue.oldest_age
+                );
+            }
+            println!("{} subscribers", subscribers.len());
+            for subscriber in &subscribers {
+                let topics: Vec<&str> = subscriber.topics.iter().map(|t| t.as_str()).collect();
+                println!(
+                    "{}\t{}\tseen {}s ago",
+                    subscriber.address,
+                    topics.join(","),
+                    subscriber.last_seen
+                );
+            }
+        }
+        AdminReply::Message(MessageLookup::Unacked(stored)) => {
+            println!(
+                "unacked, published at {}\nconsumer: {}\nvisible at: {}",
+                timestamp(stored.created_at),
+                stored.consumer.as_deref().unwrap_or("-"),
+                timestamp(stored.visible_at)
+            );
+            print_message(&stored.message)?;
+        }
+        AdminReply::Message(MessageLookup::DeadLetter {
+            message,
+            reason,
+            failed_at,
+        }) => {
+            println!("dead letter since {}: {}", timestamp(failed_at), reason);
+            print_message(&message)?;
+        }
+        AdminReply::Message(MessageLookup::NotFound) => {
+            println!("Message not found, it was acked or never published")
+        }
+        AdminReply::State(state) => println!("delivery: {}", state.as_str()),
+        AdminReply::Error(error) => return Err(anyhow!(error)),
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use common::queues::{wire::WIRE_VERSION, Subscription, Topic, SUBSCRIPTION_INTERVAL};
+
+/// Consumer which did not renew its subscription for this long is considered gone
+const CONSUMER_TTL: std::time::Duration =
+    std::time::Duration::from_secs(SUBSCRIPTION_INTERVAL.as_secs() * 3);
+
+/// Consumer as reported by the admin endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscriberInfo {
+    pub address: String,
+    pub topics: Vec<Topic>,
+    /// Seconds since the subscription was renewed
+    pub last_seen: u64,
+}
+
+struct Consumer {
+    subscription: Subscription,
+    last_seen: Instant,
+}
+
+/// Subscribers known to the broker. Every message is published to one of them,
+/// so consumers subscribed to the same topic share the load
+#[derive(Default)]
+pub struct Consumers {
+    consumers: HashMap<String, Consumer>,
+    next: usize,
+}
+
+impl Consumers {
+    /// Returns true if the consumer was not known or had expired
+    pub fn register(&mut self, subscription: Subscription) -> bool {
+        let address = subscription.address();
+        let fresh = match self.consumers.get(&address) {
+            Some(consumer) => consumer.last_seen.elapsed() > CONSUMER_TTL,
+            None => true,
+        };
+        self.consumers.insert(
+            address,
+            Consumer {
+                subscription,
+                last_seen: Instant::now(),
+            },
+        );
+        fresh
+    }
+
+    /// Wire format version messages are published to the consumer in
+    pub fn wire_version(&self, address: &str) -> u16 {
+        self.consumers.get(address).map_or(0, |consumer| {
+            consumer.subscription.wire_version.min(WIRE_VERSION)
+        })
+    }
+
+    /// Alive consumers ordered by address
+    pub fn list(&self) -> Vec<SubscriberInfo> {
+        let mut subscribers: Vec<SubscriberInfo> = self
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| consumer.last_seen.elapsed() <= CONSUMER_TTL)
+            .map(|(address, consumer)| SubscriberInfo {
+                address: address.clone(),
+                topics: consumer.subscription.topics.clone(),
+                last_seen: consumer.last_seen.elapsed().as_secs(),
+            })
+            .collect();
+        subscribers.sort_by(|a, b| a.address.cmp(&b.address));
+        subscribers
+    }
+
+    /// Picks address of an alive consumer of the topic, in turns
+    pub fn pick(&mut self, topic: Topic) -> Option<String> {
+        self.consumers
+            .retain(|_, consumer| consumer.last_seen.elapsed() <= CONSUMER_TTL);
+
+        let mut candidates: Vec<&String> = self
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| consumer.subscription.topics.contains(&topic))
+            .map(|(address, _)| address)
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        candidates.sort();
+        self.next = self.next.wrapping_add(1);
+        Some(candidates[self.next % candidates.len()].clone())
+    }
+}
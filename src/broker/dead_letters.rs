@@ -0,0 +1,86 @@
+use anyhow::{anyhow, Result};
+use common::queues::{BrokerClient, BrokerMessagePayload, DeadLetterMessage};
+use common::storage::Storage;
+use uuid::Uuid;
+
+use crate::report::{print_message, recipient, timestamp};
+
+const USAGE: &str = "usage: broker dead-letters <list | show <id> | requeue <id> | purge [<id>]>";
+
+fn parse_id(arg: Option<&String>) -> Result<Uuid> {
+    let arg = arg.ok_or(anyhow!(USAGE))?;
+    Ok(Uuid::parse_str(arg)?)
+}
+
+fn print_summary(dead_letter: &DeadLetterMessage) {
+    println!(
+        "{}\t{}\tto: {}\tattempts: {}\t{}",
+        dead_letter.message.message_id,
+        timestamp(dead_letter.failed_at),
+        recipient(&dead_letter.message),
+        dead_letter.message.attempt,
+        dead_letter.reason
+    );
+}
+
+/// Handles `broker dead-letters ...` admin commands, `args` are the ones after `dead-letters`
+pub async fn run(storage: &Storage, broker: &BrokerClient, args: &[String]) -> Result<()> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("list") => {
+            let dead_letters = storage.get_dead_letters().await?;
+            for dead_letter in &dead_letters {
+                print_summary(dead_letter);
+            }
+            println!("{} dead letters", dead_letters.len());
+        }
+        Some("show") => {
+            let id = parse_id(args.get(1))?;
+            let dead_letter = storage
+                .get_dead_letter(&id)
+                .await?
+                .ok_or(anyhow!("Dead letter {} not found", id))?;
+            println!(
+                "dead letter since {}: {}",
+                timestamp(dead_letter.failed_at),
+                dead_letter.reason
+            );
+            print_message(&dead_letter.message)?;
+        }
+        Some("requeue") => {
+            let id = parse_id(args.get(1))?;
+            let dead_letter = storage
+                .get_dead_letter(&id)
+                .await?
+                .ok_or(anyhow!("Dead letter {} not found", id))?;
+            // Published as a new message with fresh delivery attempts
+            match dead_letter.message.payload {
+                BrokerMessagePayload::Tasks(task) => broker.publish(task).await?,
+            }
+            storage.purge_dead_letters(Some(&id)).await?;
+            println!("{} requeued", id);
+        }
+        Some("purge") => {
+            let id = match args.get(1) {
+                Some(_) => Some(parse_id(args.get(1))?),
+                None => None,
+            };
+            let removed = storage.purge_dead_letters(id.as_ref()).await?;
+            println!("{} dead letters removed", removed);
+        }
+        _ => return Err(anyhow!(USAGE)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_valid_id() {
+        let id = Uuid::new_v4();
+        assert_eq!(parse_id(Some(&id.to_string())).unwrap(), id);
+        assert_eq!(parse_id(None).unwrap_err().to_string(), USAGE);
+        assert!(parse_id(Some(&"42".to_owned())).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use common::cfg::BrokerCfg;
+use common::queues::Priority;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// Encoded message together with the frame it is published under: consumer address and topic
+pub type Outgoing = (String, Vec<u8>);
+
+/// Queues of messages waiting to be published, one per priority
+#[derive(Clone)]
+pub struct Lanes {
+    high: Sender<Outgoing>,
+    normal: Sender<Outgoing>,
+}
+
+pub struct LaneReceivers {
+    high: Receiver<Outgoing>,
+    normal: Receiver<Outgoing>,
+}
+
+pub fn open(cfg: &BrokerCfg) -> (Lanes, LaneReceivers) {
+    let (high, high_rx) = channel(cfg.lane_capacity(Priority::High).max(1));
+    let (normal, normal_rx) = channel(cfg.lane_capacity(Priority::Normal).max(1));
+    (
+        Lanes { high, normal },
+        LaneReceivers {
+            high: high_rx,
+            normal: normal_rx,
+        },
+    )
+}
+
+impl Lanes {
+    pub async fn send(&self, priority: Priority, outgoing: Outgoing) {
+        let lane = match priority {
+            Priority::High => &self.high,
+            Priority::Normal => &self.normal,
+        };
+        let _ = lane.send(outgoing).await;
+    }
+}
+
+impl LaneReceivers {
+    /// Next message to publish, normal ones only when no high priority message is waiting
+    pub async fn recv(&mut self) -> Option<Outgoing> {
+        tokio::select! {
+            biased;
+            Some(outgoing) = self.high.recv() => Some(outgoing),
+            Some(outgoing) = self.normal.recv() => Some(outgoing),
+            else => None,
+        }
+    }
+}
@@ -1,64 +1,294 @@
+mod admin;
 mod cfg;
+mod consumers;
+mod dead_letters;
+mod lanes;
+mod report;
 
+use std::collections::HashMap;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex,
 };
 
+use admin::DeliveryState;
 use anyhow::Result;
-use async_zmq::SinkExt;
+use async_zmq::{zmq, SinkExt};
 use cfg::BrokerSvcCfg;
 use common::{
     cfg::build_config,
     ctrlc_handler::set_ctrlc_handler,
-    queues::{BrokerMessage, BrokerRequest},
+    queues::{
+        generate_keypair, server_socket, start_authenticator,
+        wire::{self, WIRE_VERSION},
+        BrokerClient, BrokerMessage, BrokerMessagePayload, BrokerReply, BrokerRequest,
+        BrokerRequestPayload, Priority,
+    },
+    storage::Storage,
 };
+use consumers::Consumers;
+use lanes::{LaneReceivers, Lanes};
 use tokio_stream::StreamExt;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer, Registry};
 
+const REDELIVERY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct Broker {
     cfg: BrokerSvcCfg,
+    storage: Storage,
+    consumers: Arc<Mutex<Consumers>>,
+    state: Arc<Mutex<DeliveryState>>,
+    /// Shared by the sockets and their authenticator
+    context: zmq::Context,
 }
 
 impl Broker {
-    pub fn new(cfg: BrokerSvcCfg) -> Result<Self> {
-        Ok(Self { cfg })
+    pub async fn new(cfg: BrokerSvcCfg) -> Result<Self> {
+        let storage = Storage::new(&cfg.storage).await?;
+        let context = zmq::Context::new();
+        start_authenticator(&context, &cfg.broker)?;
+        let state = match storage.is_broker_paused().await? {
+            true => {
+                tracing::warn!("Broker delivery was paused before restart, it stays paused");
+                DeliveryState::Paused
+            }
+            false => DeliveryState::Running,
+        };
+        Ok(Self {
+            cfg,
+            storage,
+            consumers: Default::default(),
+            state: Arc::new(Mutex::new(state)),
+            context,
+        })
     }
 
-    async fn rep_thread(
+    /// Publishes the message to one of its topic consumers, or to the given one, and hides it
+    /// from redelivery until the visibility timeout passes. Deferred tasks stay hidden until
+    /// they are due. Without consumers the message waits for the redelivery loop
+    async fn deliver(
         &self,
-        run: Arc<AtomicBool>,
-        tx: tokio::sync::mpsc::Sender<BrokerMessage>,
+        mut m: BrokerMessage,
+        address: Option<String>,
+        lanes: &Lanes,
     ) -> Result<()> {
-        let mut rep_sock =
-            async_zmq::reply(&format!("tcp://*:{}", self.cfg.broker.rep_port))?.bind()?;
+        if self.delivery_state() == DeliveryState::Paused {
+            return Ok(());
+        }
+
+        let address = match address {
+            Some(address) => address,
+            None => match self.consumers.lock().unwrap().pick(m.topic()) {
+                Some(address) => address,
+                None => {
+                    tracing::debug!("No consumers of {} yet", m.topic().as_str());
+                    return Ok(());
+                }
+            },
+        };
+
+        let now = chrono::Utc::now();
+        let due_at = m.deferred_until().map_or(now, |until| until.max(now));
+        let visible_at = due_at.timestamp() + self.cfg.broker.visibility_timeout.as_secs() as i64;
+        match self
+            .storage
+            .mark_broker_message_delivered(&m.message_id, visible_at, &address)
+            .await?
+        {
+            Some(attempt) => {
+                m.attempt = attempt;
+                tracing::debug!("Publishing to {}: {:?}", address, m);
+                let version = self.consumers.lock().unwrap().wire_version(&address);
+                let data = wire::encode(&m, version)?;
+                let frame = format!("{}{}", address, m.topic().as_str());
+                lanes.send(m.priority(), (frame, data)).await;
+            }
+            None => tracing::debug!("Message {} was acked meanwhile", m.message_id),
+        }
+        Ok(())
+    }
+
+    /// Tasks are stored before being published and stay stored until the subscriber acks them
+    async fn handle_request(&self, request: BrokerRequest, lanes: &Lanes) -> Result<()> {
+        match request.payload {
+            BrokerRequestPayload::Tasks(task) => {
+                let m = BrokerMessage {
+                    message_id: request.id,
+                    payload: BrokerMessagePayload::Tasks(task),
+                    attempt: 0,
+                };
+                // Retried request must not publish the message again
+                if self.storage.save_broker_message(&m).await? {
+                    self.deliver(m, None, lanes).await?;
+                }
+            }
+            BrokerRequestPayload::Ack(ack) => {
+                if !self.storage.ack_broker_message(&ack.message_id).await? {
+                    tracing::debug!("Ack for unknown message {}", ack.message_id);
+                }
+            }
+            BrokerRequestPayload::DeadLetter(dead_letter) => {
+                tracing::warn!(
+                    "Message {} moved to dead letters: {}",
+                    dead_letter.message_id,
+                    dead_letter.reason
+                );
+                self.storage
+                    .dead_letter_broker_message(&dead_letter.message_id, &dead_letter.reason)
+                    .await?;
+            }
+            BrokerRequestPayload::Subscribe(subscription) => {
+                let address = subscription.address();
+                if self.consumers.lock().unwrap().register(subscription) {
+                    // Consumer restarted and lost messages it held
+                    let messages = self.storage.get_assigned_broker_messages(&address).await?;
+                    tracing::info!(
+                        "Consumer {} subscribed, replaying {} unacked messages",
+                        address,
+                        messages.len()
+                    );
+                    for m in messages {
+                        self.deliver(m, Some(address.clone()), lanes).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the reason the tasks of the requests can not be taken: a full lane or draining
+    async fn turn_away_reason(&self, requests: &[BrokerRequest]) -> Result<Option<String>> {
+        let mut incoming: HashMap<Priority, u64> = HashMap::new();
+        for request in requests {
+            if let BrokerRequestPayload::Tasks(task) = &request.payload {
+                *incoming.entry(task.priority()).or_default() += 1;
+            }
+        }
+        if incoming.is_empty() {
+            return Ok(None);
+        }
+        if self.delivery_state() == DeliveryState::Draining {
+            return Ok(Some("broker is draining".into()));
+        }
+
+        let stored = self.storage.count_broker_messages().await?;
+        for (priority, count) in incoming {
+            let held = stored.get(&priority).copied().unwrap_or_default();
+            if held + count > self.cfg.broker.lane_capacity(priority) as u64 {
+                return Ok(Some(format!("{} priority lane is full", priority.as_str())));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn redeliver_expired(&self, lanes: &Lanes) -> Result<()> {
+        if self.delivery_state() == DeliveryState::Paused {
+            return Ok(());
+        }
+        let now = chrono::Utc::now().timestamp();
+        for m in self.storage.get_expired_broker_messages(now).await? {
+            if m.attempt >= self.cfg.broker.max_attempts {
+                let reason = format!("Not acked after {} deliveries", m.attempt);
+                tracing::warn!("Message {} moved to dead letters: {}", m.message_id, reason);
+                self.storage
+                    .dead_letter_broker_message(&m.message_id, &reason)
+                    .await?;
+                continue;
+            }
+            if m.attempt > 0 {
+                tracing::warn!(
+                    "Message {} was not acked after {} deliveries, redelivering",
+                    m.message_id,
+                    m.attempt
+                );
+            }
+            self.deliver(m, None, lanes).await?;
+        }
+        Ok(())
+    }
 
+    async fn redelivery_thread(&self, run: Arc<AtomicBool>, lanes: Lanes) -> Result<()> {
         loop {
-            if run.load(Ordering::Relaxed) == false {
+            if !run.load(Ordering::Relaxed) {
+                break;
+            }
+
+            if let Err(e) = self.redeliver_expired(&lanes).await {
+                tracing::error!("Failed to redeliver expired messages: {}", e);
+            }
+
+            tokio::time::sleep(REDELIVERY_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    async fn rep_thread(&self, run: Arc<AtomicBool>, lanes: Lanes) -> Result<()> {
+        let endpoint = format!("tcp://*:{}", self.cfg.broker.rep_port);
+        let mut rep_sock: async_zmq::Reply<std::vec::IntoIter<Vec<u8>>, Vec<u8>> =
+            async_zmq::Reply::from(server_socket(
+                &self.context,
+                &self.cfg.broker,
+                zmq::REP,
+                &endpoint,
+            )?);
+
+        loop {
+            if !run.load(Ordering::Relaxed) {
                 break;
             }
 
             tokio::select! {
                 Some(Ok(messages)) = rep_sock.next() => {
-                    for msg in messages {
-                        let data: Vec<u8> = msg.as_str().map(|str| str.as_bytes().to_vec()).ok_or(anyhow::anyhow!(""))?;
-                        let r: BrokerRequest = serde_json::from_slice(&data)?;
-
-                        match r.payload {
-                            common::queues::BrokerRequestPayload::Tasks(task) => {
-                                let m = BrokerMessage {
-                                    message_id: r.id,
-                                    payload: common::queues::BrokerMessagePayload::Tasks(task)
-                                };
-                                let _ = tx.send(m).await;
-                            },
-                            common::queues::BrokerRequestPayload::Ack(_ack) => { continue; },
+                    // Every frame is a request, the batch gets a single reply
+                    let mut reply = BrokerReply {
+                        id: uuid::Uuid::nil(),
+                        error: None,
+                        busy: false,
+                    };
+                    // Reply goes in the version the client talks
+                    let mut version = WIRE_VERSION;
+                    let mut requests = Vec::with_capacity(messages.len());
+                    for (index, msg) in messages.iter().enumerate() {
+                        match wire::decode_versioned::<BrokerRequest>(msg) {
+                            Ok((request_version, r)) => {
+                                if index == 0 {
+                                    version = request_version;
+                                    reply.id = r.id;
+                                }
+                                requests.push(r);
+                            }
+                            Err(e) => {
+                                tracing::error!("Rejected broker request: {}", e);
+                                reply.error = Some(format!("Rejected request: {}", e));
+                            }
                         }
                     }
 
-                    rep_sock.send("").await?;
+                    // Tasks of the batch are taken or turned away together,
+                    // so the producer can simply publish all of them again later
+                    match self.turn_away_reason(&requests).await {
+                        Ok(Some(error)) => {
+                            tracing::warn!("{}, tasks are turned away", error);
+                            reply.busy = true;
+                            reply.error = Some(error);
+                            requests.retain(|r| {
+                                !matches!(r.payload, BrokerRequestPayload::Tasks(_))
+                            });
+                        }
+                        Ok(None) => {}
+                        Err(e) => tracing::error!("Could not check lane capacity: {}", e),
+                    }
+
+                    for r in requests {
+                        if let Err(e) = self.handle_request(r, &lanes).await {
+                            tracing::error!("Could not handle broker request: {}", e);
+                            reply.error = Some(e.to_string());
+                        }
+                    }
+
+                    rep_sock.send(vec![wire::encode(&reply, version)?]).await?;
                 }
                 _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {}
             }
@@ -66,24 +296,23 @@ impl Broker {
         Ok(())
     }
 
-    async fn pub_thread(
-        &self,
-        run: Arc<AtomicBool>,
-        mut rx: tokio::sync::mpsc::Receiver<BrokerMessage>,
-    ) -> Result<()> {
-        let mut pub_sock =
-            async_zmq::publish(&format!("tcp://*:{}", self.cfg.broker.pub_port))?.bind()?;
+    async fn pub_thread(&self, run: Arc<AtomicBool>, mut lanes: LaneReceivers) -> Result<()> {
+        let endpoint = format!("tcp://*:{}", self.cfg.broker.pub_port);
+        let mut pub_sock = async_zmq::Publish::from(server_socket(
+            &self.context,
+            &self.cfg.broker,
+            zmq::PUB,
+            &endpoint,
+        )?);
 
         loop {
-            if run.load(Ordering::Relaxed) == false {
+            if !run.load(Ordering::Relaxed) {
                 break;
             }
 
             tokio::select! {
-                Some(msg) = rx.recv() => {
-                    tracing::debug!("pub_thread received message: {:?}", msg);
-                    let topic: Vec<u8> = "tasks".as_bytes().to_vec();
-                    let data = serde_json::to_vec(&msg)?;
+                Some((frame, data)) = lanes.recv() => {
+                    let topic: Vec<u8> = frame.into_bytes();
                     pub_sock.send(vec![topic, data].into()).await?;
                 }
                 _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {},
@@ -94,24 +323,72 @@ impl Broker {
 }
 
 async fn main_impl() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Does not need configuration, keys are generated before it is written
+    if args.first().map(|arg| arg.as_str()) == Some("keygen") {
+        let (public_key, secret_key) = generate_keypair()?;
+        println!("public_key: '{}'", public_key);
+        println!("secret_key: '{}'", secret_key);
+        return Ok(());
+    }
+
+    let cfg = build_config::<BrokerSvcCfg>()?;
+    if args.first().map(|arg| arg.as_str()) == Some("dead-letters") {
+        let storage = Storage::new(&cfg.storage).await?;
+        let broker = BrokerClient::new(cfg.broker.clone(), storage.clone())?;
+        return dead_letters::run(&storage, &broker, &args[1..]).await;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("admin") {
+        return admin::run(&cfg.broker, &args[1..]);
+    }
+
     let r = Arc::new(AtomicBool::new(true));
     set_ctrlc_handler(r.clone())?;
 
-    let cfg = build_config::<BrokerSvcCfg>()?;
-    let broker = Broker::new(cfg)?;
-    let (tx, rx) = tokio::sync::mpsc::channel::<BrokerMessage>(1);
+    let broker = Broker::new(cfg).await?;
+    let (lanes, receivers) = lanes::open(&broker.cfg.broker);
 
     let b = broker.clone();
     let r2 = r.clone();
     tokio::spawn(async move {
-        if let Err(e) = b.pub_thread(r2, rx).await {
+        if let Err(e) = b.pub_thread(r2, receivers).await {
             tracing::warn!("broker::pub_thread finished with error: {}", e);
         }
     });
 
+    // Covers messages published while nobody was subscribed and the ones lost on restart
+    let b = broker.clone();
+    let r2 = r.clone();
+    let redelivery_lanes = lanes.clone();
+    tokio::spawn(async move {
+        if let Err(e) = b.redelivery_thread(r2, redelivery_lanes).await {
+            tracing::warn!("broker::redelivery_thread finished with error: {}", e);
+        }
+    });
+
+    // REP socket can not be shared between threads, so the admin loop is not a task
+    // of the multi-threaded runtime but gets a thread with a runtime of its own
+    let b = broker.clone();
+    let r2 = r.clone();
+    std::thread::spawn(move || {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                tracing::error!("Failed to create admin runtime: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = rt.block_on(b.admin_thread(r2)) {
+            tracing::warn!("broker::admin_thread finished with error: {}", e);
+        }
+    });
+
     tracing::info!("Broker started");
 
-    broker.rep_thread(r.clone(), tx).await?;
+    broker.rep_thread(r.clone(), lanes).await?;
 
     Ok(())
 }
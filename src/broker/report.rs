@@ -0,0 +1,38 @@
+//! Output of `broker admin` and `broker dead-letters` commands
+
+use anyhow::Result;
+use common::queues::{BrokerMessage, BrokerMessagePayload, Tasks};
+use teloxide_core::types::UserId;
+
+pub fn timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
+pub fn recipient(message: &BrokerMessage) -> UserId {
+    match &message.payload {
+        BrokerMessagePayload::Tasks(Tasks::TelegramMessageTask(task)) => task.to,
+        BrokerMessagePayload::Tasks(Tasks::NotificationUpdateTask(task)) => task.to,
+    }
+}
+
+/// Prints where the message goes and the task it carries
+pub fn print_message(message: &BrokerMessage) -> Result<()> {
+    println!(
+        "id: {}\ntopic: {}\npriority: {}\nattempts: {}",
+        message.message_id,
+        message.topic().as_str(),
+        message.priority().as_str(),
+        message.attempt
+    );
+    match &message.payload {
+        BrokerMessagePayload::Tasks(Tasks::TelegramMessageTask(task)) => {
+            println!("{}", serde_json::to_string_pretty(task)?);
+        }
+        BrokerMessagePayload::Tasks(Tasks::NotificationUpdateTask(task)) => {
+            println!("{}", serde_json::to_string_pretty(task)?);
+        }
+    }
+    Ok(())
+}
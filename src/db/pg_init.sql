@@ -21,3 +21,50 @@ create table if not exists "working_hours" (
 		   and "start" < "end"),
 	foreign key ("id") references "users" ( "id" )
 );
+
+alter table "working_hours" add column if not exists "utc_offset" integer default 180 not null;
+
+alter table "mail_accounts" add column if not exists "protocol" text default 'imap' not null;
+alter table "mail_accounts" add column if not exists "host" text;
+alter table "mail_accounts" add column if not exists "port" integer;
+
+create table if not exists "broker_messages" (
+	"id" text not null unique,
+	"payload" bytea not null,
+	"created_at" timestamptz default now() not null
+);
+
+alter table "broker_messages" add column if not exists "attempts" integer default 0 not null;
+alter table "broker_messages" add column if not exists "visible_at" bigint default 0 not null;
+
+create table if not exists "dead_letters" (
+	"id" text not null unique,
+	"payload" bytea not null,
+	"attempts" integer default 0 not null,
+	"reason" text not null,
+	"failed_at" bigint default extract(epoch from now())::bigint not null
+);
+
+alter table "broker_messages" add column if not exists "consumer" text;
+
+alter table "broker_messages" add column if not exists "priority" text default 'normal' not null;
+alter table "broker_messages" add column if not exists "topic" text default 'telegram.message' not null;
+
+alter table "users" add column if not exists "notification_template" text;
+alter table "users" add column if not exists "language" text;
+
+
+create table if not exists "chat_routes" (
+	"id" bigint not null,
+	"scope" text not null,
+	"chat_id" bigint not null,
+	"thread_id" integer,
+	"title" text not null,
+	unique ("id", "scope"),
+	foreign key ("id") references "users" ( "id" )
+);
+
+alter table "mail_accounts" add column if not exists "silent_routine" bool default false not null;
+alter table "mail_accounts" add column if not exists "soft_hours_start" integer;
+alter table "mail_accounts" add column if not exists "soft_hours_end" integer;
+alter table "mail_accounts" add column if not exists "deliver_off_hours" bool default false not null;
@@ -1,6 +1,10 @@
 use anyhow::{anyhow, Result};
-use config::{Config, Environment, File};
+use config::{Config, Environment, File, Map, Value};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::queues::Priority;
 
 #[derive(Clone)]
 pub struct WebCfg {
@@ -58,6 +62,8 @@ impl TryFrom<&Config> for StorageCfg {
 #[derive(Clone)]
 pub struct BotCfg {
     pub token: String,
+    /// Updates come through the webhook instead of long polling when set
+    pub webhook: Option<WebhookCfg>,
 }
 
 impl TryFrom<&Config> for BotCfg {
@@ -65,7 +71,154 @@ impl TryFrom<&Config> for BotCfg {
 
     fn try_from(cfg: &Config) -> std::result::Result<Self, Self::Error> {
         let token = cfg.get_string("bot.secret")?;
-        Ok(BotCfg { token })
+        let webhook = match cfg.get_table("bot.webhook") {
+            Ok(table) => Some(WebhookCfg::try_from(&table)?),
+            Err(_) => None,
+        };
+        Ok(BotCfg { token, webhook })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookMode {
+    /// The bot process serves the webhook itself
+    Standalone,
+    /// The web server takes updates and hands them over to bot instances through Redis
+    WebServer,
+}
+
+impl std::str::FromStr for WebhookMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "standalone" => Ok(WebhookMode::Standalone),
+            "web_server" => Ok(WebhookMode::WebServer),
+            _ => Err(anyhow!("Unknown webhook mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookCfg {
+    pub mode: WebhookMode,
+    /// Public URL Telegram sends updates to
+    pub url: String,
+    /// Path the webhook is served on, the one of `url` unless the reverse proxy rewrites it
+    pub path: String,
+    /// Sent by Telegram in `X-Telegram-Bot-Api-Secret-Token` header of every update
+    pub secret_token: String,
+    /// Address the standalone webhook listens on
+    pub listen: std::net::SocketAddr,
+}
+
+impl TryFrom<&Map<String, Value>> for WebhookCfg {
+    type Error = anyhow::Error;
+
+    fn try_from(table: &Map<String, Value>) -> std::result::Result<Self, Self::Error> {
+        let get = |key: &str| {
+            table
+                .get(key)
+                .and_then(|value| value.clone().into_string().ok())
+                .filter(|value| !value.is_empty())
+        };
+        let mode = match get("mode") {
+            Some(mode) => mode.parse()?,
+            None => WebhookMode::Standalone,
+        };
+        let url = get("url").ok_or(anyhow!("`bot.webhook.url` is not set"))?;
+        let parsed_url = reqwest::Url::parse(&url)
+            .map_err(|e| anyhow!("`bot.webhook.url` is not valid: {}", e))?;
+        let path = get("path").unwrap_or_else(|| parsed_url.path().to_string());
+        if !path.starts_with('/') {
+            return Err(anyhow!("`bot.webhook.path` must start with '/'"));
+        }
+
+        let secret_token =
+            get("secret_token").ok_or(anyhow!("`bot.webhook.secret_token` is not set"))?;
+        let valid = secret_token.len() <= 256
+            && secret_token
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-');
+        if !valid {
+            return Err(anyhow!(
+                "`bot.webhook.secret_token` must be up to 256 characters of A-Z, a-z, 0-9, _ and -"
+            ));
+        }
+
+        let listen = get("listen")
+            .unwrap_or("0.0.0.0:8443".into())
+            .parse()
+            .map_err(|e| anyhow!("`bot.webhook.listen` is not valid: {}", e))?;
+
+        Ok(WebhookCfg {
+            mode,
+            url,
+            path,
+            secret_token,
+            listen,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsMode {
+    /// TLS is negotiated right after TCP connect (IMAPS/POP3S)
+    Implicit,
+    /// Plain connection is upgraded with STARTTLS/STLS
+    StartTls,
+}
+
+impl std::str::FromStr for TlsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "implicit" => Ok(TlsMode::Implicit),
+            "starttls" => Ok(TlsMode::StartTls),
+            _ => Err(anyhow!("Unknown TLS mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerTlsCfg {
+    pub mode: TlsMode,
+    /// PEM bundle with CA certificates trusted in addition to the native roots
+    pub ca_file: Option<PathBuf>,
+    /// Lowercase hex SHA-256 of the server leaf certificate
+    pub pin_sha256: Option<String>,
+}
+
+impl TryFrom<&Map<String, Value>> for ServerTlsCfg {
+    type Error = anyhow::Error;
+
+    fn try_from(table: &Map<String, Value>) -> std::result::Result<Self, Self::Error> {
+        let get = |key: &str| {
+            table
+                .get(key)
+                .and_then(|value| value.clone().into_string().ok())
+                .filter(|value| !value.is_empty())
+        };
+        let mode = match get("tls") {
+            Some(mode) => mode.parse()?,
+            None => TlsMode::Implicit,
+        };
+        let ca_file: Option<PathBuf> = get("ca_file").map(|path| path.into());
+        if let Some(ca_file) = &ca_file {
+            if !ca_file.exists() {
+                return Err(anyhow!(
+                    "`ca_file` value is not correct: {}",
+                    ca_file.display()
+                ));
+            }
+        }
+        let pin_sha256 = get("pin_sha256").map(|pin| pin.replace(':', "").to_lowercase());
+        Ok(ServerTlsCfg {
+            mode,
+            ca_file,
+            pin_sha256,
+        })
     }
 }
 
@@ -73,6 +226,20 @@ impl TryFrom<&Config> for BotCfg {
 pub struct MailCfg {
     pub address: String,
     pub port: u16,
+    pub tls: ServerTlsCfg,
+    /// TLS settings of particular hosts, `tls` is used for the rest
+    pub servers: HashMap<String, ServerTlsCfg>,
+}
+
+impl MailCfg {
+    pub fn tls_for(&self, host: &str) -> &ServerTlsCfg {
+        self.servers.get(host).unwrap_or(&self.tls)
+    }
+
+    /// Hosts from the config may resolve to internal addresses, the ones given by users may not
+    pub fn is_trusted_host(&self, host: &str) -> bool {
+        host == self.address || self.servers.contains_key(host)
+    }
 }
 
 impl TryFrom<&Config> for MailCfg {
@@ -81,32 +248,191 @@ impl TryFrom<&Config> for MailCfg {
     fn try_from(cfg: &Config) -> std::result::Result<Self, Self::Error> {
         let address = cfg.get_string("mail.address")?;
         let port: u16 = cfg.get_int("mail.port")? as u16;
-        Ok(MailCfg { address, port })
+        let tls = ServerTlsCfg::try_from(&cfg.get_table("mail")?)?;
+        let mut servers = HashMap::new();
+        if let Ok(table) = cfg.get_table("mail.servers") {
+            for (host, value) in table {
+                servers.insert(host, ServerTlsCfg::try_from(&value.into_table()?)?);
+            }
+        }
+        Ok(MailCfg {
+            address,
+            port,
+            tls,
+            servers,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BrokerTransport {
+    /// Separate broker process reached over ZeroMQ
+    Zmq,
+    /// Redis Streams with consumer groups, no broker process is needed
+    Redis,
+}
+
+impl std::str::FromStr for BrokerTransport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "zmq" => Ok(BrokerTransport::Zmq),
+            "redis" => Ok(BrokerTransport::Redis),
+            _ => Err(anyhow!("Unknown broker transport: {}", s)),
+        }
+    }
+}
+
+/// Decodes Z85 encoded CURVE key, as printed by `broker keygen`
+fn curve_key(name: &str, value: &str) -> Result<Vec<u8>> {
+    let key = async_zmq::zmq::z85_decode(value)
+        .map_err(|e| anyhow!("`broker.curve.{}` is not a Z85 key: {}", name, e))?;
+    if key.len() != 32 {
+        return Err(anyhow!("`broker.curve.{}` must be a 32 bytes key", name));
+    }
+    Ok(key)
+}
+
+/// CURVE keys of the broker connection, raw 32 bytes each
+#[derive(Clone)]
+pub struct CurveCfg {
+    /// Clients authenticate the broker with it
+    pub server_public_key: Vec<u8>,
+    /// Set in the broker process only
+    pub server_secret_key: Option<Vec<u8>>,
+    /// Keypair of the client process
+    pub public_key: Option<Vec<u8>>,
+    pub secret_key: Option<Vec<u8>>,
+    /// Public keys of clients the broker accepts connections from
+    pub allowed_clients: Vec<Vec<u8>>,
+}
+
+impl TryFrom<&Map<String, Value>> for CurveCfg {
+    type Error = anyhow::Error;
+
+    fn try_from(table: &Map<String, Value>) -> std::result::Result<Self, Self::Error> {
+        let get = |key: &str| -> Result<Option<Vec<u8>>> {
+            match table
+                .get(key)
+                .and_then(|value| value.clone().into_string().ok())
+                .filter(|value| !value.is_empty())
+            {
+                Some(value) => Ok(Some(curve_key(key, &value)?)),
+                None => Ok(None),
+            }
+        };
+        let server_public_key = get("server_public_key")?
+            .ok_or(anyhow!("`broker.curve.server_public_key` is not set"))?;
+        let server_secret_key = get("server_secret_key")?;
+        let public_key = get("public_key")?;
+        let secret_key = get("secret_key")?;
+        if public_key.is_some() != secret_key.is_some() {
+            return Err(anyhow!(
+                "`broker.curve.public_key` and `broker.curve.secret_key` must be set together"
+            ));
+        }
+
+        let mut allowed_clients = vec![];
+        if let Some(value) = table.get("allowed_clients") {
+            for key in value.clone().into_array()? {
+                allowed_clients.push(curve_key("allowed_clients", &key.into_string()?)?);
+            }
+        }
+        if server_secret_key.is_some() && allowed_clients.is_empty() {
+            return Err(anyhow!(
+                "`broker.curve.allowed_clients` is empty, no client could connect"
+            ));
+        }
+
+        Ok(CurveCfg {
+            server_public_key,
+            server_secret_key,
+            public_key,
+            secret_key,
+            allowed_clients,
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct BrokerCfg {
+    pub transport: BrokerTransport,
+    /// Name of this process among consumers of the same group
+    pub consumer: String,
     pub address: String,
     pub pub_port: u16,
     pub rep_port: u16,
+    /// Control endpoint of the broker, local only unless configured otherwise
+    pub admin_address: String,
+    pub admin_port: u16,
+    /// Time a delivered message stays invisible before it is redelivered unless acked
+    pub visibility_timeout: Duration,
+    /// Deliveries after which an unacked message is moved to dead letters
+    pub max_attempts: u32,
+    /// Unacked messages, deferred ones included, each lane holds before producers are turned away
+    pub high_lane_capacity: usize,
+    pub normal_lane_capacity: usize,
+    /// Encryption and authentication of ZeroMQ connections, plain text if not set
+    pub curve: Option<CurveCfg>,
 }
 
 impl TryFrom<&Config> for BrokerCfg {
     type Error = anyhow::Error;
 
     fn try_from(cfg: &Config) -> std::result::Result<Self, Self::Error> {
-        let address = cfg.get_string("broker.address")?;
-        let pub_port = cfg.get_int("broker.pub_port")? as u16;
-        let rep_port = cfg.get_int("broker.rep_port")? as u16;
+        let transport = match cfg.get_string("broker.transport") {
+            Ok(transport) => transport.parse()?,
+            Err(_) => BrokerTransport::Zmq,
+        };
+        let consumer = cfg
+            .get_string("broker.consumer")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .unwrap_or("default".into());
+        let address = cfg
+            .get_string("broker.address")
+            .unwrap_or("127.0.0.1".into());
+        let pub_port = cfg.get_int("broker.pub_port").unwrap_or(5556) as u16;
+        let rep_port = cfg.get_int("broker.rep_port").unwrap_or(5555) as u16;
+        let admin_address = cfg
+            .get_string("broker.admin_address")
+            .unwrap_or("127.0.0.1".into());
+        let admin_port = cfg.get_int("broker.admin_port").unwrap_or(5557) as u16;
+        let visibility_timeout =
+            Duration::from_secs(cfg.get_int("broker.visibility_timeout").unwrap_or(60) as u64);
+        let max_attempts = cfg.get_int("broker.max_attempts").unwrap_or(10) as u32;
+        let high_lane_capacity = cfg.get_int("broker.lanes.high").unwrap_or(1000) as usize;
+        let normal_lane_capacity = cfg.get_int("broker.lanes.normal").unwrap_or(10000) as usize;
+        let curve = match cfg.get_table("broker.curve") {
+            Ok(table) => Some(CurveCfg::try_from(&table)?),
+            Err(_) => None,
+        };
         Ok(BrokerCfg {
+            transport,
+            consumer,
             address,
             pub_port,
             rep_port,
+            admin_address,
+            admin_port,
+            visibility_timeout,
+            max_attempts,
+            high_lane_capacity,
+            normal_lane_capacity,
+            curve,
         })
     }
 }
 
+impl BrokerCfg {
+    pub fn lane_capacity(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::High => self.high_lane_capacity,
+            Priority::Normal => self.normal_lane_capacity,
+        }
+    }
+}
+
 pub fn build_config<T: TryFrom<Config, Error = anyhow::Error>>() -> Result<T> {
     let cfg = Config::builder()
         .add_source(File::with_name("config"))
@@ -0,0 +1,309 @@
+use chrono::Datelike;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Language user-facing texts are written in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    Ru,
+}
+
+impl Language {
+    pub const ALL: &'static [Language] = &[Language::En, Language::Ru];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Ru => "ru",
+        }
+    }
+
+    /// Language for Telegram `language_code` of the user, English unless we have the one asked for
+    pub fn from_code(code: Option<&str>) -> Language {
+        code.and_then(|code| code.split(['-', '_']).next())
+            .and_then(|code| code.to_lowercase().parse().ok())
+            .unwrap_or_default()
+    }
+
+    fn catalog(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Language::En => EN,
+            Language::Ru => RU,
+        }
+    }
+}
+
+impl std::str::FromStr for Language {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(Language::En),
+            "ru" => Ok(Language::Ru),
+            _ => Err(anyhow::anyhow!("Unknown language: {}", s)),
+        }
+    }
+}
+
+/// Message of the catalog, the English one if the language has none
+pub fn tr(lang: Language, key: &str) -> &'static str {
+    let lookup = |catalog: &'static [(&'static str, &'static str)]| {
+        catalog
+            .iter()
+            .find(|(entry, _)| *entry == key)
+            .map(|(_, text)| *text)
+    };
+    lookup(lang.catalog())
+        .or_else(|| lookup(EN))
+        .unwrap_or_else(|| {
+            tracing::error!("There is no message `{}` in the catalog", key);
+            ""
+        })
+}
+
+/// Message with `{name}` arguments substituted
+pub fn trf(lang: Language, key: &str, args: &[(&str, &(dyn Display + Sync))]) -> String {
+    let mut text = tr(lang, key).to_string();
+    for (name, value) in args {
+        text = text.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    text
+}
+
+/// Whether the text is the message in any of the languages, for matching button presses
+pub fn is_any(key: &str, text: &str) -> bool {
+    Language::ALL.iter().any(|lang| tr(*lang, key) == text)
+}
+
+fn plural_form(lang: Language, count: u64) -> &'static str {
+    match lang {
+        Language::En if count == 1 => "one",
+        Language::En => "many",
+        Language::Ru => match (count % 10, count % 100) {
+            (1, rem) if rem != 11 => "one",
+            (2..=4, rem) if !(12..=14).contains(&rem) => "few",
+            _ => "many",
+        },
+    }
+}
+
+/// Message about `count` things, `key` has `.one`, `.few` and `.many` forms.
+/// `{count}` is substituted along with the other arguments
+pub fn plural(
+    lang: Language,
+    key: &str,
+    count: u64,
+    args: &[(&str, &(dyn Display + Sync))],
+) -> String {
+    let key = format!("{}.{}", key, plural_form(lang, count));
+    let mut args = args.to_vec();
+    args.push(("count", &count));
+    trf(lang, &key, &args)
+}
+
+const MONTHS_EN: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const MONTHS_RU: [&str; 12] = [
+    "января",
+    "февраля",
+    "марта",
+    "апреля",
+    "мая",
+    "июня",
+    "июля",
+    "августа",
+    "сентября",
+    "октября",
+    "ноября",
+    "декабря",
+];
+
+/// Unix timestamp as date and time in UTC, written the way the language does
+pub fn format_time(lang: Language, timestamp: i64) -> String {
+    let time = match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time,
+        None => return String::new(),
+    };
+    let month = time.month0() as usize;
+    match lang {
+        Language::En => format!(
+            "{} {}, {} {} UTC",
+            MONTHS_EN[month],
+            time.day(),
+            time.year(),
+            time.format("%H:%M")
+        ),
+        Language::Ru => format!(
+            "{} {} {}, {} UTC",
+            time.day(),
+            MONTHS_RU[month],
+            time.year(),
+            time.format("%H:%M")
+        ),
+    }
+}
+
+const EN: &[(&str, &str)] = &[
+    ("language.name", "English"),
+    ("language.set", "I will talk to you in English now."),
+    ("language.auto", "I will follow the language of your Telegram app."),
+    ("language.usage", "Usage: /language en, /language ru or /language auto"),
+    ("keyboard.fetch_all", "Fetch all emails"),
+    ("settings.link", "Open settings"),
+    ("mail.no_subject", "No subject"),
+    ("fetch.empty", "There are no messages for you now"),
+    ("fetch.more.one", "{count} more message is waiting, press \"{button}\" again to get it."),
+    ("fetch.more.many", "{count} more messages are waiting, press \"{button}\" again to get them."),
+    ("notify.working_again", "{email} is working again."),
+    ("notify.no_account", "Mail checking was turned off because there is no mail account configured."),
+    ("notification.read", "✓ Read"),
+    ("notification.removed", "🗑 Deleted"),
+    ("problem.auth_failed", "I could not log into {email}: the mail server rejected the login or password. Please update the account settings."),
+    ("problem.unreachable", "The mail server of {email} is not reachable. I will keep trying and let you know when it is back."),
+    ("problem.other", "Checking {email} keeps failing. I will keep trying less often."),
+    ("problem.short.auth_failed", "login is rejected"),
+    ("problem.short.unreachable", "mail server is unreachable"),
+    ("problem.short.other", "checks fail"),
+    ("command.start", "get started"),
+    ("command.help", "list the commands"),
+    ("command.status", "show the mailbox state"),
+    ("command.pause", "stop checking mail"),
+    ("command.resume", "start checking mail again"),
+    ("command.quiet", "hold routine notifications for N hours, /quiet off to stop"),
+    ("command.settings", "show notification settings"),
+    ("command.language", "choose the language: en, ru or auto"),
+    ("command.link", "send mail to this group or topic, /link important for important mail only"),
+    ("command.unlink", "send mail to the private chat again, /unlink important for important mail"),
+    ("help.header", "These commands are supported:"),
+    ("start.greeting", "Hi! I check your mailbox and tell you about new mail here. Important mail comes right away, the rest during your working hours."),
+    ("start.sign_up", "Open the web app to sign up and connect your mail account."),
+    ("start.ready", "You are all set. See /status or /help for what else I can do."),
+    ("not_registered", "You are not signed up yet, open the web app first."),
+    ("status.account", "Mail account: {email}"),
+    ("status.no_account", "Mail account: not configured"),
+    ("status.checking_on", "Checking: on"),
+    ("status.checking_paused", "Checking: paused"),
+    ("status.health_ok", "Health: ok"),
+    ("status.health_failing.one", "Health: {problem} ({count} failed check in a row)"),
+    ("status.health_failing.many", "Health: {problem} ({count} failed checks in a row)"),
+    ("status.last_check", "Last check: {time}"),
+    ("status.never_checked", "Last check: never"),
+    ("status.waiting.one", "{count} notification is waiting"),
+    ("status.waiting.many", "{count} notifications are waiting"),
+    ("status.quiet_until", "Quiet until: {time}"),
+    ("pause.done", "Mail checking is paused. Send /resume to start it again."),
+    ("resume.no_account", "There is no mail account to check, set it up first."),
+    ("resume.done", "Mail checking is on."),
+    ("quiet.off", "Quiet mode is off."),
+    ("quiet.usage", "Usage: /quiet <hours> or /quiet off"),
+    ("quiet.on", "Routine notifications are held until {time}. Important mail still comes right away."),
+    ("settings.working_hours", "Working hours: {start}:00 - {end}:00 (UTC{offset})"),
+    ("settings.important_senders", "Important senders: {list}"),
+    ("settings.important_tags", "Important tags: {list}"),
+    ("settings.language", "Language: {language}"),
+    ("settings.none", "none"),
+    ("settings.routine_silent", "Routine mail: silent"),
+    ("settings.routine_audible", "Routine mail: with sound"),
+    ("settings.off_hours_silent", "Mail outside working hours: delivered silently"),
+    ("settings.off_hours_held", "Mail outside working hours: held until they start"),
+    ("settings.soft_hours", "Silent hours: {start}:00 - {end}:00 (Moscow time)"),
+    ("settings.route", "Mail goes to: {chat}"),
+    ("settings.route_important", "Important mail goes to: {chat}"),
+    ("settings.private_chat", "this chat"),
+    ("group.private_only", "This command works in the private chat with me."),
+    ("link.usage", "Usage: /link or /link important, sent in the group or topic the mail should go to"),
+    ("link.private", "Send /link in the group or forum topic the mail should go to. Add me there first, you must be an admin of it."),
+    ("link.bot_absent", "I am not a member of this chat, add me first."),
+    ("link.not_admin", "Only admins of this chat can route mail into it."),
+    ("link.done", "Mail of {name} will come here now."),
+    ("link.done_important", "Important mail of {name} will come here now."),
+    ("unlink.usage", "Usage: /unlink or /unlink important"),
+    ("unlink.done", "Mail goes to the private chat again."),
+    ("unlink.done_important", "Important mail goes along with the rest again."),
+];
+
+const RU: &[(&str, &str)] = &[
+    ("language.name", "Русский"),
+    ("language.set", "Теперь я говорю с вами по-русски."),
+    ("language.auto", "Я буду говорить на языке вашего приложения Telegram."),
+    ("language.usage", "Использование: /language en, /language ru или /language auto"),
+    ("keyboard.fetch_all", "Получить все письма"),
+    ("settings.link", "Открыть настройки"),
+    ("mail.no_subject", "Без темы"),
+    ("fetch.empty", "Сейчас для вас нет сообщений"),
+    ("fetch.more.one", "Ждёт ещё {count} сообщение, нажмите «{button}» ещё раз, чтобы получить его."),
+    ("fetch.more.few", "Ждут ещё {count} сообщения, нажмите «{button}» ещё раз, чтобы получить их."),
+    ("fetch.more.many", "Ждут ещё {count} сообщений, нажмите «{button}» ещё раз, чтобы получить их."),
+    ("notify.working_again", "{email} снова работает."),
+    ("notify.no_account", "Проверка почты выключена: почтовый ящик не настроен."),
+    ("notification.read", "✓ Прочитано"),
+    ("notification.removed", "🗑 Удалено"),
+    ("problem.auth_failed", "Не удалось войти в {email}: почтовый сервер отклонил логин или пароль. Обновите настройки ящика."),
+    ("problem.unreachable", "Почтовый сервер {email} недоступен. Я продолжу попытки и сообщу, когда он заработает."),
+    ("problem.other", "Проверка {email} раз за разом завершается ошибкой. Я буду пробовать реже."),
+    ("problem.short.auth_failed", "логин отклонён"),
+    ("problem.short.unreachable", "почтовый сервер недоступен"),
+    ("problem.short.other", "проверки завершаются ошибкой"),
+    ("command.start", "начать"),
+    ("command.help", "список команд"),
+    ("command.status", "состояние ящика"),
+    ("command.pause", "остановить проверку почты"),
+    ("command.resume", "возобновить проверку почты"),
+    ("command.quiet", "придержать обычные уведомления на N часов, /quiet off — отменить"),
+    ("command.settings", "настройки уведомлений"),
+    ("command.language", "выбрать язык: en, ru или auto"),
+    ("command.link", "присылать почту в эту группу или тему, /link important — только важную"),
+    ("command.unlink", "присылать почту снова в личный чат, /unlink important — важную"),
+    ("help.header", "Поддерживаются команды:"),
+    ("start.greeting", "Привет! Я проверяю ваш почтовый ящик и сообщаю здесь о новых письмах. Важные приходят сразу, остальные — в рабочие часы."),
+    ("start.sign_up", "Откройте веб-приложение, чтобы зарегистрироваться и подключить почтовый ящик."),
+    ("start.ready", "Всё готово. Что ещё я умею — в /status и /help."),
+    ("not_registered", "Вы ещё не зарегистрированы, сначала откройте веб-приложение."),
+    ("status.account", "Почтовый ящик: {email}"),
+    ("status.no_account", "Почтовый ящик: не настроен"),
+    ("status.checking_on", "Проверка: включена"),
+    ("status.checking_paused", "Проверка: приостановлена"),
+    ("status.health_ok", "Состояние: в порядке"),
+    ("status.health_failing.one", "Состояние: {problem} ({count} неудачная проверка подряд)"),
+    ("status.health_failing.few", "Состояние: {problem} ({count} неудачные проверки подряд)"),
+    ("status.health_failing.many", "Состояние: {problem} ({count} неудачных проверок подряд)"),
+    ("status.last_check", "Последняя проверка: {time}"),
+    ("status.never_checked", "Последняя проверка: ещё не было"),
+    ("status.waiting.one", "Ждёт отправки {count} уведомление"),
+    ("status.waiting.few", "Ждут отправки {count} уведомления"),
+    ("status.waiting.many", "Ждут отправки {count} уведомлений"),
+    ("status.quiet_until", "Тихий режим до {time}"),
+    ("pause.done", "Проверка почты приостановлена. Отправьте /resume, чтобы возобновить её."),
+    ("resume.no_account", "Нет почтового ящика для проверки, сначала настройте его."),
+    ("resume.done", "Проверка почты включена."),
+    ("quiet.off", "Тихий режим выключен."),
+    ("quiet.usage", "Использование: /quiet <часы> или /quiet off"),
+    ("quiet.on", "Обычные уведомления придержаны до {time}. Важные письма по-прежнему приходят сразу."),
+    ("settings.working_hours", "Рабочие часы: {start}:00–{end}:00 (UTC{offset})"),
+    ("settings.important_senders", "Важные отправители: {list}"),
+    ("settings.important_tags", "Важные метки: {list}"),
+    ("settings.language", "Язык: {language}"),
+    ("settings.none", "нет"),
+    ("settings.routine_silent", "Обычные письма: без звука"),
+    ("settings.routine_audible", "Обычные письма: со звуком"),
+    ("settings.off_hours_silent", "Письма вне рабочих часов: приходят без звука"),
+    ("settings.off_hours_held", "Письма вне рабочих часов: ждут начала рабочих часов"),
+    ("settings.soft_hours", "Тихие часы: {start}:00–{end}:00 (по Москве)"),
+    ("settings.route", "Почта приходит в: {chat}"),
+    ("settings.route_important", "Важная почта приходит в: {chat}"),
+    ("settings.private_chat", "этот чат"),
+    ("group.private_only", "Эта команда работает в личном чате со мной."),
+    ("link.usage", "Использование: /link или /link important в группе или теме, куда должна приходить почта"),
+    ("link.private", "Отправьте /link в группе или теме форума, куда должна приходить почта. Сначала добавьте меня туда, вы должны быть её администратором."),
+    ("link.bot_absent", "Я не состою в этом чате, сначала добавьте меня."),
+    ("link.not_admin", "Направлять почту в этот чат могут только его администраторы."),
+    ("link.done", "Теперь почта пользователя {name} будет приходить сюда."),
+    ("link.done_important", "Теперь важная почта пользователя {name} будет приходить сюда."),
+    ("unlink.usage", "Использование: /unlink или /unlink important"),
+    ("unlink.done", "Почта снова приходит в личный чат."),
+    ("unlink.done_important", "Важная почта снова приходит вместе с остальной."),
+];
@@ -1,9 +1,13 @@
 pub mod cfg;
 pub mod ctrlc_handler;
 pub mod heartbeat;
+pub mod i18n;
 pub mod macros;
+pub mod mail_diagnostics;
 pub mod queues;
 pub mod sentry;
 pub mod sessions;
 pub mod storage;
+pub mod templates;
+pub mod tls;
 pub mod types;
@@ -0,0 +1,375 @@
+use rustls_connector::{rustls, HandshakeError, TlsStream};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::cfg::{MailCfg, ServerTlsCfg, TlsMode};
+use crate::storage::{MailAccount, MailProtocol};
+use crate::tls;
+use crate::types::{Error, NetworkError};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStage {
+    Dns,
+    /// Server resolves only to loopback, private or otherwise internal addresses
+    PrivateAddress,
+    Tcp,
+    StartTls,
+    TlsCertificate,
+    Tls,
+    Greeting,
+    Capability,
+    Auth,
+    NoInbox,
+    Timeout,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConnectionReport {
+    pub ok: bool,
+    /// Stage at which the check failed, `None` when every stage passed
+    pub failed_stage: Option<ConnectionStage>,
+    pub error: Option<String>,
+    pub capabilities: Vec<String>,
+}
+
+impl ConnectionReport {
+    fn passed(capabilities: Vec<String>) -> Self {
+        ConnectionReport {
+            ok: true,
+            failed_stage: None,
+            error: None,
+            capabilities,
+        }
+    }
+
+    fn failed(stage: ConnectionStage, error: impl ToString) -> Self {
+        ConnectionReport {
+            ok: false,
+            failed_stage: Some(stage),
+            error: Some(error.to_string()),
+            capabilities: vec![],
+        }
+    }
+}
+
+const HTTPS_PORT: u16 = 443;
+const JMAP_MAIL_CAPABILITY: &str = "urn:ietf:params:jmap:mail";
+const MAX_REDIRECTS: usize = 5;
+
+type StageResult<T> = std::result::Result<T, ConnectionReport>;
+
+/// Checks that mail account is usable: resolves and connects to the server, performs
+/// TLS handshake and logs in. IMAP accounts must have INBOX, POP3 ones an accessible
+/// maildrop and JMAP ones a mail account in their session.
+/// Servers given by the user may not resolve to internal addresses, only the ones
+/// from `mail` config are trusted with them.
+pub async fn diagnose(account: MailAccount, cfg: &MailCfg, timeout: Duration) -> ConnectionReport {
+    let check = async {
+        match account.protocol {
+            MailProtocol::Jmap => diagnose_jmap(&account, cfg, timeout).await,
+            MailProtocol::Imap | MailProtocol::Pop3 => {
+                let (host, port) = account.server(cfg);
+                let tls_cfg = cfg.tls_for(&host).clone();
+                let allow_private = cfg.is_trusted_host(&host);
+                let account = account.clone();
+                tokio::task::spawn_blocking(move || {
+                    diagnose_blocking(&host, port, allow_private, &tls_cfg, &account, timeout)
+                })
+                .await
+                .unwrap_or_else(|e| Err(ConnectionReport::failed(ConnectionStage::Timeout, e)))
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, check).await {
+        Ok(report) => report.unwrap_or_else(|report| report),
+        Err(_) => ConnectionReport::failed(
+            ConnectionStage::Timeout,
+            format!("Check did not finish in {} seconds", timeout.as_secs()),
+        ),
+    }
+}
+
+fn diagnose_blocking(
+    host: &str,
+    port: u16,
+    allow_private: bool,
+    tls_cfg: &ServerTlsCfg,
+    account: &MailAccount,
+    timeout: Duration,
+) -> StageResult<ConnectionReport> {
+    let address = resolve(host, port, allow_private)?;
+    let mut stream = connect_tcp(&address, timeout)?;
+    let starttls = tls_cfg.mode == TlsMode::StartTls;
+    if starttls {
+        tls::negotiate_starttls(account.protocol, &mut stream)
+            .map_err(|e| ConnectionReport::failed(ConnectionStage::StartTls, e))?;
+    }
+    let stream = handshake(host, stream, tls_cfg)?;
+
+    match account.protocol {
+        MailProtocol::Imap => check_imap(stream, account, !starttls),
+        MailProtocol::Pop3 => check_pop3(stream, account, !starttls),
+        MailProtocol::Jmap => unreachable!("JMAP accounts are checked over HTTP"),
+    }
+}
+
+async fn diagnose_jmap(
+    account: &MailAccount,
+    cfg: &MailCfg,
+    timeout: Duration,
+) -> StageResult<ConnectionReport> {
+    let base_url = account.jmap_base_url(cfg);
+    let url = reqwest::Url::parse(&base_url)
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Dns, e))?;
+    let host = url
+        .host_str()
+        .ok_or(ConnectionReport::failed(
+            ConnectionStage::Dns,
+            format!("{} has no host", base_url),
+        ))?
+        .to_owned();
+    let port = url.port_or_known_default().unwrap_or(HTTPS_PORT);
+    let tls_cfg = (url.scheme() == "https").then(|| cfg.tls_for(&host).clone());
+    let allow_private = cfg.is_trusted_host(&host);
+
+    let reachable_host = host.clone();
+    let address = tokio::task::spawn_blocking(move || {
+        let address = resolve(&reachable_host, port, allow_private)?;
+        let stream = connect_tcp(&address, timeout)?;
+        if let Some(tls_cfg) = &tls_cfg {
+            handshake(&reachable_host, stream, tls_cfg)?;
+        }
+        Ok(address)
+    })
+    .await
+    .unwrap_or_else(|e| Err(ConnectionReport::failed(ConnectionStage::Timeout, e)))?;
+
+    check_jmap(&base_url, &host, address, account, timeout).await
+}
+
+fn resolve(host: &str, port: u16, allow_private: bool) -> StageResult<SocketAddr> {
+    tls::resolve(host, port, allow_private).map_err(|e| {
+        let stage = match &e {
+            Error::NetworkError(NetworkError::PrivateAddress(_)) => ConnectionStage::PrivateAddress,
+            _ => ConnectionStage::Dns,
+        };
+        ConnectionReport::failed(stage, e)
+    })
+}
+
+fn connect_tcp(address: &SocketAddr, timeout: Duration) -> StageResult<TcpStream> {
+    let stream = TcpStream::connect_timeout(address, timeout)
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Tcp, e))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Tcp, e))?;
+    Ok(stream)
+}
+
+fn handshake(
+    host: &str,
+    stream: TcpStream,
+    tls_cfg: &ServerTlsCfg,
+) -> StageResult<TlsStream<TcpStream>> {
+    tls::handshake(host, stream, tls_cfg).map_err(|e| {
+        let stage = match &e {
+            Error::NetworkError(NetworkError::HandshakeError {
+                error: HandshakeError::Failure(io_error),
+                ..
+            }) => match io_error
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+            {
+                Some(rustls::Error::InvalidCertificate(_)) => ConnectionStage::TlsCertificate,
+                _ => ConnectionStage::Tls,
+            },
+            Error::NetworkError(NetworkError::HandshakeError {
+                error: HandshakeError::WouldBlock(_),
+                ..
+            }) => ConnectionStage::Timeout,
+            Error::NetworkError(NetworkError::CertificatePinMismatch { .. }) => {
+                ConnectionStage::TlsCertificate
+            }
+            _ => ConnectionStage::Tls,
+        };
+        ConnectionReport::failed(stage, e)
+    })
+}
+
+fn check_imap(
+    stream: TlsStream<TcpStream>,
+    account: &MailAccount,
+    read_greeting: bool,
+) -> StageResult<ConnectionReport> {
+    let mut client = imap::Client::new(stream);
+    if read_greeting {
+        client
+            .read_greeting()
+            .map_err(|e| ConnectionReport::failed(ConnectionStage::Greeting, e))?;
+    }
+
+    let mut session = client
+        .login(&account.email, &account.password)
+        .map_err(|(e, _)| ConnectionReport::failed(ConnectionStage::Auth, e))?;
+
+    // Servers may announce more of them once the user is logged in.
+    // The parsed ones can not be listed, so the response is read as is
+    let response = session
+        .run_command_and_read_response("CAPABILITY")
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Capability, e))?;
+    let capabilities: Vec<String> = String::from_utf8_lossy(&response)
+        .lines()
+        .filter_map(|line| line.strip_prefix("* CAPABILITY "))
+        .flat_map(|line| line.split_whitespace().map(|cap| cap.to_owned()))
+        .collect();
+
+    let inbox = session
+        .list(None, Some("INBOX"))
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::NoInbox, e))?;
+    if inbox.is_empty() {
+        return Err(ConnectionReport::failed(
+            ConnectionStage::NoInbox,
+            "Server has no INBOX folder",
+        ));
+    }
+
+    let _ = session.logout();
+
+    Ok(ConnectionReport::passed(capabilities))
+}
+
+fn read_pop3_line(stream: &mut BufReader<TlsStream<TcpStream>>) -> std::io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(std::io::Error::other("connection closed by server"));
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn read_pop3_status(stream: &mut BufReader<TlsStream<TcpStream>>) -> std::io::Result<String> {
+    let line = read_pop3_line(stream)?;
+    match line.starts_with("+OK") {
+        true => Ok(line),
+        false => Err(std::io::Error::other(line)),
+    }
+}
+
+fn pop3_command(
+    stream: &mut BufReader<TlsStream<TcpStream>>,
+    command: &str,
+) -> std::io::Result<String> {
+    let socket = stream.get_mut();
+    socket.write_all(command.as_bytes())?;
+    socket.write_all(b"\r\n")?;
+    socket.flush()?;
+    read_pop3_status(stream)
+}
+
+fn check_pop3(
+    stream: TlsStream<TcpStream>,
+    account: &MailAccount,
+    read_greeting: bool,
+) -> StageResult<ConnectionReport> {
+    let mut stream = BufReader::new(stream);
+    if read_greeting {
+        read_pop3_status(&mut stream)
+            .map_err(|e| ConnectionReport::failed(ConnectionStage::Greeting, e))?;
+    }
+
+    // CAPA is optional (RFC 2449), servers without it are still usable
+    let mut capabilities = vec![];
+    if pop3_command(&mut stream, "CAPA").is_ok() {
+        loop {
+            let line = read_pop3_line(&mut stream)
+                .map_err(|e| ConnectionReport::failed(ConnectionStage::Capability, e))?;
+            if line == "." {
+                break;
+            }
+            capabilities.push(line);
+        }
+    }
+
+    pop3_command(&mut stream, &format!("USER {}", account.email))
+        .and_then(|_| pop3_command(&mut stream, &format!("PASS {}", account.password)))
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Auth, e))?;
+
+    pop3_command(&mut stream, "STAT")
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::NoInbox, e))?;
+
+    let _ = pop3_command(&mut stream, "QUIT");
+
+    Ok(ConnectionReport::passed(capabilities))
+}
+
+/// Reads JMAP session of the account through the address which passed the earlier stages
+async fn check_jmap(
+    base_url: &str,
+    host: &str,
+    address: SocketAddr,
+    account: &MailAccount,
+    timeout: Duration,
+) -> StageResult<ConnectionReport> {
+    // Redirects may only stay on the checked server, others could lead to internal ones
+    let (checked_host, checked_port) = (host.to_owned(), address.port());
+    let redirects = reqwest::redirect::Policy::custom(move |attempt| {
+        let same_server = attempt.url().host_str() == Some(checked_host.as_str())
+            && attempt.url().port_or_known_default() == Some(checked_port);
+        match same_server && attempt.previous().len() < MAX_REDIRECTS {
+            true => attempt.follow(),
+            false => attempt.stop(),
+        }
+    });
+    let http = reqwest::Client::builder()
+        .resolve(host, address)
+        .redirect(redirects)
+        .timeout(timeout)
+        .build()
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Tls, e))?;
+    let response = http
+        .get(format!("{}/.well-known/jmap", base_url.trim_end_matches('/')))
+        .basic_auth(&account.email, Some(&account.password))
+        .send()
+        .await
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Greeting, e))?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(ConnectionReport::failed(ConnectionStage::Auth, status));
+    }
+    let session: Value = response
+        .error_for_status()
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Greeting, e))?
+        .json()
+        .await
+        .map_err(|e| ConnectionReport::failed(ConnectionStage::Greeting, e))?;
+
+    if !session["primaryAccounts"][JMAP_MAIL_CAPABILITY].is_string() {
+        return Err(ConnectionReport::failed(
+            ConnectionStage::NoInbox,
+            format!("Server has no mail account for {}", account.email),
+        ));
+    }
+    let capabilities = session["capabilities"]
+        .as_object()
+        .map(|capabilities| capabilities.keys().cloned().collect())
+        .unwrap_or_default();
+
+    Ok(ConnectionReport::passed(capabilities))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_hosts_may_not_resolve_to_internal_addresses() {
+        let report = resolve("127.0.0.1", 993, false).unwrap_err();
+        assert_eq!(report.failed_stage, Some(ConnectionStage::PrivateAddress));
+        assert!(resolve("127.0.0.1", 993, true).is_ok());
+    }
+}
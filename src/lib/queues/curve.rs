@@ -0,0 +1,144 @@
+use anyhow::{anyhow, Result};
+use async_zmq::zmq;
+use std::collections::HashSet;
+
+use crate::cfg::BrokerCfg;
+
+/// Endpoint libzmq asks for authentication of incoming connections, see ZMQ RFC 27
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+const ZAP_DOMAIN: &str = "broker";
+
+/// Creates socket connected to the broker, encrypted if CURVE keys are configured
+pub fn client_socket(
+    cfg: &BrokerCfg,
+    kind: zmq::SocketType,
+    endpoint: &str,
+) -> Result<zmq::Socket> {
+    connect(cfg, kind, endpoint, false)
+}
+
+/// Like `client_socket`, but on the broker host, where the server secret key is configured,
+/// connects with the server keypair. The broker accepts it without an allow-list entry
+pub fn admin_socket(cfg: &BrokerCfg, kind: zmq::SocketType, endpoint: &str) -> Result<zmq::Socket> {
+    connect(cfg, kind, endpoint, true)
+}
+
+fn connect(
+    cfg: &BrokerCfg,
+    kind: zmq::SocketType,
+    endpoint: &str,
+    as_server: bool,
+) -> Result<zmq::Socket> {
+    let socket = zmq::Context::new().socket(kind)?;
+    if let Some(curve) = &cfg.curve {
+        let keypair = match (
+            &curve.server_secret_key,
+            &curve.public_key,
+            &curve.secret_key,
+        ) {
+            (Some(server_secret_key), _, _) if as_server => {
+                Some((&curve.server_public_key, server_secret_key))
+            }
+            (_, Some(public_key), Some(secret_key)) => Some((public_key, secret_key)),
+            _ => None,
+        };
+        let Some((public_key, secret_key)) = keypair else {
+            return Err(anyhow!(
+                "CURVE keypair of the broker client is not configured"
+            ));
+        };
+        socket.set_curve_serverkey(&curve.server_public_key)?;
+        socket.set_curve_publickey(public_key)?;
+        socket.set_curve_secretkey(secret_key)?;
+    }
+    socket.connect(endpoint)?;
+    Ok(socket)
+}
+
+/// Creates broker socket bound to the endpoint. With CURVE keys configured only clients
+/// accepted by the authenticator running in the same context can connect
+pub fn server_socket(
+    context: &zmq::Context,
+    cfg: &BrokerCfg,
+    kind: zmq::SocketType,
+    endpoint: &str,
+) -> Result<zmq::Socket> {
+    let socket = context.socket(kind)?;
+    if let Some(curve) = &cfg.curve {
+        let secret_key = curve
+            .server_secret_key
+            .as_ref()
+            .ok_or(anyhow!("`broker.curve.server_secret_key` is not set"))?;
+        socket.set_curve_server(true)?;
+        socket.set_curve_secretkey(secret_key)?;
+        socket.set_zap_domain(ZAP_DOMAIN)?;
+    }
+    socket.bind(endpoint)?;
+    Ok(socket)
+}
+
+/// Answers ZAP requests of the context sockets, admitting CURVE clients from the allow-list.
+/// Has to be started before the server sockets are bound, does nothing without CURVE keys
+pub fn start_authenticator(context: &zmq::Context, cfg: &BrokerCfg) -> Result<()> {
+    let Some(curve) = &cfg.curve else {
+        tracing::warn!("Broker CURVE keys are not configured, connections are not authenticated");
+        return Ok(());
+    };
+    // The server keypair is held by the broker host only, `broker admin` connects with it there
+    let mut allowed: HashSet<Vec<u8>> = curve.allowed_clients.iter().cloned().collect();
+    allowed.insert(curve.server_public_key.clone());
+
+    let handler = context.socket(zmq::REP)?;
+    handler.bind(ZAP_ENDPOINT)?;
+    std::thread::spawn(move || loop {
+        let request = match handler.recv_multipart(0) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!("Broker authenticator finished with error: {}", e);
+                break;
+            }
+        };
+        // version, request id, domain, address, identity, mechanism, credentials...
+        let version = request.first().cloned().unwrap_or_default();
+        let request_id = request.get(1).cloned().unwrap_or_default();
+        let mechanism = request.get(5).map(|m| m.as_slice()).unwrap_or_default();
+        let client_key = request.get(6);
+
+        let accepted = mechanism == b"CURVE" && client_key.is_some_and(|key| allowed.contains(key));
+        let (status, text) = if accepted {
+            ("200", "OK")
+        } else {
+            tracing::warn!(
+                "Rejected broker client {} with key {}",
+                request
+                    .get(3)
+                    .map(|address| String::from_utf8_lossy(address).into_owned())
+                    .unwrap_or_default(),
+                client_key
+                    .and_then(|key| zmq::z85_encode(key).ok())
+                    .unwrap_or_default()
+            );
+            ("400", "Client key is not allowed")
+        };
+        let reply: Vec<Vec<u8>> = vec![
+            version,
+            request_id,
+            status.into(),
+            text.into(),
+            vec![],
+            vec![],
+        ];
+        if let Err(e) = handler.send_multipart(reply, 0) {
+            tracing::error!("Broker authenticator failed to reply: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Generates CURVE keypair, Z85 encoded public and secret keys
+pub fn generate_keypair() -> Result<(String, String)> {
+    let keypair = zmq::CurveKeyPair::new()?;
+    let public_key = zmq::z85_encode(&keypair.public_key)?;
+    let secret_key = zmq::z85_encode(&keypair.secret_key)?;
+    Ok((public_key, secret_key))
+}
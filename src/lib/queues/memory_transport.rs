@@ -0,0 +1,229 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::{
+    count_by_lane, BrokerMessage, BrokerMessagePayload, Priority, Tasks, Topic, Transport,
+};
+use crate::types::BrokerError;
+
+struct Consumer {
+    topics: Vec<Topic>,
+    tx: Sender<BrokerMessage>,
+}
+
+#[derive(Default)]
+struct Group {
+    consumers: Vec<Consumer>,
+    next: usize,
+}
+
+impl Group {
+    /// Consumers of the topic take messages in turns, a full one is skipped.
+    /// Returns the message back if nobody took it
+    fn offer(&mut self, mut message: BrokerMessage) -> Option<BrokerMessage> {
+        self.consumers.retain(|consumer| !consumer.tx.is_closed());
+        let topic = message.topic();
+        let count = self.consumers.len();
+        for offset in 0..count {
+            let index = (self.next + offset) % count;
+            let consumer = &self.consumers[index];
+            if !consumer.topics.contains(&topic) {
+                continue;
+            }
+            match consumer.tx.try_send(message) {
+                Ok(_) => {
+                    self.next = index + 1;
+                    return None;
+                }
+                Err(e) => message = e.into_inner(),
+            }
+        }
+        Some(message)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    groups: HashMap<String, Group>,
+    /// Messages published while nobody was subscribed
+    backlog: Vec<BrokerMessage>,
+    unacked: HashMap<Uuid, BrokerMessage>,
+    dead_letters: Vec<(BrokerMessage, String)>,
+}
+
+/// Queue living inside a single process, meant for tests only: every process has its own
+/// queue, so it is not offered in config. Nothing survives a restart and unacked messages
+/// are not redelivered. Lanes are unbounded unless their capacity is set
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    state: Arc<Mutex<State>>,
+    capacity: HashMap<Priority, usize>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unacked tasks the lane holds before producers are turned away
+    pub fn with_lane_capacity(mut self, priority: Priority, capacity: usize) -> Self {
+        self.capacity.insert(priority, capacity);
+        self
+    }
+
+    pub async fn unacked(&self) -> Vec<BrokerMessage> {
+        self.state.lock().await.unacked.values().cloned().collect()
+    }
+
+    pub async fn dead_letters(&self) -> Vec<(BrokerMessage, String)> {
+        self.state.lock().await.dead_letters.clone()
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn publish(&self, task: Tasks) -> Result<()> {
+        self.publish_batch(vec![task]).await
+    }
+
+    /// Tasks of the batch are taken or turned away together, like the broker does
+    async fn publish_batch(&self, tasks: Vec<Tasks>) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let held = count_by_lane(
+            state
+                .unacked
+                .values()
+                .map(|message| match &message.payload {
+                    BrokerMessagePayload::Tasks(task) => task,
+                }),
+        );
+        for (priority, count) in count_by_lane(&tasks) {
+            let capacity = self.capacity.get(&priority).copied().unwrap_or(usize::MAX);
+            let held = held.get(&priority).copied().unwrap_or_default();
+            if held + count > capacity as u64 {
+                let error = format!("{} priority lane is full", priority.as_str());
+                return Err(BrokerError::Busy(error).into());
+            }
+        }
+
+        for task in tasks {
+            let message = BrokerMessage {
+                message_id: Uuid::new_v4(),
+                payload: BrokerMessagePayload::Tasks(task),
+                attempt: 1,
+            };
+            state.unacked.insert(message.message_id, message.clone());
+
+            let mut taken = false;
+            for group in state.groups.values_mut() {
+                taken |= group.offer(message.clone()).is_none();
+            }
+            if !taken {
+                state.backlog.push(message);
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, group: &str, topics: &[Topic]) -> Result<Receiver<BrokerMessage>> {
+        let (tx, rx) = channel(100);
+        let mut state = self.state.lock().await;
+        let group = state.groups.entry(group.to_owned()).or_default();
+        group.consumers.push(Consumer {
+            topics: topics.to_vec(),
+            tx,
+        });
+
+        let backlog = std::mem::take(&mut state.backlog);
+        for message in backlog {
+            let mut left = Some(message);
+            for group in state.groups.values_mut() {
+                if let Some(message) = left.take() {
+                    left = group.offer(message);
+                }
+            }
+            state.backlog.extend(left);
+        }
+        Ok(rx)
+    }
+
+    async fn ack(&self, message_id: Uuid) -> Result<()> {
+        self.state.lock().await.unacked.remove(&message_id);
+        Ok(())
+    }
+
+    async fn dead_letter(&self, message_id: Uuid, reason: String) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if let Some(message) = state.unacked.remove(&message_id) {
+            state.dead_letters.push((message, reason));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queues::TelegramMessageTask;
+    use teloxide_core::types::UserId;
+
+    fn task(important: bool) -> Tasks {
+        Tasks::TelegramMessageTask(TelegramMessageTask {
+            to: UserId(1),
+            text: "New mail".into(),
+            send_after: chrono::Utc::now(),
+            important,
+            chat: None,
+            silent: false,
+            mail: None,
+            recheck: false,
+        })
+    }
+
+    fn is_busy(result: Result<()>) -> bool {
+        matches!(
+            result.err().and_then(|e| e.downcast::<BrokerError>().ok()),
+            Some(BrokerError::Busy(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn full_lane_turns_producers_away() {
+        let transport = MemoryTransport::new().with_lane_capacity(Priority::Normal, 2);
+        transport.publish(task(false)).await.unwrap();
+        transport.publish(task(false)).await.unwrap();
+        assert!(is_busy(transport.publish(task(false)).await));
+
+        // The other lane is not affected
+        transport.publish(task(true)).await.unwrap();
+
+        let unacked = transport.unacked().await;
+        let routine = unacked
+            .iter()
+            .find(|message| message.priority() == Priority::Normal)
+            .unwrap();
+        transport.ack(routine.message_id).await.unwrap();
+        transport.publish(task(false)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn batch_is_taken_or_turned_away_together() {
+        let transport = MemoryTransport::new().with_lane_capacity(Priority::High, 2);
+        transport.publish(task(true)).await.unwrap();
+
+        let batch = vec![task(false), task(true), task(true)];
+        assert!(is_busy(transport.publish_batch(batch).await));
+        assert_eq!(transport.unacked().await.len(), 1);
+
+        transport
+            .publish_batch(vec![task(false), task(true)])
+            .await
+            .unwrap();
+        assert_eq!(transport.unacked().await.len(), 3);
+    }
+}
@@ -1,31 +1,57 @@
+mod curve;
+mod memory_transport;
+mod redis_transport;
+pub mod wire;
+mod zmq_transport;
+
 use anyhow::Result;
-use async_zmq::StreamExt;
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    spawn,
-    sync::mpsc::{channel, Receiver, Sender},
-};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
 use uuid::Uuid;
 
-use crate::cfg::BrokerCfg;
-use crate::retry;
+use crate::cfg::{BrokerCfg, BrokerTransport};
+use crate::storage::{ChatRoute, MailChange, MailRef, NotifiedMail, Storage};
 
-#[derive(Clone)]
-pub struct BrokerClient {
-    cfg: BrokerCfg,
-}
+pub use curve::{
+    admin_socket, client_socket, generate_keypair, server_socket, start_authenticator,
+};
+pub use memory_transport::MemoryTransport;
+pub use redis_transport::RedisTransport;
+pub use zmq_transport::{ZmqTransport, SUBSCRIPTION_INTERVAL};
 
 use teloxide_core::types::UserId;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TelegramMessageTask {
+    /// User the mail belongs to, their settings apply to the task
     pub to: UserId,
     pub text: String,
     pub send_after: chrono::DateTime<chrono::Utc>,
     pub important: bool,
+    /// Group chat or forum topic the user routed the mail to, the private chat if not set
+    #[serde(default)]
+    pub chat: Option<ChatRoute>,
+    /// Sent without sound
+    #[serde(default)]
+    pub silent: bool,
+    /// Mail the notification is about, `None` for service messages and sources which can not tell
+    #[serde(default)]
+    pub mail: Option<MailRef>,
+    /// Deferred notification whose mail the checker looks up again once it is due.
+    /// The subscriber holds it until the mail is confirmed unread, for a while at most
+    #[serde(default)]
+    pub recheck: bool,
 }
 
 impl TelegramMessageTask {
+    /// Chat the notification is sent to
+    pub fn destination(&self) -> ChatRoute {
+        self.chat.unwrap_or(self.to.into())
+    }
+
     pub fn can_send_now(&self) -> bool {
         let now = chrono::Utc::now();
         if now > self.send_after {
@@ -33,11 +59,118 @@ impl TelegramMessageTask {
         }
         false
     }
+
+    /// Moment before which the subscriber is expected to hold the task, `None` if it is sent right away
+    pub fn deferred_until(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self.important {
+            true => None,
+            false => Some(self.send_after),
+        }
+    }
+}
+
+/// Asks the bot to update the notification, the mail was read or removed in the mailbox
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationUpdateTask {
+    pub to: UserId,
+    pub notified: NotifiedMail,
+    pub change: MailChange,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Tasks {
     TelegramMessageTask(TelegramMessageTask),
+    NotificationUpdateTask(NotificationUpdateTask),
+}
+
+impl Tasks {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Tasks::TelegramMessageTask(_) => Topic::TelegramMessage,
+            Tasks::NotificationUpdateTask(_) => Topic::TelegramEdit,
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        match self {
+            Tasks::TelegramMessageTask(task) if task.important => Priority::High,
+            Tasks::TelegramMessageTask(_) => Priority::Normal,
+            Tasks::NotificationUpdateTask(_) => Priority::Normal,
+        }
+    }
+}
+
+/// Number of the tasks going to each lane
+fn count_by_lane<'a>(tasks: impl IntoIterator<Item = &'a Tasks>) -> HashMap<Priority, u64> {
+    let mut counts = HashMap::new();
+    for task in tasks {
+        *counts.entry(task.priority()).or_default() += 1;
+    }
+    counts
+}
+
+/// Kind of task together with the channel it is delivered through.
+/// Subscribers receive only the topics they declared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Topic {
+    TelegramMessage,
+    TelegramEdit,
+}
+
+impl Topic {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Topic::TelegramMessage => "telegram.message",
+            Topic::TelegramEdit => "telegram.edit",
+        }
+    }
+}
+
+/// Lane a message travels in, high priority ones are delivered ahead of queued normal ones.
+/// Each lane has its own capacity, so a flood of routine mail does not hold important one back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+        }
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            _ => Err(anyhow::anyhow!("Unknown priority: {}", s)),
+        }
+    }
+}
+
+/// Declares that `consumer` of `group` takes messages of `topics`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub group: String,
+    pub consumer: String,
+    pub topics: Vec<Topic>,
+    /// Newest wire format version the consumer reads, messages are published to it in that one
+    #[serde(default)]
+    pub wire_version: u16,
+}
+
+impl Subscription {
+    /// Prefix of the frames the broker publishes for this consumer
+    pub fn address(&self) -> String {
+        format!("{}/{}/", self.group, self.consumer)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,102 +178,145 @@ pub struct Ack {
     pub message_id: uuid::Uuid,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Asks the broker to stop delivering the message which can not be processed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub message_id: uuid::Uuid,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BrokerMessagePayload {
     Tasks(Tasks),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BrokerMessage {
     pub message_id: uuid::Uuid,
     pub payload: BrokerMessagePayload,
+    /// Number of times the broker published the message, starting from 1
+    #[serde(default)]
+    pub attempt: u32,
+}
+
+impl BrokerMessage {
+    pub fn topic(&self) -> Topic {
+        match &self.payload {
+            BrokerMessagePayload::Tasks(task) => task.topic(),
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        match &self.payload {
+            BrokerMessagePayload::Tasks(task) => task.priority(),
+        }
+    }
+
+    pub fn deferred_until(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match &self.payload {
+            BrokerMessagePayload::Tasks(Tasks::TelegramMessageTask(task)) => task.deferred_until(),
+            BrokerMessagePayload::Tasks(Tasks::NotificationUpdateTask(_)) => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BrokerRequestPayload {
     Tasks(Tasks),
     Ack(Ack),
+    /// Registers the consumer, repeated periodically to stay registered.
+    /// Messages assigned to the consumer before it (re)appeared are published again
+    Subscribe(Subscription),
+    DeadLetter(DeadLetter),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Message moved out of the queue after it could not be delivered
+#[derive(Debug)]
+pub struct DeadLetterMessage {
+    pub message: BrokerMessage,
+    pub reason: String,
+    /// Unix timestamp of the moment the message was moved
+    pub failed_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BrokerRequest {
     pub id: Uuid,
     pub payload: BrokerRequestPayload,
 }
 
-impl BrokerClient {
-    pub fn new(cfg: BrokerCfg) -> Result<Self> {
-        Ok(Self { cfg })
-    }
-
-    pub async fn send(&self, payload: BrokerRequestPayload) -> Result<()> {
-        let id = uuid::Uuid::new_v4();
-        let m = crate::queues::BrokerRequest { id, payload };
-        let data = serde_json::to_vec(&m)?;
-
-        tracing::info!("send {}", String::from_utf8_lossy(&data));
-
-        async fn send_impl(data: Vec<u8>, cfg: BrokerCfg) -> Result<()> {
-            let handle = tokio::runtime::Handle::current();
-            let thread_handle = handle.spawn_blocking(move || {
-                tokio::runtime::Builder::new_current_thread()
-                    .build()?
-                    .block_on(async move {
-                        let requestor =
-                            async_zmq::request(&format!("tcp://{}:{}", cfg.address, cfg.rep_port))?
-                                .connect()?;
-                        requestor.send(data).await?;
-                        let _ = requestor.recv().await?;
-
-                        Ok::<(), anyhow::Error>(())
-                    })
-            });
-            let res = thread_handle.await??;
-            Ok(res)
-        }
+/// Reply of the broker to a batch of requests, `id` is the one of the first request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrokerReply {
+    pub id: Uuid,
+    pub error: Option<String>,
+    /// Tasks were not accepted because their lane is full, the producer should retry later
+    #[serde(default)]
+    pub busy: bool,
+}
 
-        retry! { send_impl(data.clone(), self.cfg.clone()).await }?;
+/// Delivers tasks from producers to consumers with at-least-once semantics
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn publish(&self, task: Tasks) -> Result<()>;
 
+    async fn publish_batch(&self, tasks: Vec<Tasks>) -> Result<()> {
+        for task in tasks {
+            self.publish(task).await?;
+        }
         Ok(())
     }
 
-    pub async fn subscribe(&self) -> Result<Receiver<BrokerMessage>> {
-        let sub =
-            async_zmq::subscribe(&format!("tcp://{}:{}", self.cfg.address, self.cfg.pub_port))?
-                .connect()?;
-        sub.set_subscribe(&"tasks")?;
+    /// Starts receiving messages of `topics`. Each message goes to one consumer
+    /// of the `group`, unacked ones are delivered again
+    async fn subscribe(&self, group: &str, topics: &[Topic]) -> Result<Receiver<BrokerMessage>>;
 
-        let (tx, rx) = channel(10);
+    async fn ack(&self, message_id: Uuid) -> Result<()>;
 
-        async fn sub_impl(mut sub: async_zmq::Subscribe, tx: Sender<BrokerMessage>) -> Result<()> {
-            while let Some(messages) = sub.next().await {
-                match messages {
-                    Ok(msgs) => {
-                        for msg in msgs {
-                            tracing::info!("received {:?}", msg.as_str());
+    /// Stops delivering the message and keeps it for inspection
+    async fn dead_letter(&self, message_id: Uuid, reason: String) -> Result<()>;
+}
 
-                            if msg.get_more() {
-                                continue;
-                            }
+#[derive(Clone)]
+pub struct BrokerClient {
+    transport: Arc<dyn Transport>,
+}
 
-                            let str = msg.as_str().ok_or(anyhow::anyhow!(""))?;
-                            let m: BrokerMessage = serde_json::from_str(&str).unwrap();
-                            tx.send(m).await.ok();
-                        }
-                    }
-                    Err(_) => {}
-                }
-            }
+impl BrokerClient {
+    pub fn new(cfg: BrokerCfg, storage: Storage) -> Result<Self> {
+        let transport: Arc<dyn Transport> = match cfg.transport {
+            BrokerTransport::Zmq => Arc::new(ZmqTransport::new(cfg)),
+            BrokerTransport::Redis => Arc::new(RedisTransport::new(cfg, storage)),
+        };
+        Ok(Self { transport })
+    }
 
-            Ok(())
-        }
-        spawn(sub_impl(sub, tx));
-        Ok(rx)
+    /// Client over a transport which is not picked by config, e.g. `MemoryTransport` in tests
+    pub fn with_transport(transport: Arc<dyn Transport>) -> Self {
+        Self { transport }
     }
 
-    pub async fn ack(&self, message_id: uuid::Uuid) -> Result<()> {
-        let payload = BrokerRequestPayload::Ack(Ack { message_id });
-        self.send(payload).await?;
-        Ok(())
+    pub async fn publish(&self, task: Tasks) -> Result<()> {
+        self.transport.publish(task).await
+    }
+
+    pub async fn publish_batch(&self, tasks: Vec<Tasks>) -> Result<()> {
+        self.transport.publish_batch(tasks).await
+    }
+
+    pub async fn subscribe(
+        &self,
+        group: &str,
+        topics: &[Topic],
+    ) -> Result<Receiver<BrokerMessage>> {
+        self.transport.subscribe(group, topics).await
+    }
+
+    pub async fn ack(&self, message_id: Uuid) -> Result<()> {
+        self.transport.ack(message_id).await
+    }
+
+    pub async fn dead_letter(&self, message_id: Uuid, reason: String) -> Result<()> {
+        self.transport.dead_letter(message_id, reason).await
     }
 }
@@ -0,0 +1,315 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::wire::{self, WIRE_VERSION};
+use super::{
+    count_by_lane, BrokerMessage, BrokerMessagePayload, Priority, Tasks, Topic, Transport,
+};
+use crate::cfg::BrokerCfg;
+use crate::storage::{Storage, StreamEntry};
+use crate::types::BrokerError;
+
+const READ_BLOCK: std::time::Duration = std::time::Duration::from_secs(5);
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Each topic and lane is kept in its own stream, so important tasks do not wait behind
+/// routine ones. Routine tasks keep the stream the topic had before lanes
+fn stream_key(topic: Topic, priority: Priority) -> String {
+    match priority {
+        Priority::High => format!("BROKER:{}:{}", topic.as_str(), priority.as_str()),
+        Priority::Normal => format!("BROKER:{}", topic.as_str()),
+    }
+}
+
+/// Streams of the topic in the order they are read
+fn stream_keys(topic: Topic) -> Vec<String> {
+    [Priority::High, Priority::Normal]
+        .into_iter()
+        .map(|priority| stream_key(topic, priority))
+        .collect()
+}
+
+struct Received {
+    stream: String,
+    group: String,
+    entry_id: String,
+    message: BrokerMessage,
+}
+
+/// Keeps tasks in Redis streams, subscribers read them through consumer groups.
+/// Entries left unacked for the visibility timeout are claimed by another consumer.
+/// Unacked tasks of each lane are counted, producers are turned away when it is full
+#[derive(Clone)]
+pub struct RedisTransport {
+    cfg: BrokerCfg,
+    storage: Storage,
+    received: Arc<Mutex<HashMap<Uuid, Received>>>,
+}
+
+impl RedisTransport {
+    pub fn new(cfg: BrokerCfg, storage: Storage) -> Self {
+        Self {
+            cfg,
+            storage,
+            received: Default::default(),
+        }
+    }
+
+    /// Takes places for all of the tasks in their lanes or for none of them
+    async fn reserve(&self, tasks: &[Tasks]) -> Result<()> {
+        let mut reserved = vec![];
+        for (priority, count) in count_by_lane(tasks) {
+            let capacity = self.cfg.lane_capacity(priority);
+            if !self.storage.lane_reserve(priority, count, capacity).await? {
+                for (priority, count) in reserved {
+                    self.storage.lane_release(priority, count).await?;
+                }
+                let error = format!("{} priority lane is full", priority.as_str());
+                return Err(BrokerError::Busy(error).into());
+            }
+            reserved.push((priority, count));
+        }
+        Ok(())
+    }
+
+    async fn release(&self, tasks: &[Tasks]) -> Result<()> {
+        for (priority, count) in count_by_lane(tasks) {
+            self.storage.lane_release(priority, count).await?;
+        }
+        Ok(())
+    }
+
+    async fn add(&self, task: &Tasks) -> Result<()> {
+        let message = BrokerMessage {
+            message_id: Uuid::new_v4(),
+            payload: BrokerMessagePayload::Tasks(task.clone()),
+            attempt: 0,
+        };
+        let data = wire::encode(&message, WIRE_VERSION)?;
+        self.storage
+            .stream_add(&stream_key(task.topic(), task.priority()), &data)
+            .await?;
+        Ok(())
+    }
+
+    /// Entries written in unknown format are dropped, they could never be processed
+    async fn decode(
+        &self,
+        stream: &str,
+        group: &str,
+        entry: &StreamEntry,
+    ) -> Result<Option<BrokerMessage>> {
+        match wire::decode(&entry.data) {
+            Ok(message) => Ok(Some(message)),
+            Err(e) => {
+                tracing::error!("Dropped entry {} of {} stream: {}", entry.id, stream, e);
+                self.storage.stream_ack(stream, group, &entry.id).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn forward(
+        &self,
+        stream: &str,
+        group: &str,
+        entry: StreamEntry,
+        tx: &Sender<BrokerMessage>,
+    ) -> Result<()> {
+        let Some(mut message) = self.decode(stream, group, &entry).await? else {
+            return Ok(());
+        };
+        message.attempt = entry.deliveries.max(1);
+        self.received.lock().await.insert(
+            message.message_id,
+            Received {
+                stream: stream.to_owned(),
+                group: group.to_owned(),
+                entry_id: entry.id,
+                message: message.clone(),
+            },
+        );
+        let _ = tx.send(message).await;
+        Ok(())
+    }
+
+    /// Takes over entries other consumers (or this one before restart) did not ack in time
+    async fn reclaim(&self, stream: &str, group: &str, tx: &Sender<BrokerMessage>) -> Result<()> {
+        let timeout = self.cfg.visibility_timeout;
+        let now = chrono::Utc::now();
+        for entry in self
+            .storage
+            .stream_idle_entries(stream, group, timeout)
+            .await?
+        {
+            let Some(mut message) = self.decode(stream, group, &entry).await? else {
+                continue;
+            };
+            if let Some(until) = message.deferred_until() {
+                // Deferred tasks are held by the consumer until they are due
+                if until + timeout > now {
+                    continue;
+                }
+            }
+
+            if entry.deliveries >= self.cfg.max_attempts {
+                let reason = format!("Not acked after {} deliveries", entry.deliveries);
+                tracing::warn!(
+                    "Message {} moved to dead letters: {}",
+                    message.message_id,
+                    reason
+                );
+                message.attempt = entry.deliveries;
+                self.storage.add_dead_letter(&message, &reason).await?;
+                self.storage.stream_ack(stream, group, &entry.id).await?;
+                self.storage.lane_release(message.priority(), 1).await?;
+                continue;
+            }
+
+            let claimed = self
+                .storage
+                .stream_claim(stream, group, &self.cfg.consumer, timeout, &entry.id)
+                .await?;
+            if claimed {
+                tracing::warn!(
+                    "Message {} was not acked after {} deliveries, redelivering",
+                    message.message_id,
+                    entry.deliveries
+                );
+                let entry = StreamEntry {
+                    deliveries: entry.deliveries + 1,
+                    ..entry
+                };
+                self.forward(stream, group, entry, tx).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads streams of the topic, entries of the important one go ahead of the routine ones
+    async fn read(
+        &self,
+        streams: &[String],
+        group: &str,
+        tx: &Sender<BrokerMessage>,
+    ) -> Result<()> {
+        let consumer = &self.cfg.consumer;
+        let keys: Vec<&str> = streams.iter().map(String::as_str).collect();
+        // Entries delivered to this consumer before restart come first
+        let pending = self
+            .storage
+            .stream_read_group(&keys, group, consumer, "0", READ_BLOCK)
+            .await?;
+        for (stream, entry) in pending {
+            self.forward(&stream, group, entry, tx).await?;
+        }
+
+        while !tx.is_closed() {
+            let entries = self
+                .storage
+                .stream_read_group(&keys, group, consumer, ">", READ_BLOCK)
+                .await?;
+            for (stream, entry) in entries {
+                self.forward(&stream, group, entry, tx).await?;
+            }
+            for stream in streams {
+                self.reclaim(stream, group, tx).await?;
+                self.storage.stream_trim_acked(stream).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transport for RedisTransport {
+    async fn publish(&self, task: Tasks) -> Result<()> {
+        self.publish_batch(vec![task]).await
+    }
+
+    /// Tasks of the batch are taken or turned away together, like the broker does
+    async fn publish_batch(&self, tasks: Vec<Tasks>) -> Result<()> {
+        self.reserve(&tasks).await?;
+        for (index, task) in tasks.iter().enumerate() {
+            if let Err(e) = self.add(task).await {
+                self.release(&tasks[index..]).await?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, group: &str, topics: &[Topic]) -> Result<Receiver<BrokerMessage>> {
+        let (tx, rx) = channel(10);
+        for topic in topics {
+            let streams = stream_keys(*topic);
+            for stream in &streams {
+                self.storage.stream_create_group(stream, group).await?;
+            }
+
+            let transport = self.clone();
+            let group = group.to_owned();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while let Err(e) = transport.read(&streams, &group, &tx).await {
+                    tracing::error!("Reading of {} streams failed: {}", streams.join(", "), e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            });
+        }
+        Ok(rx)
+    }
+
+    async fn ack(&self, message_id: Uuid) -> Result<()> {
+        let received = self.received.lock().await.remove(&message_id);
+        match received {
+            Some(received) => {
+                self.storage
+                    .stream_ack(&received.stream, &received.group, &received.entry_id)
+                    .await?;
+                self.storage
+                    .lane_release(received.message.priority(), 1)
+                    .await
+            }
+            None => {
+                tracing::debug!("Ack for unknown message {}", message_id);
+                Ok(())
+            }
+        }
+    }
+
+    async fn dead_letter(&self, message_id: Uuid, reason: String) -> Result<()> {
+        let received = self.received.lock().await.remove(&message_id);
+        if let Some(received) = received {
+            tracing::warn!("Message {} moved to dead letters: {}", message_id, reason);
+            self.storage
+                .add_dead_letter(&received.message, &reason)
+                .await?;
+            self.storage
+                .stream_ack(&received.stream, &received.group, &received.entry_id)
+                .await?;
+            self.storage
+                .lane_release(received.message.priority(), 1)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn important_stream_is_read_first() {
+        assert_eq!(
+            stream_keys(Topic::TelegramMessage),
+            vec!["BROKER:telegram.message:high", "BROKER:telegram.message"]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::types::WireError;
+
+/// Version of the envelope and the messages in it, bumped on incompatible changes
+pub const WIRE_VERSION: u16 = 1;
+/// Oldest version still understood. Version 0 is plain JSON without envelope
+pub const MIN_WIRE_VERSION: u16 = 0;
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u16,
+    body: T,
+}
+
+/// Serializes the value in the given version of the format, the peer may not know the current one
+pub fn encode<T: Serialize>(value: &T, version: u16) -> Result<Vec<u8>> {
+    if !(MIN_WIRE_VERSION..=WIRE_VERSION).contains(&version) {
+        return Err(WireError::UnsupportedVersion(version).into());
+    }
+    if version == 0 {
+        return Ok(serde_json::to_vec(value)?);
+    }
+    Ok(serde_cbor::to_vec(&Envelope {
+        version,
+        body: value,
+    })?)
+}
+
+/// Deserializes the value, returning the format version it was sent in.
+/// Messages of unknown versions are rejected before their body is looked at
+pub fn decode_versioned<T: DeserializeOwned>(data: &[u8]) -> Result<(u16, T)> {
+    if data.first() == Some(&b'{') {
+        let value =
+            serde_json::from_slice(data).map_err(|e| WireError::Malformed(e.to_string()))?;
+        return Ok((0, value));
+    }
+
+    let envelope: Envelope<serde_cbor::Value> =
+        serde_cbor::from_slice(data).map_err(|e| WireError::Malformed(e.to_string()))?;
+    if !(MIN_WIRE_VERSION..=WIRE_VERSION).contains(&envelope.version) {
+        return Err(WireError::UnsupportedVersion(envelope.version).into());
+    }
+    let value = serde_cbor::value::from_value(envelope.body)
+        .map_err(|e| WireError::Malformed(e.to_string()))?;
+    Ok((envelope.version, value))
+}
+
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    Ok(decode_versioned(data)?.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Ping {
+        id: u32,
+        text: String,
+    }
+
+    fn ping() -> Ping {
+        Ping {
+            id: 7,
+            text: "hello".into(),
+        }
+    }
+
+    fn wire_error(result: Result<(u16, Ping)>) -> WireError {
+        result.unwrap_err().downcast::<WireError>().unwrap()
+    }
+
+    #[test]
+    fn round_trips_current_version() {
+        let data = encode(&ping(), WIRE_VERSION).unwrap();
+        assert_ne!(data.first(), Some(&b'{'));
+        assert_eq!(
+            decode_versioned::<Ping>(&data).unwrap(),
+            (WIRE_VERSION, ping())
+        );
+    }
+
+    #[test]
+    fn decodes_legacy_json() {
+        let data = br#"{"id":7,"text":"hello"}"#;
+        assert_eq!(decode_versioned::<Ping>(data).unwrap(), (0, ping()));
+        assert_eq!(encode(&ping(), 0).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let data = serde_cbor::to_vec(&Envelope {
+            version: WIRE_VERSION + 1,
+            body: ping(),
+        })
+        .unwrap();
+        assert!(matches!(
+            wire_error(decode_versioned(&data)),
+            WireError::UnsupportedVersion(version) if version == WIRE_VERSION + 1
+        ));
+        assert!(encode(&ping(), WIRE_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let data = encode(&ping(), WIRE_VERSION).unwrap();
+        for data in [
+            &data[..data.len() / 2],
+            &[0xff, 0x00, 0x13][..],
+            &[][..],
+            b"{\"id\":",
+        ] {
+            assert!(matches!(
+                wire_error(decode_versioned(data)),
+                WireError::Malformed(_)
+            ));
+        }
+
+        // Known envelope around a body of another shape
+        let data = encode(&"not a ping", WIRE_VERSION).unwrap();
+        assert!(matches!(
+            wire_error(decode_versioned(&data)),
+            WireError::Malformed(_)
+        ));
+    }
+}
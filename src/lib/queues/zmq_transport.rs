@@ -0,0 +1,389 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use async_zmq::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use tokio::{
+    spawn,
+    sync::mpsc::{channel, Receiver, Sender},
+    sync::oneshot,
+};
+use uuid::Uuid;
+
+use super::curve::client_socket;
+use super::wire::{self, WIRE_VERSION};
+use super::{
+    Ack, BrokerMessage, BrokerReply, BrokerRequest, BrokerRequestPayload, DeadLetter, Subscription,
+    Tasks, Topic, Transport,
+};
+use crate::cfg::BrokerCfg;
+use crate::retry;
+use crate::types::BrokerError;
+
+/// Period of subscription renewals, the broker forgets consumers silent for a few periods
+pub const SUBSCRIPTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+const SUBSCRIPTION_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Submissions waiting to be sent before callers have to wait for the connection
+const QUEUE_SIZE: usize = 100;
+
+type Dealer = async_zmq::Dealer<std::vec::IntoIter<Vec<u8>>, Vec<u8>>;
+
+/// Requests of a single caller waiting to be sent, the broker replies to them at once
+struct Submission {
+    requests: Vec<BrokerRequest>,
+    reply: oneshot::Sender<Result<()>>,
+}
+
+/// Builds multipart message of the requests: empty delimiter and a frame per request.
+/// The broker replies with the id of the first one
+fn encode(requests: &[BrokerRequest]) -> Result<(Uuid, Vec<Vec<u8>>)> {
+    let mut frames = vec![vec![]];
+    for request in requests {
+        frames.push(wire::encode(request, WIRE_VERSION)?);
+    }
+    let id = requests
+        .first()
+        .map(|request| request.id)
+        .ok_or(anyhow!("Empty batch"))?;
+    Ok((id, frames))
+}
+
+fn decode(reply: async_zmq::Multipart) -> Result<BrokerReply> {
+    let frame = reply.last().ok_or(anyhow!("Empty reply"))?;
+    wire::decode(frame)
+}
+
+/// Owns the DEALER socket: sends every submission as its own message without waiting
+/// for replies to earlier ones and routes replies back to the callers
+async fn connection(cfg: BrokerCfg, mut submissions: Receiver<Submission>) -> Result<()> {
+    let endpoint = format!("tcp://{}:{}", cfg.address, cfg.rep_port);
+    let mut dealer = Dealer::from(client_socket(&cfg, async_zmq::zmq::DEALER, &endpoint)?);
+    let mut waiting: HashMap<Uuid, oneshot::Sender<Result<()>>> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            submission = submissions.recv() => {
+                let Some(submission) = submission else {
+                    break;
+                };
+
+                // Callers which gave up waiting are not interesting anymore
+                waiting.retain(|_, reply| !reply.is_closed());
+
+                let sent = match encode(&submission.requests) {
+                    Ok((id, frames)) => dealer.send(frames.into()).await.map(|_| id).map_err(|e| anyhow!(e)),
+                    Err(e) => Err(e),
+                };
+                match sent {
+                    Ok(id) => {
+                        waiting.insert(id, submission.reply);
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to send requests to broker: {}", e);
+                        let _ = submission.reply.send(Err(e));
+                    }
+                }
+            }
+            Some(reply) = dealer.next() => {
+                let reply = match reply.map_err(|e| anyhow!(e)).and_then(decode) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        tracing::error!("Malformed broker reply: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(sender) = waiting.remove(&reply.id) {
+                    let result = match reply.error {
+                        Some(error) if reply.busy => Err(BrokerError::Busy(error).into()),
+                        Some(error) => Err(anyhow!("Broker rejected request: {}", error)),
+                        None => Ok(()),
+                    };
+                    let _ = sender.send(result);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Talks to the broker process: requests go through a long-lived DEALER socket,
+/// messages come from SUB socket
+#[derive(Clone)]
+pub struct ZmqTransport {
+    cfg: BrokerCfg,
+    submissions: Sender<Submission>,
+}
+
+impl ZmqTransport {
+    /// Must be called within tokio runtime, the connection task is spawned on it
+    pub fn new(cfg: BrokerCfg) -> Self {
+        let (tx, rx) = channel(QUEUE_SIZE);
+        let connection_cfg = cfg.clone();
+        spawn(async move {
+            if let Err(e) = connection(connection_cfg, rx).await {
+                tracing::error!("Broker connection finished with error: {}", e);
+            }
+        });
+        Self {
+            cfg,
+            submissions: tx,
+        }
+    }
+
+    async fn submit(&self, requests: Vec<BrokerRequest>) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.submissions
+            .send(Submission { requests, reply })
+            .await
+            .map_err(|_| anyhow!("Broker connection is closed"))?;
+        match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(reply) => reply?,
+            Err(_) => Err(anyhow!(
+                "Broker did not reply in {} seconds",
+                REQUEST_TIMEOUT.as_secs()
+            )),
+        }
+    }
+
+    /// Sends requests as one message, the broker handles them in order
+    pub async fn send_batch(&self, payloads: Vec<BrokerRequestPayload>) -> Result<()> {
+        let requests: Vec<BrokerRequest> = payloads
+            .into_iter()
+            .map(|payload| BrokerRequest {
+                id: Uuid::new_v4(),
+                payload,
+            })
+            .collect();
+        tracing::debug!("send {:?}", requests);
+
+        retry! { self.submit(requests.clone()).await }
+    }
+
+    pub async fn send(&self, payload: BrokerRequestPayload) -> Result<()> {
+        self.send_batch(vec![payload]).await
+    }
+}
+
+#[async_trait]
+impl Transport for ZmqTransport {
+    async fn publish(&self, task: Tasks) -> Result<()> {
+        self.send(BrokerRequestPayload::Tasks(task)).await
+    }
+
+    async fn publish_batch(&self, tasks: Vec<Tasks>) -> Result<()> {
+        let payloads = tasks.into_iter().map(BrokerRequestPayload::Tasks).collect();
+        self.send_batch(payloads).await
+    }
+
+    async fn subscribe(&self, group: &str, topics: &[Topic]) -> Result<Receiver<BrokerMessage>> {
+        let subscription = Subscription {
+            group: group.to_owned(),
+            consumer: self.cfg.consumer.clone(),
+            topics: topics.to_vec(),
+            wire_version: WIRE_VERSION,
+        };
+        let endpoint = format!("tcp://{}:{}", self.cfg.address, self.cfg.pub_port);
+        let sub =
+            async_zmq::Subscribe::from(client_socket(&self.cfg, async_zmq::zmq::SUB, &endpoint)?);
+        sub.set_subscribe(&subscription.address())?;
+
+        let (tx, rx) = channel(10);
+
+        async fn sub_impl(mut sub: async_zmq::Subscribe, tx: Sender<BrokerMessage>) -> Result<()> {
+            while let Some(messages) = sub.next().await {
+                if let Ok(msgs) = messages {
+                    for msg in msgs {
+                        if msg.get_more() {
+                            continue;
+                        }
+
+                        // Not acked, so the broker redelivers it and eventually gives up
+                        let m: BrokerMessage = match wire::decode(&msg) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                tracing::error!("Rejected message from broker: {}", e);
+                                continue;
+                            }
+                        };
+                        tracing::debug!("received {:?}", m);
+                        tx.send(m).await.ok();
+                    }
+                }
+            }
+
+            Ok(())
+        }
+        let closed = tx.clone();
+        spawn(sub_impl(sub, tx));
+
+        // Subscription is established asynchronously, give it time before registering
+        // with the broker, which starts publishing to this consumer right away
+        let transport = self.clone();
+        spawn(async move {
+            tokio::time::sleep(SUBSCRIPTION_DELAY).await;
+            while !closed.is_closed() {
+                let payload = BrokerRequestPayload::Subscribe(subscription.clone());
+                if let Err(e) = transport.send(payload).await {
+                    tracing::error!("Failed to renew subscription: {}", e);
+                }
+                tokio::time::sleep(SUBSCRIPTION_INTERVAL).await;
+            }
+        });
+
+        Ok(rx)
+    }
+
+    async fn ack(&self, message_id: Uuid) -> Result<()> {
+        let payload = BrokerRequestPayload::Ack(Ack { message_id });
+        self.send(payload).await
+    }
+
+    async fn dead_letter(&self, message_id: Uuid, reason: String) -> Result<()> {
+        let payload = BrokerRequestPayload::DeadLetter(DeadLetter { message_id, reason });
+        self.send(payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::BrokerTransport;
+    use std::time::{Duration, Instant};
+
+    type Reply = async_zmq::Reply<std::vec::IntoIter<Vec<u8>>, Vec<u8>>;
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn broker_cfg(rep_port: u16) -> BrokerCfg {
+        BrokerCfg {
+            transport: BrokerTransport::Zmq,
+            consumer: "test".into(),
+            address: "127.0.0.1".into(),
+            pub_port: free_port(),
+            rep_port,
+            admin_address: "127.0.0.1".into(),
+            admin_port: free_port(),
+            visibility_timeout: Duration::from_secs(60),
+            max_attempts: 10,
+            high_lane_capacity: 1000,
+            normal_lane_capacity: 10000,
+            curve: None,
+        }
+    }
+
+    /// Replies to every message like the broker does, dead letter requests are rejected.
+    /// The socket is not `Sync`, so it runs on its own thread like the broker endpoints
+    fn start_broker(rep_port: u16) {
+        let endpoint = format!("tcp://127.0.0.1:{}", rep_port);
+        let mut rep: Reply = async_zmq::reply(&endpoint).unwrap().bind().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                while let Some(Ok(messages)) = rep.next().await {
+                    let requests: Vec<BrokerRequest> = messages
+                        .iter()
+                        .map(|frame| wire::decode(frame).unwrap())
+                        .collect();
+                    let rejected = requests
+                        .iter()
+                        .any(|r| matches!(r.payload, BrokerRequestPayload::DeadLetter(_)));
+                    let reply = BrokerReply {
+                        id: requests[0].id,
+                        error: rejected.then(|| "dead letters are not kept".to_owned()),
+                        busy: false,
+                    };
+                    rep.send(vec![wire::encode(&reply, WIRE_VERSION).unwrap()])
+                        .await
+                        .unwrap();
+                }
+            })
+        });
+    }
+
+    #[tokio::test]
+    async fn every_caller_gets_its_own_reply() {
+        let port = free_port();
+        start_broker(port);
+        let transport = ZmqTransport::new(broker_cfg(port));
+
+        let acks: Vec<_> = (0..20).map(|_| transport.ack(Uuid::new_v4())).collect();
+        let dead_letter = transport.dead_letter(Uuid::new_v4(), "test".into());
+        let (acks, dead_letter) = tokio::join!(futures::future::join_all(acks), dead_letter);
+
+        assert!(acks.iter().all(|ack| ack.is_ok()));
+        assert!(dead_letter.is_err());
+    }
+
+    /// Request as it was sent before the DEALER connection: a blocking thread with its
+    /// own runtime and a fresh REQ socket for every request
+    async fn send_over_new_connection(endpoint: String, request: BrokerRequest) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .build()?
+                .block_on(async move {
+                    let requestor = async_zmq::request(&endpoint)?.connect()?;
+                    requestor
+                        .send(wire::encode(&request, WIRE_VERSION)?)
+                        .await?;
+                    let _ = requestor.recv().await?;
+                    Ok::<(), anyhow::Error>(())
+                })
+        })
+        .await?
+    }
+
+    /// Throughput of the connection per request against the shared DEALER one,
+    /// run with `cargo test --lib broker_throughput -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn broker_throughput() {
+        const REQUESTS: usize = 2000;
+        const CONCURRENCY: usize = 16;
+
+        let port = free_port();
+        start_broker(port);
+        let endpoint = format!("tcp://127.0.0.1:{}", port);
+
+        let started = Instant::now();
+        for chunk in (0..REQUESTS).collect::<Vec<_>>().chunks(CONCURRENCY) {
+            let sends = chunk.iter().map(|_| {
+                let request = BrokerRequest {
+                    id: Uuid::new_v4(),
+                    payload: BrokerRequestPayload::Ack(Ack {
+                        message_id: Uuid::new_v4(),
+                    }),
+                };
+                send_over_new_connection(endpoint.clone(), request)
+            });
+            for result in futures::future::join_all(sends).await {
+                result.unwrap();
+            }
+        }
+        let before = started.elapsed();
+
+        let transport = ZmqTransport::new(broker_cfg(port));
+        let started = Instant::now();
+        for chunk in (0..REQUESTS).collect::<Vec<_>>().chunks(CONCURRENCY) {
+            let sends = chunk.iter().map(|_| transport.ack(Uuid::new_v4()));
+            for result in futures::future::join_all(sends).await {
+                result.unwrap();
+            }
+        }
+        let after = started.elapsed();
+
+        let rate = |elapsed: Duration| REQUESTS as f64 / elapsed.as_secs_f64();
+        println!(
+            "{} requests, {} at once: connection per request {:.0}/s, DEALER connection {:.0}/s",
+            REQUESTS,
+            CONCURRENCY,
+            rate(before),
+            rate(after)
+        );
+    }
+}
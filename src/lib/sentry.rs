@@ -3,10 +3,8 @@ pub fn init_sentry() -> sentry::ClientInitGuard {
     options.release = sentry::release_name!();
     options.attach_stacktrace = true;
 
-    let guard = sentry::init((
+    sentry::init((
         "https://fd712925b5fc9c2bc1ac4edf3d1c0b82@sentry.wposek.ru/5",
         options,
-    ));
-
-    guard
+    ))
 }
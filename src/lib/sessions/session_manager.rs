@@ -26,7 +26,7 @@ impl SessionManager {
 
     pub async fn auth_v2(&mut self, user: &WebAppUser) -> Result<()> {
         let cookie = uuid::Uuid::new_v4().to_string();
-        self.storage.set_session_v2(&cookie, &user).await?;
+        self.storage.set_session_v2(&cookie, user).await?;
 
         if !self.storage.is_user_registed(user).await? {
             self.storage.register_user(user).await?;
@@ -53,33 +53,27 @@ impl SessionManager {
     }
 
     fn get_cookie(&self) -> Option<String> {
-        let cookie = self.cookies.get(COOKIE_NAME);
-        let cookie = match cookie {
-            Some(cookie) => {
-                cookie
-            },
-            None => return None
-        };
+        let cookie = self.cookies.get(COOKIE_NAME)?;
         Some(cookie.value().to_owned())
     }
 
     pub async fn get_user_v2(&self) -> Result<WebAppUser> {
         let cookie = match self.get_cookie() {
             Some(cookie) => cookie,
-            None => return Err(Error::InternalError(InternalError::RuntimeError(format!("Unauthorized"))))
+            None => return Err(Error::InternalError(InternalError::RuntimeError("Unauthorized".to_string())))
         };
         match self.storage.get_session_v2(&cookie).await? {
             Some(user) => Ok(user),
-            None => Err(Error::InternalError(InternalError::RuntimeError(format!("Unauthorized"))))
+            None => Err(Error::InternalError(InternalError::RuntimeError("Unauthorized".to_string())))
         }
     }
 
     pub async fn logout_v2(&mut self) {
-        let ref cookie = match self.get_cookie() {
+        let cookie = match self.get_cookie() {
             Some(cookie) => cookie,
             None => return
         };
-        self.storage.remove_session_v2(cookie).await.ok();
+        self.storage.remove_session_v2(&cookie).await.ok();
         self.cookies.remove(Cookie::build(COOKIE_NAME).build());
     }
 }
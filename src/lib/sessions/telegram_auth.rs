@@ -15,7 +15,7 @@ pub struct WebAppInitData {
     pub data_check_string: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, Hash)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct WebAppUser {
     pub id: i64,
 }
@@ -26,17 +26,11 @@ impl std::convert::From<i64> for WebAppUser {
     }
 }
 
-impl std::cmp::PartialEq for WebAppUser {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
-    }
-}
-
 impl TryFrom<&str> for WebAppInitData {
     type Error = String;
 
     fn try_from(query: &str) -> Result<Self, Self::Error> {
-        let params = querystring::querify(&query);
+        let params = querystring::querify(query);
         let mut items = query.split("&")
             .filter(|s| !s.starts_with("hash="))
             .map(|str| urlencoding::decode(str).unwrap().to_string())
@@ -68,7 +62,7 @@ impl TryFrom<&str> for WebAppInitData {
         }
 
         if init_data.hash.is_empty() {
-            return Err(format!("TryFrom failed: hash is empty"));
+            return Err("TryFrom failed: hash is empty".to_string());
         }
 
         Ok(init_data)
@@ -10,10 +10,10 @@ pub struct AttachRequest {
 }
 
 impl AttachRequest {
-    pub fn new(username: &String) -> AttachRequest {
+    pub fn new(username: &str) -> AttachRequest {
         let mut rng = rand::thread_rng();
         let code = rng.gen_range(100000..999999).to_string();
-        let username = username.clone();
+        let username = username.to_owned();
         let expires = std::time::SystemTime::now() + std::time::Duration::from_secs(30);
         AttachRequest {
             code,
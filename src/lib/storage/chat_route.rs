@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use teloxide_core::types::{ChatId, MessageId, ThreadId, UserId};
+
+/// Which notifications of the user go to the linked chat
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteScope {
+    /// All mail of the account
+    Account,
+    /// Mail matching the importance rules, it goes there instead of the account chat
+    Important,
+}
+
+impl RouteScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteScope::Account => "account",
+            RouteScope::Important => "important",
+        }
+    }
+}
+
+impl std::str::FromStr for RouteScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "account" => Ok(RouteScope::Account),
+            "important" => Ok(RouteScope::Important),
+            _ => Err(anyhow::anyhow!("Unknown route scope: {}", s)),
+        }
+    }
+}
+
+/// Chat a notification is delivered to: private chat of the user, a group or a topic of a forum
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChatRoute {
+    pub chat_id: i64,
+    /// Forum topic, `None` for the whole chat
+    pub thread_id: Option<i32>,
+}
+
+impl ChatRoute {
+    pub fn chat(&self) -> ChatId {
+        ChatId(self.chat_id)
+    }
+
+    pub fn thread(&self) -> Option<ThreadId> {
+        self.thread_id.map(|id| ThreadId(MessageId(id)))
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.chat().is_user()
+    }
+}
+
+impl From<UserId> for ChatRoute {
+    fn from(user: UserId) -> Self {
+        ChatRoute {
+            chat_id: ChatId::from(user).0,
+            thread_id: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkedChat {
+    pub route: ChatRoute,
+    /// Title of the chat when it was linked, for showing the route to the user
+    pub title: String,
+}
+
+/// Chats the user routed notifications to, they go to the private chat otherwise
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatRoutes {
+    pub account: Option<LinkedChat>,
+    pub important: Option<LinkedChat>,
+}
+
+impl ChatRoutes {
+    /// Where a notification goes, `None` for the private chat
+    pub fn route(&self, important: bool) -> Option<ChatRoute> {
+        let important = match important {
+            true => self.important.as_ref(),
+            false => None,
+        };
+        important
+            .or(self.account.as_ref())
+            .map(|linked| linked.route)
+    }
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+/// How routine notifications of the account are delivered, important ones always make a sound
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeliverySettings {
+    /// Routine notifications never make a sound
+    pub silent_routine: bool,
+    /// Hours `[start, end)` in the user time zone when routine notifications are silent,
+    /// the band may span midnight
+    pub soft_hours: Option<[u8; 2]>,
+    /// Routine mail outside working hours comes right away but silently, instead of waiting for them
+    pub deliver_off_hours: bool,
+}
+
+impl DeliverySettings {
+    pub fn is_valid(&self) -> bool {
+        match self.soft_hours {
+            Some([start, end]) => start < 24 && end < 24 && start != end,
+            None => true,
+        }
+    }
+
+    pub fn is_soft_hour(&self, hour: u32) -> bool {
+        match self.soft_hours {
+            Some([start, end]) if start <= end => (start as u32..end as u32).contains(&hour),
+            Some([start, end]) => hour >= start as u32 || hour < end as u32,
+            None => false,
+        }
+    }
+}
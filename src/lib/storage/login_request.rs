@@ -10,10 +10,10 @@ pub struct LoginRequest {
 }
 
 impl LoginRequest {
-    pub fn new(username: &String) -> LoginRequest {
+    pub fn new(username: &str) -> LoginRequest {
         let mut rng = rand::thread_rng();
         let code = rng.gen_range(100000..999999).to_string();
-        let username = username.clone();
+        let username = username.to_owned();
         let expires = std::time::SystemTime::now() + std::time::Duration::from_secs(30);
         LoginRequest {
             code,
@@ -1,10 +1,55 @@
 use serde::{Deserialize, Serialize};
 
 use super::cipher::Cipher;
-#[derive(Serialize, Deserialize, Debug)]
+use crate::cfg::MailCfg;
+
+const POP3S_PORT: u16 = 995;
+const HTTPS_PORT: u16 = 443;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MailProtocol {
+    #[default]
+    Imap,
+    Pop3,
+    Jmap,
+}
+
+impl MailProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MailProtocol::Imap => "imap",
+            MailProtocol::Pop3 => "pop3",
+            MailProtocol::Jmap => "jmap",
+        }
+    }
+}
+
+impl std::str::FromStr for MailProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "imap" => Ok(MailProtocol::Imap),
+            "pop3" => Ok(MailProtocol::Pop3),
+            "jmap" => Ok(MailProtocol::Jmap),
+            _ => Err(anyhow::anyhow!("Unknown mail protocol: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MailAccount {
     pub email: String,
     pub password: String,
+    #[serde(default)]
+    pub protocol: MailProtocol,
+    /// Mail server host, `mail.address` from config is used when empty
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Mail server port, protocol default is used when empty
+    #[serde(default)]
+    pub port: Option<u16>,
 }
 
 
@@ -12,12 +57,42 @@ pub struct MailAccount {
 pub struct MailAccountEncrypted {
     pub email: String,
     pub password: Vec<u8>,
+    pub protocol: MailProtocol,
+    pub host: Option<String>,
+    pub port: Option<u16>,
 }
 
 impl MailAccount {
+    /// Returns server address of the account falling back to `mail` config and protocol defaults
+    pub fn server(&self, cfg: &MailCfg) -> (String, u16) {
+        let host = self.host.clone().unwrap_or(cfg.address.clone());
+        let port = self.port.unwrap_or(match self.protocol {
+            MailProtocol::Imap => cfg.port,
+            MailProtocol::Pop3 => POP3S_PORT,
+            MailProtocol::Jmap => HTTPS_PORT,
+        });
+        (host, port)
+    }
+
+    /// Base URL of the JMAP server, `host` may include scheme for non-TLS servers
+    pub fn jmap_base_url(&self, cfg: &MailCfg) -> String {
+        let host = self.host.clone().unwrap_or(cfg.address.clone());
+        match (host.contains("://"), self.port) {
+            (true, _) => host,
+            (false, Some(port)) => format!("https://{}:{}", host, port),
+            (false, None) => format!("https://{}", host),
+        }
+    }
+
     pub fn encrypt(self, cipher: &Cipher) -> MailAccountEncrypted {
         let password = cipher.encrypt(self.password.as_bytes());
-        MailAccountEncrypted { email: self.email, password }
+        MailAccountEncrypted {
+            email: self.email,
+            password,
+            protocol: self.protocol,
+            host: self.host,
+            port: self.port,
+        }
     }
 }
 
@@ -27,6 +102,9 @@ impl MailAccountEncrypted {
         MailAccount {
             email: self.email,
             password: String::from_utf8(password).unwrap(),
+            protocol: self.protocol,
+            host: self.host,
+            port: self.port,
         }
     }
 }
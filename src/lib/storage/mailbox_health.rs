@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+const BASE_BACKOFF_SECS: i64 = 60;
+const MAX_BACKOFF_SECS: i64 = 6 * 3600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxProblem {
+    AuthFailed,
+    Unreachable,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MailboxHealth {
+    pub failures: u32,
+    pub problem: Option<MailboxProblem>,
+    /// Unix timestamp before which the mailbox should not be checked again
+    pub next_check_at: i64,
+    /// Whether the user was told about the current problem
+    pub notified: bool,
+}
+
+impl MailboxHealth {
+    pub fn can_check_now(&self) -> bool {
+        chrono::Utc::now().timestamp() >= self.next_check_at
+    }
+
+    pub fn is_failing(&self) -> bool {
+        self.failures > 0
+    }
+
+    /// Registers failed check and postpones the next one exponentially
+    pub fn record_failure(&mut self, problem: MailboxProblem) {
+        self.failures += 1;
+        self.problem = Some(problem);
+        let backoff = BASE_BACKOFF_SECS
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(MAX_BACKOFF_SECS);
+        self.next_check_at = chrono::Utc::now().timestamp() + backoff;
+    }
+}
@@ -1,11 +1,20 @@
 mod attach_request;
+mod chat_route;
 mod cipher;
+mod delivery_settings;
 mod login_request;
 mod mail_account;
+mod mailbox_health;
+mod notified_mail;
+#[allow(clippy::module_inception)]
 mod storage;
 
 pub use attach_request::AttachRequest;
+pub use chat_route::{ChatRoute, ChatRoutes, LinkedChat, RouteScope};
+pub use delivery_settings::DeliverySettings;
 pub use login_request::LoginRequest;
-pub use mail_account::MailAccount;
-pub use storage::Storage;
+pub use mail_account::{MailAccount, MailProtocol};
+pub use mailbox_health::{MailboxHealth, MailboxProblem};
+pub use notified_mail::{DeferredMail, MailChange, MailRef, NotifiedMail};
+pub use storage::{BrokerQueueStats, Storage, StoredBrokerMessage, StreamEntry};
 pub use cipher::Cipher;
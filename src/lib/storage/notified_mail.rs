@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+use crate::storage::ChatRoute;
+
+/// Where the mail a notification is about is found in the mailbox again
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MailRef {
+    /// Login of the mail account, the user may switch to another mailbox meanwhile
+    #[serde(default)]
+    pub account: String,
+    pub folder: String,
+    /// IMAP UID or JMAP email id
+    pub id: String,
+    /// UIDVALIDITY of the IMAP folder, UIDs under another one belong to other mails
+    pub uid_validity: Option<u32>,
+}
+
+impl MailRef {
+    pub fn key(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.uid_validity.unwrap_or_default(),
+            self.id,
+            self.account,
+            self.folder
+        )
+    }
+}
+
+/// What happened to the mail since the user was notified about it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailChange {
+    /// Read in another client
+    Seen,
+    /// Deleted or expunged
+    Gone,
+}
+
+/// Notification sent to Telegram, kept for a while to update it when the mail is read elsewhere
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotifiedMail {
+    pub mail: MailRef,
+    pub chat: ChatRoute,
+    pub message_id: i32,
+    /// MarkdownV2 text of the notification, the edited message keeps it
+    pub text: String,
+    /// Unix timestamp
+    pub sent_at: i64,
+}
+
+/// Mail whose notification waits in the queue for working hours,
+/// it is cancelled if the mail is read or removed before. Once due, it is looked up
+/// one last time and the notification is either cancelled or confirmed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeferredMail {
+    pub mail: MailRef,
+    /// Unix timestamp the notification is sent at
+    pub send_after: i64,
+}
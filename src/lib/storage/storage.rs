@@ -1,13 +1,23 @@
 use anyhow::Result;
+use bb8_redis::redis::streams::{
+    StreamClaimReply, StreamInfoGroupsReply, StreamPendingCountReply, StreamPendingReply,
+    StreamRangeReply, StreamReadOptions, StreamReadReply,
+};
 use bb8_redis::redis::AsyncCommands;
 
-use std::collections::{BTreeMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
 
 use crate::cfg::StorageCfg;
+use crate::i18n::Language;
+use crate::queues::{BrokerMessage, DeadLetterMessage, Priority};
 use crate::sessions::WebAppUser;
 use crate::storage::mail_account::MailAccountEncrypted;
-use crate::storage::MailAccount;
+use crate::storage::{
+    ChatRoute, ChatRoutes, DeferredMail, DeliverySettings, LinkedChat, MailAccount, MailProtocol,
+    MailRef, MailboxHealth, NotifiedMail, RouteScope,
+};
 
 use super::cipher::Cipher;
 
@@ -15,6 +25,52 @@ type PGPool =
     bb8::Pool<bb8_postgres::PostgresConnectionManager<bb8_postgres::tokio_postgres::NoTls>>;
 type RedisPool = bb8::Pool<bb8_redis::RedisConnectionManager>;
 
+/// Moscow time, in minutes east of UTC
+const DEFAULT_UTC_OFFSET: i32 = 3 * 60;
+
+/// Entry of a Redis stream carrying a serialized message
+pub struct StreamEntry {
+    pub id: String,
+    pub data: Vec<u8>,
+    /// Number of times the entry was delivered to consumers of the group
+    pub deliveries: u32,
+}
+
+/// Unacked broker messages of a topic and priority
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrokerQueueStats {
+    pub topic: String,
+    pub priority: Priority,
+    /// Waiting to be delivered, for the first time or again
+    pub queued: u64,
+    /// Delivered and not acked yet, deferred ones held by consumers included
+    pub in_flight: u64,
+    /// Seconds since the oldest message was published
+    pub oldest_age: i64,
+}
+
+/// Unacked broker message with its delivery state
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredBrokerMessage {
+    pub message: BrokerMessage,
+    /// Address of the consumer the message was last delivered to
+    pub consumer: Option<String>,
+    /// Unix timestamp before which the message is not delivered again
+    pub visible_at: i64,
+    /// Unix timestamp of the moment the message was published
+    pub created_at: i64,
+}
+
+const STREAM_FIELD: &str = "message";
+/// Telegram updates taken by the web server and not picked up by the bot yet, older ones are dropped
+const TELEGRAM_UPDATES_MAX_LEN: isize = 10_000;
+
+/// Stream entry id `<ms>-<seq>` as a pair which compares the way Redis orders them
+fn parse_stream_id(id: &str) -> Option<(u64, u64)> {
+    let (ms, seq) = id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
+}
+
 #[derive(Clone)]
 pub struct Storage {
     pg: PGPool,
@@ -62,22 +118,18 @@ impl Storage {
     pub async fn set_mail_account(
         &self,
         user: &WebAppUser,
-        email: &String,
-        password: &String,
+        account: MailAccount,
         cipher: &Cipher,
     ) -> Result<()> {
-        let account = MailAccount {
-            email: email.clone(),
-            password: password.clone(),
-        };
         let encrypted_account = account.encrypt(cipher);
         let conn = self.pg.get().await?;
         let statement = conn
             .prepare(
                 r#"
-            INSERT INTO "mail_accounts" ("id", "username", "password")
-            VALUES ($3, $1, $2)
-            ON CONFLICT ("id") DO UPDATE SET "username" = $1, "password" = $2;
+            INSERT INTO "mail_accounts" ("id", "username", "password", "protocol", "host", "port")
+            VALUES ($3, $1, $2, $4, $5, $6)
+            ON CONFLICT ("id") DO UPDATE
+            SET "username" = $1, "password" = $2, "protocol" = $4, "host" = $5, "port" = $6;
         "#,
             )
             .await?;
@@ -87,6 +139,9 @@ impl Storage {
                 &encrypted_account.email,
                 &encrypted_account.password,
                 &user.id,
+                &encrypted_account.protocol.as_str(),
+                &encrypted_account.host,
+                &encrypted_account.port.map(|port| port as i32),
             ],
         )
         .await?;
@@ -102,7 +157,7 @@ impl Storage {
         let statement = conn
             .prepare(
                 r#"
-            SELECT "username", "password"
+            SELECT "username", "password", "protocol", "host", "port"
             FROM "mail_accounts"
             WHERE "id" = $1
         "#,
@@ -114,13 +169,80 @@ impl Storage {
         }
 
         let row = &rows[0];
+        let protocol: String = row.get(2);
+        let port: Option<i32> = row.get(4);
         let enc_account = MailAccountEncrypted {
             email: row.get(0),
             password: row.get(1),
+            protocol: MailProtocol::from_str(&protocol)?,
+            host: row.get(3),
+            port: port.map(|port| port as u16),
         };
         Ok(Some(enc_account.decrypt(cipher)))
     }
 
+    /// Delivery settings of the user mail account, the defaults if there is no account
+    pub async fn get_delivery_settings(&self, user: &WebAppUser) -> Result<DeliverySettings> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "silent_routine", "soft_hours_start", "soft_hours_end", "deliver_off_hours"
+            FROM "mail_accounts"
+            WHERE "id" = $1
+        "#,
+            )
+            .await?;
+        let row = match conn.query_opt(&statement, &[&user.id]).await? {
+            Some(row) => row,
+            None => return Ok(DeliverySettings::default()),
+        };
+        let start: Option<i32> = row.get(1);
+        let end: Option<i32> = row.get(2);
+        Ok(DeliverySettings {
+            silent_routine: row.get(0),
+            soft_hours: start.zip(end).map(|(start, end)| [start as u8, end as u8]),
+            deliver_off_hours: row.get(3),
+        })
+    }
+
+    /// Returns `false` if the user has no mail account the settings belong to
+    pub async fn set_delivery_settings(
+        &self,
+        user: &WebAppUser,
+        settings: &DeliverySettings,
+    ) -> Result<bool> {
+        if !settings.is_valid() {
+            return Err(anyhow::anyhow!("Soft hours are not valid"));
+        }
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            UPDATE "mail_accounts"
+            SET "silent_routine" = $1, "soft_hours_start" = $2, "soft_hours_end" = $3,
+                "deliver_off_hours" = $4
+            WHERE "id" = $5
+        "#,
+            )
+            .await?;
+        let start = settings.soft_hours.map(|hours| hours[0] as i32);
+        let end = settings.soft_hours.map(|hours| hours[1] as i32);
+        let updated = conn
+            .execute(
+                &statement,
+                &[
+                    &settings.silent_routine,
+                    &start,
+                    &end,
+                    &settings.deliver_off_hours,
+                    &user.id,
+                ],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
     pub async fn add_processed_mails(&self, user: &WebAppUser, uids: &[u32]) -> Result<()> {
         let key = format!("PROCESSED_MAIL:{}", user.id);
         let mut conn = self.redis.get().await?;
@@ -145,6 +267,314 @@ impl Storage {
         Ok(unprocessed)
     }
 
+    /// Returns ids which are not remembered under `namespace` yet,
+    /// used by mail sources that have no server-side processed flag
+    pub async fn filter_unknown_ids(
+        &self,
+        user: &WebAppUser,
+        namespace: &str,
+        ids: &[String],
+    ) -> Result<Vec<String>> {
+        let mut unknown = Vec::<String>::new();
+        let key = format!("{}:{}", namespace, user.id);
+        let mut conn = self.redis.get().await?;
+
+        for id in ids {
+            let exists: bool = conn.sismember(&key, id).await?;
+            if !exists {
+                unknown.push(id.clone());
+            }
+        }
+
+        Ok(unknown)
+    }
+
+    pub async fn add_known_ids(
+        &self,
+        user: &WebAppUser,
+        namespace: &str,
+        ids: &[String],
+    ) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let key = format!("{}:{}", namespace, user.id);
+        let mut conn = self.redis.get().await?;
+        // In one go, the first sync of a mailbox remembers all of its mail
+        let _: () = conn.sadd(&key, ids).await?;
+        Ok(())
+    }
+
+    /// Whether ids under `namespace` were ever remembered for the user, an empty set is not enough
+    /// to tell since ids of removed messages are forgotten
+    pub async fn is_synced(&self, user: &WebAppUser, namespace: &str) -> Result<bool> {
+        let key = format!("{}_SYNCED:{}", namespace, user.id);
+        let mut conn = self.redis.get().await?;
+        let synced: bool = conn.exists(&key).await?;
+        Ok(synced)
+    }
+
+    pub async fn mark_synced(&self, user: &WebAppUser, namespace: &str) -> Result<()> {
+        let key = format!("{}_SYNCED:{}", namespace, user.id);
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.set(&key, 1).await?;
+        Ok(())
+    }
+
+    /// Drops remembered ids of messages which are no longer reported by the server
+    pub async fn forget_missing_ids(
+        &self,
+        user: &WebAppUser,
+        namespace: &str,
+        present: &[String],
+    ) -> Result<()> {
+        let key = format!("{}:{}", namespace, user.id);
+        let mut conn = self.redis.get().await?;
+        let known: HashSet<String> = conn.smembers(&key).await?;
+        for id in known.iter().filter(|id| !present.contains(id)) {
+            let _: () = conn.srem(&key, id).await?;
+        }
+        Ok(())
+    }
+
+    /// Remembers the sent notification, so it can be updated when the mail changes in the mailbox
+    pub async fn add_notified_mail(
+        &self,
+        user: &WebAppUser,
+        notified: &NotifiedMail,
+    ) -> Result<()> {
+        let key = format!("NOTIFIED_MAILS:{}", user.id);
+        let data = serde_cbor::to_vec(notified)?;
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.hset(&key, notified.mail.key(), data).await?;
+        Ok(())
+    }
+
+    pub async fn get_notified_mails(&self, user: &WebAppUser) -> Result<Vec<NotifiedMail>> {
+        let key = format!("NOTIFIED_MAILS:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let data: Vec<Vec<u8>> = conn.hvals(&key).await?;
+        let mut notified = Vec::with_capacity(data.len());
+        for data in data {
+            notified.push(serde_cbor::from_slice(&data)?);
+        }
+        Ok(notified)
+    }
+
+    pub async fn remove_notified_mails(&self, user: &WebAppUser, mails: &[&MailRef]) -> Result<()> {
+        if mails.is_empty() {
+            return Ok(());
+        }
+        let key = format!("NOTIFIED_MAILS:{}", user.id);
+        let fields: Vec<String> = mails.iter().map(|mail| mail.key()).collect();
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.hdel(&key, fields).await?;
+        Ok(())
+    }
+
+    /// Remembers mails whose notifications wait for working hours, to cancel them if the mail is read
+    pub async fn add_deferred_mails(
+        &self,
+        user: &WebAppUser,
+        mails: &[DeferredMail],
+    ) -> Result<()> {
+        if mails.is_empty() {
+            return Ok(());
+        }
+        let key = format!("DEFERRED_MAILS:{}", user.id);
+        let mut fields = Vec::with_capacity(mails.len());
+        for mail in mails {
+            fields.push((mail.mail.key(), serde_cbor::to_vec(mail)?));
+        }
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.hset_multiple(&key, &fields).await?;
+        Ok(())
+    }
+
+    pub async fn get_deferred_mails(&self, user: &WebAppUser) -> Result<Vec<DeferredMail>> {
+        let key = format!("DEFERRED_MAILS:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let data: Vec<Vec<u8>> = conn.hvals(&key).await?;
+        let mut mails = Vec::with_capacity(data.len());
+        for data in data {
+            mails.push(serde_cbor::from_slice(&data)?);
+        }
+        Ok(mails)
+    }
+
+    pub async fn remove_deferred_mails(&self, user: &WebAppUser, mails: &[&MailRef]) -> Result<()> {
+        if mails.is_empty() {
+            return Ok(());
+        }
+        let key = format!("DEFERRED_MAILS:{}", user.id);
+        let fields: Vec<String> = mails.iter().map(|mail| mail.key()).collect();
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.hdel(&key, fields).await?;
+        Ok(())
+    }
+
+    /// Marks deferred notifications of the mails as not to be sent, the bot drops them when they are due
+    pub async fn cancel_mails(&self, user: &WebAppUser, mails: &[&MailRef]) -> Result<()> {
+        if mails.is_empty() {
+            return Ok(());
+        }
+        let key = format!("CANCELLED_MAILS:{}", user.id);
+        let members: Vec<String> = mails.iter().map(|mail| mail.key()).collect();
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.sadd(&key, members).await?;
+        // Outlives the longest deferral, a weekend with holidays around it
+        let _: () = conn.expire(&key, 7 * 24 * 3600).await?;
+        Ok(())
+    }
+
+    /// Whether the notification of the mail was cancelled, the mark is cleared
+    pub async fn take_cancelled_mail(&self, user: &WebAppUser, mail: &MailRef) -> Result<bool> {
+        let key = format!("CANCELLED_MAILS:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let removed: i64 = conn.srem(&key, mail.key()).await?;
+        Ok(removed > 0)
+    }
+
+    /// Marks due deferred notifications of the mails as still unread, the bot sends them then
+    pub async fn confirm_mails(&self, user: &WebAppUser, mails: &[&MailRef]) -> Result<()> {
+        if mails.is_empty() {
+            return Ok(());
+        }
+        let key = format!("CONFIRMED_MAILS:{}", user.id);
+        let members: Vec<String> = mails.iter().map(|mail| mail.key()).collect();
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.sadd(&key, members).await?;
+        // Marks of notifications sent by "Fetch all emails" meanwhile are left to expire
+        let _: () = conn.expire(&key, 24 * 3600).await?;
+        Ok(())
+    }
+
+    /// Whether the mail of the due notification was confirmed unread, the mark is cleared
+    pub async fn take_confirmed_mail(&self, user: &WebAppUser, mail: &MailRef) -> Result<bool> {
+        let key = format!("CONFIRMED_MAILS:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let removed: i64 = conn.srem(&key, mail.key()).await?;
+        Ok(removed > 0)
+    }
+
+    /// HIGHESTMODSEQ of the IMAP folder the notified mails were last looked up at
+    pub async fn get_folder_modseq(&self, user: &WebAppUser, folder: &str) -> Result<Option<u64>> {
+        let key = format!("FOLDER_MODSEQ:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        Ok(conn.hget(&key, folder).await?)
+    }
+
+    pub async fn set_folder_modseq(
+        &self,
+        user: &WebAppUser,
+        folder: &str,
+        modseq: u64,
+    ) -> Result<()> {
+        let key = format!("FOLDER_MODSEQ:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.hset(&key, folder, modseq).await?;
+        Ok(())
+    }
+
+    pub async fn get_mailbox_health(&self, user: &WebAppUser) -> Result<MailboxHealth> {
+        let key = format!("MAILBOX_HEALTH:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let data: Option<Vec<u8>> = conn.get(&key).await?;
+        match data {
+            Some(data) => Ok(serde_cbor::from_slice(&data)?),
+            None => Ok(MailboxHealth::default()),
+        }
+    }
+
+    pub async fn set_mailbox_health(
+        &self,
+        user: &WebAppUser,
+        health: &MailboxHealth,
+    ) -> Result<()> {
+        let key = format!("MAILBOX_HEALTH:{}", user.id);
+        let data = serde_cbor::to_vec(health)?;
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.set(&key, data).await?;
+        Ok(())
+    }
+
+    pub async fn reset_mailbox_health(&self, user: &WebAppUser) -> Result<()> {
+        let key = format!("MAILBOX_HEALTH:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.del(&key).await?;
+        Ok(())
+    }
+
+    /// Unix timestamp of the last successful check of the user mailbox
+    /// Counts connection tests the user ran in the current `window` of seconds, this one included
+    pub async fn count_connection_test(&self, user: &WebAppUser, window: i64) -> Result<i64> {
+        let key = format!("CONNECTION_TESTS:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let count: i64 = conn.incr(&key, 1).await?;
+        if count == 1 {
+            let _: () = conn.expire(&key, window).await?;
+        }
+        Ok(count)
+    }
+
+    pub async fn get_last_check(&self, user: &WebAppUser) -> Result<Option<i64>> {
+        let key = format!("LAST_CHECK:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        Ok(conn.get(&key).await?)
+    }
+
+    pub async fn set_last_check(&self, user: &WebAppUser, timestamp: i64) -> Result<()> {
+        let key = format!("LAST_CHECK:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.set(&key, timestamp).await?;
+        Ok(())
+    }
+
+    /// Unix timestamp until which routine notifications are held back, `None` when not quiet
+    pub async fn get_quiet_until(&self, user: &WebAppUser) -> Result<Option<i64>> {
+        let key = format!("QUIET_UNTIL:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let until: Option<i64> = conn.get(&key).await?;
+        Ok(until.filter(|until| *until > chrono::Utc::now().timestamp()))
+    }
+
+    pub async fn set_quiet_until(&self, user: &WebAppUser, until: i64) -> Result<()> {
+        let key = format!("QUIET_UNTIL:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.set(&key, until).await?;
+        let _: () = conn.expire_at(&key, until).await?;
+        Ok(())
+    }
+
+    pub async fn reset_quiet(&self, user: &WebAppUser) -> Result<()> {
+        let key = format!("QUIET_UNTIL:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.del(&key).await?;
+        Ok(())
+    }
+
+    /// Queues raw Telegram update for bot instances, each update is taken by one of them
+    pub async fn push_telegram_update(&self, update: &str) -> Result<()> {
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.rpush("TELEGRAM_UPDATES", update).await?;
+        let _: () = conn
+            .ltrim("TELEGRAM_UPDATES", -TELEGRAM_UPDATES_MAX_LEN, -1)
+            .await?;
+        Ok(())
+    }
+
+    /// Takes the oldest queued Telegram update, waiting for one up to `timeout`
+    pub async fn pop_telegram_update(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<Option<String>> {
+        let mut conn = self.redis.get().await?;
+        let update: Option<(String, String)> = conn
+            .blpop("TELEGRAM_UPDATES", timeout.as_secs_f64())
+            .await?;
+        Ok(update.map(|(_, update)| update))
+    }
+
     pub async fn is_checking_enabled(&self, user: &WebAppUser) -> Result<bool> {
         let conn = self.pg.get().await?;
         let statement = conn
@@ -263,6 +693,43 @@ impl Storage {
         Ok(())
     }
 
+    /// Minutes east of UTC the working hours and times in notifications are in,
+    /// Moscow time unless the user set another
+    pub async fn get_user_utc_offset(&self, user: &WebAppUser) -> Result<chrono::FixedOffset> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+             SELECT "utc_offset"
+             FROM "working_hours"
+             WHERE "id" = $1
+        "#,
+            )
+            .await?;
+        let row = conn.query_opt(&statement, &[&user.id]).await?;
+        let minutes: i32 = row.map(|row| row.get(0)).unwrap_or(DEFAULT_UTC_OFFSET);
+        chrono::FixedOffset::east_opt(minutes * 60).ok_or(anyhow::anyhow!(
+            "Invalid UTC offset {} of user {}",
+            minutes,
+            user.id
+        ))
+    }
+
+    pub async fn set_user_utc_offset(&self, user: &WebAppUser, minutes: i32) -> Result<()> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            UPDATE "working_hours"
+            SET "utc_offset" = $1
+            WHERE "id" = $2
+       "#,
+            )
+            .await?;
+        conn.execute(&statement, &[&minutes, &user.id]).await?;
+        Ok(())
+    }
+
     pub async fn get_important_emails(&self, user: &WebAppUser) -> Result<Vec<String>> {
         let conn = self.pg.get().await?;
         let statement = conn
@@ -277,13 +744,9 @@ impl Storage {
         Ok(rows.iter().map(|row| row.get(0)).collect())
     }
 
-    pub async fn set_important_emails(
-        &self,
-        user: &WebAppUser,
-        emails: &Vec<String>,
-    ) -> Result<bool> {
+    pub async fn set_important_emails(&self, user: &WebAppUser, emails: &[String]) -> Result<bool> {
         let conn = self.pg.get().await?;
-        let emails_array = postgres_array::Array::from_vec(emails.clone(), 0);
+        let emails_array = postgres_array::Array::from_vec(emails.to_vec(), 0);
         let statement = conn
             .prepare(
                 r#"
@@ -311,8 +774,8 @@ impl Storage {
         Ok(rows.iter().map(|row| row.get(0)).collect())
     }
 
-    pub async fn set_important_tags(&self, user: &WebAppUser, tags: &Vec<String>) -> Result<()> {
-        let tags_array = postgres_array::Array::from_vec(tags.clone(), 0);
+    pub async fn set_important_tags(&self, user: &WebAppUser, tags: &[String]) -> Result<()> {
+        let tags_array = postgres_array::Array::from_vec(tags.to_vec(), 0);
         let conn = self.pg.get().await?;
         let statement = conn
             .prepare(
@@ -327,20 +790,672 @@ impl Storage {
         Ok(())
     }
 
-    pub async fn set_heartbeat(&self, service: &String, timestamp: i64) -> Result<bool> {
+    /// Template of the user notifications, `None` for the default preset
+    pub async fn get_notification_template(&self, user: &WebAppUser) -> Result<Option<String>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "notification_template" FROM "users"
+            WHERE "id" = $1
+        "#,
+            )
+            .await?;
+        let row = conn.query_opt(&statement, &[&user.id]).await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    pub async fn set_notification_template(
+        &self,
+        user: &WebAppUser,
+        template: Option<&str>,
+    ) -> Result<()> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            UPDATE "users"
+            SET "notification_template" = $1
+            WHERE "id" = $2
+        "#,
+            )
+            .await?;
+        conn.execute(&statement, &[&template, &user.id]).await?;
+        Ok(())
+    }
+
+    /// Language the user talks to, the chosen one or the one of the Telegram app
+    pub async fn get_user_language(&self, user: &WebAppUser) -> Result<Language> {
+        if let Some(language) = self.get_language_override(user).await? {
+            return Ok(language);
+        }
+        let key = format!("TELEGRAM_LANGUAGE:{}", user.id);
         let mut conn = self.redis.get().await?;
-        let res = conn
-            .hset(&String::from("HEARTBEAT"), service, timestamp)
+        let language: Option<String> = conn.get(&key).await?;
+        Ok(language
+            .and_then(|language| language.parse().ok())
+            .unwrap_or_default())
+    }
+
+    /// Language chosen by the user, `None` to follow the Telegram app
+    pub async fn get_language_override(&self, user: &WebAppUser) -> Result<Option<Language>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "language" FROM "users"
+            WHERE "id" = $1
+        "#,
+            )
             .await?;
+        let row = conn.query_opt(&statement, &[&user.id]).await?;
+        let language: Option<String> = row.and_then(|row| row.get(0));
+        Ok(language.and_then(|language| language.parse().ok()))
+    }
+
+    pub async fn set_language_override(
+        &self,
+        user: &WebAppUser,
+        language: Option<Language>,
+    ) -> Result<()> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            UPDATE "users"
+            SET "language" = $1
+            WHERE "id" = $2
+        "#,
+            )
+            .await?;
+        let language = language.map(|language| language.as_str());
+        conn.execute(&statement, &[&language, &user.id]).await?;
+        Ok(())
+    }
+
+    /// Remembers the language of the user Telegram app, seen in their messages
+    pub async fn set_telegram_language(&self, user: &WebAppUser, language: Language) -> Result<()> {
+        let key = format!("TELEGRAM_LANGUAGE:{}", user.id);
+        let mut conn = self.redis.get().await?;
+        let _: () = conn.set(&key, language.as_str()).await?;
+        Ok(())
+    }
+
+    /// Group chats and forum topics the user routed notifications to
+    pub async fn get_chat_routes(&self, user: &WebAppUser) -> Result<ChatRoutes> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "scope", "chat_id", "thread_id", "title" FROM "chat_routes"
+            WHERE "id" = $1
+        "#,
+            )
+            .await?;
+        let rows = conn.query(&statement, &[&user.id]).await?;
+        let mut routes = ChatRoutes::default();
+        for row in rows {
+            let linked = LinkedChat {
+                route: ChatRoute {
+                    chat_id: row.get(1),
+                    thread_id: row.get(2),
+                },
+                title: row.get(3),
+            };
+            let scope: String = row.get(0);
+            match RouteScope::from_str(&scope)? {
+                RouteScope::Account => routes.account = Some(linked),
+                RouteScope::Important => routes.important = Some(linked),
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Routes the notifications to the chat, `None` sends them to the private chat again
+    pub async fn set_chat_route(
+        &self,
+        user: &WebAppUser,
+        scope: RouteScope,
+        linked: Option<&LinkedChat>,
+    ) -> Result<()> {
+        let conn = self.pg.get().await?;
+        match linked {
+            Some(linked) => {
+                let statement = conn
+                    .prepare(
+                        r#"
+            INSERT INTO "chat_routes" ("id", "scope", "chat_id", "thread_id", "title")
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT ("id", "scope") DO UPDATE
+            SET "chat_id" = $3, "thread_id" = $4, "title" = $5;
+        "#,
+                    )
+                    .await?;
+                conn.execute(
+                    &statement,
+                    &[
+                        &user.id,
+                        &scope.as_str(),
+                        &linked.route.chat_id,
+                        &linked.route.thread_id,
+                        &linked.title,
+                    ],
+                )
+                .await?;
+            }
+            None => {
+                let statement = conn
+                    .prepare(
+                        r#"
+            DELETE FROM "chat_routes"
+            WHERE "id" = $1 AND "scope" = $2
+        "#,
+                    )
+                    .await?;
+                conn.execute(&statement, &[&user.id, &scope.as_str()])
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_heartbeat(&self, service: &String, timestamp: i64) -> Result<bool> {
+        let mut conn = self.redis.get().await?;
+        let res = conn.hset("HEARTBEAT", service, timestamp).await?;
         Ok(res)
     }
 
     pub async fn get_heartbeat(&self) -> Result<BTreeMap<String, i64>> {
         let mut conn = self.redis.get().await?;
-        let res = conn.hgetall(&String::from("HEARTBEAT")).await?;
+        let res = conn.hgetall("HEARTBEAT").await?;
         Ok(res)
     }
 
+    /// Returns false if the message was already saved
+    pub async fn save_broker_message(&self, message: &BrokerMessage) -> Result<bool> {
+        let payload = serde_json::to_vec(message)?;
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            INSERT INTO "broker_messages" ("id", "payload", "priority", "topic")
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT ("id") DO NOTHING;
+        "#,
+            )
+            .await?;
+        let inserted = conn
+            .execute(
+                &statement,
+                &[
+                    &message.message_id.to_string(),
+                    &payload,
+                    &message.priority().as_str(),
+                    &message.topic().as_str(),
+                ],
+            )
+            .await?;
+        Ok(inserted > 0)
+    }
+
+    pub async fn ack_broker_message(&self, message_id: &uuid::Uuid) -> Result<bool> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            DELETE FROM "broker_messages"
+            WHERE "id" = $1
+        "#,
+            )
+            .await?;
+        let removed = conn.execute(&statement, &[&message_id.to_string()]).await?;
+        Ok(removed > 0)
+    }
+
+    /// Counts the delivery to `consumer` and hides the message until `visible_at` (unix timestamp).
+    /// Returns the number of deliveries, `None` when the message was already acked
+    pub async fn mark_broker_message_delivered(
+        &self,
+        message_id: &uuid::Uuid,
+        visible_at: i64,
+        consumer: &str,
+    ) -> Result<Option<u32>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            UPDATE "broker_messages"
+            SET "attempts" = "attempts" + 1, "visible_at" = $2, "consumer" = $3
+            WHERE "id" = $1
+            RETURNING "attempts"
+        "#,
+            )
+            .await?;
+        let row = conn
+            .query_opt(
+                &statement,
+                &[&message_id.to_string(), &visible_at, &consumer],
+            )
+            .await?;
+        Ok(row.map(|row| row.get::<_, i32>(0) as u32))
+    }
+
+    /// Returns unacked messages last delivered to `consumer`
+    pub async fn get_assigned_broker_messages(&self, consumer: &str) -> Result<Vec<BrokerMessage>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "payload", "attempts" FROM "broker_messages"
+            WHERE "consumer" = $1
+            ORDER BY "priority" = 'high' DESC, "created_at"
+        "#,
+            )
+            .await?;
+        let rows = conn.query(&statement, &[&consumer]).await?;
+        rows.iter().map(Self::broker_message_from_row).collect()
+    }
+
+    /// Returns unacked messages whose visibility timeout expired by `now` (unix timestamp)
+    pub async fn get_expired_broker_messages(&self, now: i64) -> Result<Vec<BrokerMessage>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "payload", "attempts" FROM "broker_messages"
+            WHERE "visible_at" <= $1
+            ORDER BY "priority" = 'high' DESC, "created_at"
+        "#,
+            )
+            .await?;
+        let rows = conn.query(&statement, &[&now]).await?;
+        rows.iter().map(Self::broker_message_from_row).collect()
+    }
+
+    /// Returns number of unacked messages in each lane
+    pub async fn count_broker_messages(&self) -> Result<HashMap<Priority, u64>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "priority", count(*) FROM "broker_messages"
+            GROUP BY "priority"
+        "#,
+            )
+            .await?;
+        let mut counts = HashMap::new();
+        for row in conn.query(&statement, &[]).await? {
+            let priority: Priority = row.get::<_, String>(0).parse()?;
+            counts.insert(priority, row.get::<_, i64>(1) as u64);
+        }
+        Ok(counts)
+    }
+
+    /// Returns depth of the queues by topic and priority, `now` is unix timestamp
+    pub async fn get_broker_queue_stats(&self, now: i64) -> Result<Vec<BrokerQueueStats>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "topic", "priority",
+                count(*) FILTER (WHERE "visible_at" <= $1),
+                count(*) FILTER (WHERE "visible_at" > $1),
+                extract(epoch from now() - min("created_at"))::bigint
+            FROM "broker_messages"
+            GROUP BY "topic", "priority"
+            ORDER BY "topic", "priority"
+        "#,
+            )
+            .await?;
+        let rows = conn.query(&statement, &[&now]).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(BrokerQueueStats {
+                    topic: row.get(0),
+                    priority: row.get::<_, String>(1).parse()?,
+                    queued: row.get::<_, i64>(2) as u64,
+                    in_flight: row.get::<_, i64>(3) as u64,
+                    oldest_age: row.get(4),
+                })
+            })
+            .collect()
+    }
+
+    pub async fn get_broker_message(
+        &self,
+        message_id: &uuid::Uuid,
+    ) -> Result<Option<StoredBrokerMessage>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "payload", "attempts", "consumer", "visible_at",
+                extract(epoch from "created_at")::bigint
+            FROM "broker_messages"
+            WHERE "id" = $1
+        "#,
+            )
+            .await?;
+        let row = conn
+            .query_opt(&statement, &[&message_id.to_string()])
+            .await?;
+        match row {
+            Some(row) => Ok(Some(StoredBrokerMessage {
+                message: Self::broker_message_from_row(&row)?,
+                consumer: row.get(2),
+                visible_at: row.get(3),
+                created_at: row.get(4),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn broker_message_from_row(row: &bb8_postgres::tokio_postgres::Row) -> Result<BrokerMessage> {
+        let payload: Vec<u8> = row.get(0);
+        let mut message: BrokerMessage = serde_json::from_slice(&payload)?;
+        message.attempt = row.get::<_, i32>(1) as u32;
+        Ok(message)
+    }
+
+    /// Moves unacked message to dead letters, returns false when there is no such message
+    pub async fn dead_letter_broker_message(
+        &self,
+        message_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<bool> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            WITH "moved" AS (
+                DELETE FROM "broker_messages" WHERE "id" = $1
+                RETURNING "id", "payload", "attempts"
+            )
+            INSERT INTO "dead_letters" ("id", "payload", "attempts", "reason")
+            SELECT "id", "payload", "attempts", $2 FROM "moved"
+            ON CONFLICT ("id") DO NOTHING
+        "#,
+            )
+            .await?;
+        let moved = conn
+            .execute(&statement, &[&message_id.to_string(), &reason])
+            .await?;
+        Ok(moved > 0)
+    }
+
+    pub async fn get_dead_letters(&self) -> Result<Vec<DeadLetterMessage>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "payload", "attempts", "reason", "failed_at" FROM "dead_letters"
+            ORDER BY "failed_at"
+        "#,
+            )
+            .await?;
+        let rows = conn.query(&statement, &[]).await?;
+        rows.iter().map(Self::dead_letter_from_row).collect()
+    }
+
+    pub async fn get_dead_letter(
+        &self,
+        message_id: &uuid::Uuid,
+    ) -> Result<Option<DeadLetterMessage>> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            SELECT "payload", "attempts", "reason", "failed_at" FROM "dead_letters"
+            WHERE "id" = $1
+        "#,
+            )
+            .await?;
+        let row = conn
+            .query_opt(&statement, &[&message_id.to_string()])
+            .await?;
+        row.as_ref().map(Self::dead_letter_from_row).transpose()
+    }
+
+    fn dead_letter_from_row(row: &bb8_postgres::tokio_postgres::Row) -> Result<DeadLetterMessage> {
+        Ok(DeadLetterMessage {
+            message: Self::broker_message_from_row(row)?,
+            reason: row.get(2),
+            failed_at: row.get(3),
+        })
+    }
+
+    /// Removes the dead letter with given id or all of them, returns the number of removed
+    pub async fn purge_dead_letters(&self, message_id: Option<&uuid::Uuid>) -> Result<u64> {
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            DELETE FROM "dead_letters"
+            WHERE $1::text IS NULL OR "id" = $1
+        "#,
+            )
+            .await?;
+        let id = message_id.map(|id| id.to_string());
+        Ok(conn.execute(&statement, &[&id]).await?)
+    }
+
+    pub async fn add_dead_letter(&self, message: &BrokerMessage, reason: &str) -> Result<()> {
+        let payload = serde_json::to_vec(message)?;
+        let conn = self.pg.get().await?;
+        let statement = conn
+            .prepare(
+                r#"
+            INSERT INTO "dead_letters" ("id", "payload", "attempts", "reason")
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT ("id") DO NOTHING
+        "#,
+            )
+            .await?;
+        conn.execute(
+            &statement,
+            &[
+                &message.message_id.to_string(),
+                &payload,
+                &(message.attempt as i32),
+                &reason,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Not trimmed by length, that could drop unacked entries. Lanes bound how many of them
+    /// there are, acked ones are dropped by `stream_trim_acked`
+    pub async fn stream_add(&self, stream: &str, data: &[u8]) -> Result<String> {
+        let mut conn = self.redis.get().await?;
+        let id: String = conn.xadd(stream, "*", &[(STREAM_FIELD, data)]).await?;
+        Ok(id)
+    }
+
+    /// Drops entries which every group of the stream has read and acked
+    pub async fn stream_trim_acked(&self, stream: &str) -> Result<()> {
+        let mut conn = self.redis.get().await?;
+        let groups: StreamInfoGroupsReply = conn.xinfo_groups(stream).await?;
+        let mut min_id: Option<(u64, u64)> = None;
+        for group in groups.groups {
+            let pending: StreamPendingReply = conn.xpending(stream, &group.name).await?;
+            // The oldest unacked entry is kept, with none of them the ones not read yet
+            let keep_from = match pending {
+                StreamPendingReply::Data(pending) => parse_stream_id(&pending.start_id),
+                StreamPendingReply::Empty => parse_stream_id(&group.last_delivered_id)
+                    .map(|(ms, seq)| (ms, seq.saturating_add(1))),
+            };
+            let Some(keep_from) = keep_from else {
+                return Ok(());
+            };
+            min_id = Some(min_id.map_or(keep_from, |min_id| min_id.min(keep_from)));
+        }
+
+        if let Some((ms, seq)) = min_id {
+            let _: i64 = bb8_redis::redis::cmd("XTRIM")
+                .arg(stream)
+                .arg("MINID")
+                .arg(format!("{}-{}", ms, seq))
+                .query_async(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Whether delivery was paused with `broker admin pause` and not resumed since
+    pub async fn is_broker_paused(&self) -> Result<bool> {
+        let mut conn = self.redis.get().await?;
+        let paused: bool = conn.exists("BROKER_PAUSED").await?;
+        Ok(paused)
+    }
+
+    pub async fn set_broker_paused(&self, paused: bool) -> Result<()> {
+        let mut conn = self.redis.get().await?;
+        let _: () = match paused {
+            true => conn.set("BROKER_PAUSED", 1).await?,
+            false => conn.del("BROKER_PAUSED").await?,
+        };
+        Ok(())
+    }
+
+    /// Takes `count` places in the lane, none are taken if fewer than that are left of `capacity`
+    pub async fn lane_reserve(
+        &self,
+        priority: Priority,
+        count: u64,
+        capacity: usize,
+    ) -> Result<bool> {
+        let key = format!("BROKER_LANE:{}", priority.as_str());
+        let mut conn = self.redis.get().await?;
+        let held: i64 = conn.incr(&key, count).await?;
+        if held > capacity as i64 {
+            let _: () = conn.decr(&key, count).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Frees places of messages which left the lane: were acked or dead lettered
+    pub async fn lane_release(&self, priority: Priority, count: u64) -> Result<()> {
+        let key = format!("BROKER_LANE:{}", priority.as_str());
+        let mut conn = self.redis.get().await?;
+        let held: i64 = conn.decr(&key, count).await?;
+        // Messages acked by two consumers after a redelivery are released twice
+        if held < 0 {
+            let _: () = conn.set(&key, 0).await?;
+        }
+        Ok(())
+    }
+
+    /// Creates consumer group reading the stream from the beginning, does nothing if it exists
+    pub async fn stream_create_group(&self, stream: &str, group: &str) -> Result<()> {
+        let mut conn = self.redis.get().await?;
+        let created: bb8_redis::redis::RedisResult<()> =
+            conn.xgroup_create_mkstream(stream, group, "0").await;
+        match created {
+            Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+            created => Ok(created?),
+        }
+    }
+
+    /// Reads entries of the streams for `consumer`: new ones with `from` = ">", its own unacked
+    /// ones with "0". Waits until any of the streams has some, returns them stream by stream
+    /// in the given order along with the stream they are from
+    pub async fn stream_read_group(
+        &self,
+        streams: &[&str],
+        group: &str,
+        consumer: &str,
+        from: &str,
+        block: std::time::Duration,
+    ) -> Result<Vec<(String, StreamEntry)>> {
+        let options = StreamReadOptions::default()
+            .group(group, consumer)
+            .count(100)
+            .block(block.as_millis() as usize);
+        let froms = vec![from; streams.len()];
+        let mut conn = self.redis.get().await?;
+        let reply: Option<StreamReadReply> = conn.xread_options(streams, &froms, &options).await?;
+        let mut keys = reply.map(|reply| reply.keys).unwrap_or_default();
+        keys.sort_by_key(|key| streams.iter().position(|stream| *stream == key.key));
+        let entries = keys
+            .into_iter()
+            .flat_map(|key| {
+                let stream = key.key;
+                key.ids.into_iter().filter_map(move |entry| {
+                    entry.get::<Vec<u8>>(STREAM_FIELD).map(|data| {
+                        let entry = StreamEntry {
+                            id: entry.id.clone(),
+                            data,
+                            deliveries: 0,
+                        };
+                        (stream.clone(), entry)
+                    })
+                })
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    pub async fn stream_ack(&self, stream: &str, group: &str, id: &str) -> Result<()> {
+        let mut conn = self.redis.get().await?;
+        let _: i64 = conn.xack(stream, group, &[id]).await?;
+        Ok(())
+    }
+
+    /// Returns entries of the group which were not acked for `min_idle`, with their delivery counts
+    pub async fn stream_idle_entries(
+        &self,
+        stream: &str,
+        group: &str,
+        min_idle: std::time::Duration,
+    ) -> Result<Vec<StreamEntry>> {
+        let mut conn = self.redis.get().await?;
+        let pending: StreamPendingCountReply = bb8_redis::redis::cmd("XPENDING")
+            .arg(stream)
+            .arg(group)
+            .arg("IDLE")
+            .arg(min_idle.as_millis() as u64)
+            .arg("-")
+            .arg("+")
+            .arg(100)
+            .query_async(&mut *conn)
+            .await?;
+
+        let mut entries = Vec::with_capacity(pending.ids.len());
+        for pending in pending.ids {
+            let range: StreamRangeReply = conn.xrange(stream, &pending.id, &pending.id).await?;
+            let data = range
+                .ids
+                .first()
+                .and_then(|entry| entry.get::<Vec<u8>>(STREAM_FIELD));
+            if let Some(data) = data {
+                entries.push(StreamEntry {
+                    id: pending.id,
+                    data,
+                    deliveries: pending.times_delivered as u32,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Takes idle entry over to `consumer`, returns false if another consumer claimed it first
+    pub async fn stream_claim(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        min_idle: std::time::Duration,
+        id: &str,
+    ) -> Result<bool> {
+        let mut conn = self.redis.get().await?;
+        let reply: StreamClaimReply = conn
+            .xclaim(stream, group, consumer, min_idle.as_millis() as u64, &[id])
+            .await?;
+        Ok(!reply.ids.is_empty())
+    }
+
     pub async fn migrate_pg(&self, sql: &str) -> Result<()> {
         let conn = self.pg.get().await?;
         conn.simple_query(sql).await?;
@@ -384,3 +1499,16 @@ impl Storage {
         Ok(row.is_some())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_ids_compare_by_numbers() {
+        assert_eq!(parse_stream_id("1700000000000-3"), Some((1700000000000, 3)));
+        assert!(parse_stream_id("10-0") > parse_stream_id("9-5"));
+        assert!(parse_stream_id("9-10") > parse_stream_id("9-9"));
+        assert_eq!(parse_stream_id("nonsense"), None);
+    }
+}
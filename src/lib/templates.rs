@@ -0,0 +1,426 @@
+use serde::Serialize;
+use teloxide::utils::markdown::escape;
+
+use crate::types::TemplateError;
+
+/// Longest template accepted, leaves room for the values within Telegram message limit
+pub const MAX_TEMPLATE_LEN: usize = 1024;
+/// Telegram rejects longer messages
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Values a template can refer to as `{{ name }}` or check with `{% if name %}...{% endif %}`
+pub const PLACEHOLDERS: &[&str] = &[
+    "name", "sender", "address", "subject", "snippet", "folder", "account", "received",
+];
+
+/// Characters which are markup in MarkdownV2 and must be escaped elsewhere
+const RESERVED: &str = "_*[]()~`>#+-=|{}.!";
+
+/// Values of a mail put into the notification. They are raw, escaping is done while rendering
+#[derive(Debug, Clone, Default)]
+pub struct MailFields {
+    /// Display name of the sender, empty if the mail has none
+    pub name: String,
+    pub address: String,
+    pub subject: String,
+    /// Beginning of the text, empty if the source does not provide it
+    pub snippet: String,
+    pub folder: String,
+    /// Address of the mail account the mail came to
+    pub account: String,
+    /// Time the mail was found, in the user time zone
+    pub received: String,
+}
+
+impl MailFields {
+    fn get(&self, placeholder: &str) -> &str {
+        match placeholder {
+            "name" => &self.name,
+            "sender" if self.name.is_empty() => &self.address,
+            "sender" => &self.name,
+            "address" => &self.address,
+            "subject" => &self.subject,
+            "snippet" => &self.snippet,
+            "folder" => &self.folder,
+            "account" => &self.account,
+            "received" => &self.received,
+            _ => "",
+        }
+    }
+
+    fn values_mut(&mut self) -> [&mut String; 7] {
+        [
+            &mut self.name,
+            &mut self.address,
+            &mut self.subject,
+            &mut self.snippet,
+            &mut self.folder,
+            &mut self.account,
+            &mut self.received,
+        ]
+    }
+
+    /// Made up mail the templates are previewed and checked with
+    pub fn sample() -> MailFields {
+        MailFields {
+            name: "Jane Doe".into(),
+            address: "jane.doe@example.com".into(),
+            subject: "Quarterly report (draft #2)".into(),
+            snippet: "Hi! The numbers are attached, please take a look before Friday.".into(),
+            folder: "INBOX".into(),
+            account: "you@example.com".into(),
+            received: "01.04.2024 09:30".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Preset {
+    pub name: &'static str,
+    pub template: &'static str,
+}
+
+/// Built-in templates, the first one is used unless the user picks another
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "default",
+        template: "*{{ sender }}*\n{% if name %}{{ address }}\n{% endif %}{{ subject }}",
+    },
+    Preset {
+        name: "compact",
+        template: "*{{ sender }}*: {{ subject }}",
+    },
+    Preset {
+        name: "detailed",
+        template: "*{{ sender }}*\n{% if name %}{{ address }}\n{% endif %}{{ subject }}\
+                   {% if snippet %}\n\n_{{ snippet }}_{% endif %}\n\n\
+                   {{ account }} \\| {{ folder }} \\| {{ received }}",
+    },
+];
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Field(String),
+    If(String, Vec<Part>),
+}
+
+/// Notification text with placeholders. Text around them is MarkdownV2 written by the user
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+fn check_placeholder(name: &str) -> Result<(), TemplateError> {
+    match PLACEHOLDERS.contains(&name) {
+        true => Ok(()),
+        false => Err(TemplateError::UnknownPlaceholder(name.to_string())),
+    }
+}
+
+impl Template {
+    /// Parses the template and checks that it renders to valid MarkdownV2,
+    /// both with all values present and with the optional ones missing
+    pub fn compile(source: &str) -> Result<Template, TemplateError> {
+        if source.chars().count() > MAX_TEMPLATE_LEN {
+            return Err(TemplateError::TooLong(MAX_TEMPLATE_LEN));
+        }
+        let template = Template::parse(source)?;
+
+        let full = MailFields::sample();
+        let minimal = MailFields {
+            name: String::new(),
+            snippet: String::new(),
+            ..MailFields::sample()
+        };
+        for fields in [full, minimal] {
+            let text = template.render(&fields);
+            if text.trim().is_empty() {
+                return Err(TemplateError::Empty);
+            }
+            if text.chars().count() > MAX_MESSAGE_LEN {
+                return Err(TemplateError::MessageTooLong(MAX_MESSAGE_LEN));
+            }
+            validate_markdown(&text)?;
+        }
+        Ok(template)
+    }
+
+    pub fn default_preset() -> Template {
+        Template::parse(PRESETS[0].template).expect("Default preset is valid")
+    }
+
+    fn parse(source: &str) -> Result<Template, TemplateError> {
+        // Open `if` blocks: placeholder, position and the parts before the block
+        let mut blocks: Vec<(String, usize, Vec<Part>)> = vec![];
+        let mut parts = vec![];
+        let mut rest = source;
+        let mut offset = 0;
+
+        loop {
+            let start = match [rest.find("{{"), rest.find("{%")]
+                .into_iter()
+                .flatten()
+                .min()
+            {
+                Some(start) => start,
+                None => {
+                    if !rest.is_empty() {
+                        parts.push(Part::Text(rest.to_string()));
+                    }
+                    break;
+                }
+            };
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+
+            let position = offset + start;
+            let (opening, closing) = match rest[start..].starts_with("{{") {
+                true => ("{{", "}}"),
+                false => ("{%", "%}"),
+            };
+            let end = rest[start + 2..]
+                .find(closing)
+                .ok_or(TemplateError::Unclosed(opening.to_string(), position))?;
+            let inner = rest[start + 2..start + 2 + end].trim();
+
+            if opening == "{{" {
+                check_placeholder(inner)?;
+                parts.push(Part::Field(inner.to_string()));
+            } else {
+                match inner.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    ["if", name] => {
+                        check_placeholder(name)?;
+                        blocks.push((name.to_string(), position, std::mem::take(&mut parts)));
+                    }
+                    ["endif"] => {
+                        let (name, _, outer) = blocks
+                            .pop()
+                            .ok_or(TemplateError::Unexpected("endif".into(), position))?;
+                        let body = std::mem::replace(&mut parts, outer);
+                        parts.push(Part::If(name, body));
+                    }
+                    _ => return Err(TemplateError::Unexpected(inner.to_string(), position)),
+                }
+            }
+
+            let consumed = start + 2 + end + 2;
+            rest = &rest[consumed..];
+            offset += consumed;
+        }
+
+        if let Some((_, position, _)) = blocks.pop() {
+            return Err(TemplateError::Unclosed("{% if %}".into(), position));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn render(&self, fields: &MailFields) -> String {
+        let mut text = String::new();
+        render_parts(&self.parts, fields, &mut text);
+        text
+    }
+
+    /// Renders the notification within Telegram message limit, the longest values are cut
+    /// until it fits. Markup of the template is left intact, so the text stays valid
+    pub fn render_message(&self, fields: &MailFields) -> String {
+        let mut fields = fields.clone();
+        loop {
+            let text = self.render(&fields);
+            if text.chars().count() <= MAX_MESSAGE_LEN {
+                return text;
+            }
+            let longest = fields
+                .values_mut()
+                .into_iter()
+                .max_by_key(|value| value.chars().count())
+                .filter(|value| !value.is_empty());
+            match longest {
+                Some(value) => {
+                    *value = match value.chars().count() / 2 {
+                        0 => String::new(),
+                        keep => truncate(value, keep),
+                    }
+                }
+                // Compiled templates fit without the values
+                None => return text,
+            }
+        }
+    }
+}
+
+fn render_parts(parts: &[Part], fields: &MailFields, text: &mut String) {
+    for part in parts {
+        match part {
+            Part::Text(literal) => text.push_str(literal),
+            Part::Field(name) => text.push_str(&escape(fields.get(name))),
+            Part::If(name, body) => {
+                if !fields.get(name).is_empty() {
+                    render_parts(body, fields, text);
+                }
+            }
+        }
+    }
+}
+
+fn starts_with(chars: &[char], at: usize, marker: &str) -> bool {
+    chars.len() - at >= marker.len()
+        && chars[at..at + marker.len()]
+            .iter()
+            .copied()
+            .eq(marker.chars())
+}
+
+/// Checks the text the way Telegram parses MarkdownV2: reserved characters are escaped
+/// and every entity is closed in the order it was opened
+pub fn validate_markdown(text: &str) -> Result<(), TemplateError> {
+    let invalid = |message: String| TemplateError::InvalidMarkdown(message);
+    let chars: Vec<char> = text.chars().collect();
+    let mut open: Vec<(&'static str, usize)> = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\\' {
+            if i + 1 >= chars.len() {
+                return Err(invalid(format!("`\\` at {} escapes nothing", i)));
+            }
+            i += 2;
+            continue;
+        }
+
+        // Nothing but the closing marker means anything in code
+        let code = match open.last() {
+            Some((marker, _)) if *marker == "`" || *marker == "```" => Some(*marker),
+            _ => None,
+        };
+        if let Some(marker) = code {
+            if starts_with(&chars, i, marker) {
+                i += marker.len();
+                open.pop();
+            } else {
+                i += 1;
+            }
+            continue;
+        }
+
+        let marker = if starts_with(&chars, i, "```") {
+            "```"
+        } else if starts_with(&chars, i, "__") {
+            "__"
+        } else if starts_with(&chars, i, "||") {
+            "||"
+        } else {
+            match c {
+                '`' => "`",
+                '*' => "*",
+                '_' => "_",
+                '~' => "~",
+                _ => "",
+            }
+        };
+        if !marker.is_empty() {
+            match open.iter().rposition(|(opened, _)| *opened == marker) {
+                Some(index) if index + 1 == open.len() => {
+                    open.pop();
+                }
+                Some(_) => {
+                    return Err(invalid(format!(
+                        "`{}` at {} closes over another entity",
+                        marker, i
+                    )))
+                }
+                None => open.push((marker, i)),
+            }
+            i += marker.len();
+            continue;
+        }
+
+        match c {
+            '[' => open.push(("[", i)),
+            ']' => {
+                if !matches!(open.last(), Some(("[", _))) {
+                    return Err(invalid(format!("`]` at {} must be escaped with `\\`", i)));
+                }
+                open.pop();
+                if chars.get(i + 1) != Some(&'(') {
+                    return Err(invalid(format!("link at {} has no (url)", i)));
+                }
+                // Only `)` and `\` are escaped in the url
+                let mut j = i + 2;
+                loop {
+                    match chars.get(j) {
+                        None => {
+                            return Err(invalid(format!("link url at {} is not closed", i + 1)))
+                        }
+                        Some('\\') => j += 2,
+                        Some(')') => break,
+                        Some(_) => j += 1,
+                    }
+                }
+                i = j;
+            }
+            // Block quotation
+            '>' if i == 0 || chars[i - 1] == '\n' => {}
+            c if RESERVED.contains(c) => {
+                return Err(invalid(format!(
+                    "`{}` at {} must be escaped with `\\`",
+                    c, i
+                )));
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    match open.pop() {
+        Some((marker, position)) => Err(invalid(format!(
+            "`{}` at {} is not closed",
+            marker, position
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Cuts the text to at most `max` characters, marking the cut with an ellipsis
+pub fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(max.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_values_are_cut_to_fit() {
+        let template = Template::compile(PRESETS[2].template).unwrap();
+        let fields = MailFields {
+            subject: "Re: ".repeat(2000),
+            snippet: "(!)".repeat(3000),
+            ..MailFields::sample()
+        };
+        let text = template.render_message(&fields);
+        assert!(text.chars().count() <= MAX_MESSAGE_LEN);
+        validate_markdown(&text).unwrap();
+        assert!(text.starts_with("*Jane Doe*\njane\\.doe@example\\.com\nRe: Re: "));
+        assert!(text.ends_with("you@example\\.com \\| INBOX \\| 01\\.04\\.2024 09:30"));
+
+        let short = MailFields::sample();
+        assert_eq!(template.render_message(&short), template.render(&short));
+    }
+
+    #[test]
+    fn rendered_length_is_checked() {
+        let template = "{{ snippet }}".repeat(MAX_TEMPLATE_LEN / 13);
+        assert!(matches!(
+            Template::compile(&template),
+            Err(TemplateError::MessageTooLong(MAX_MESSAGE_LEN))
+        ));
+    }
+}
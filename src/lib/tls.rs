@@ -0,0 +1,230 @@
+use rustls_connector::{RustlsConnector, RustlsConnectorConfig, TlsStream};
+use sha2::{Digest, Sha256};
+use std::io::{BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::cfg::{ServerTlsCfg, TlsMode};
+use crate::storage::MailProtocol;
+use crate::types::*;
+
+/// Mail servers not answering in time must not hold up checks of other users
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn build_connector(cfg: &ServerTlsCfg) -> Result<RustlsConnector> {
+    let mut connector_cfg = RustlsConnectorConfig::new_with_native_certs()?;
+    if let Some(ca_file) = &cfg.ca_file {
+        let ca_error = |e: std::io::Error| {
+            Error::NetworkError(NetworkError::CaBundleError(ca_file.display().to_string(), e))
+        };
+        let file = std::fs::File::open(ca_file).map_err(ca_error)?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(ca_error)?;
+        let (added, ignored) = connector_cfg.add_parsable_certificates(certs);
+        tracing::debug!(
+            "Loaded {} CA certificates from {}, {} ignored",
+            added,
+            ca_file.display(),
+            ignored
+        );
+    }
+    Ok(connector_cfg.connector_with_no_client_auth())
+}
+
+fn read_line(stream: &mut TcpStream) -> Result<String> {
+    // Read byte by byte: nothing past the line may be consumed before the TLS handshake
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\n") {
+        if stream.read(&mut byte)? == 0 {
+            let error = "connection closed by server".to_owned();
+            return Err(Error::NetworkError(NetworkError::StartTlsError(error)));
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_owned())
+}
+
+/// Upgrades plain connection with STARTTLS (IMAP) or STLS (POP3), consuming the server greeting
+pub fn negotiate_starttls(protocol: MailProtocol, stream: &mut TcpStream) -> Result<()> {
+    let starttls_error = |line: String| Error::NetworkError(NetworkError::StartTlsError(line));
+
+    let _greeting = read_line(stream)?;
+    match protocol {
+        MailProtocol::Imap => {
+            stream.write_all(b"a0 STARTTLS\r\n")?;
+            loop {
+                let line = read_line(stream)?;
+                if line.starts_with("a0 OK") {
+                    return Ok(());
+                }
+                if line.starts_with("a0 ") {
+                    return Err(starttls_error(line));
+                }
+            }
+        }
+        MailProtocol::Pop3 => {
+            stream.write_all(b"STLS\r\n")?;
+            let line = read_line(stream)?;
+            match line.starts_with("+OK") {
+                true => Ok(()),
+                false => Err(starttls_error(line)),
+            }
+        }
+        MailProtocol::Jmap => Err(starttls_error("not supported for JMAP".into())),
+    }
+}
+
+fn verify_pin(host: &str, stream: &TlsStream<TcpStream>, expected: &str) -> Result<()> {
+    let actual = stream
+        .conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| hex::encode(Sha256::digest(cert.as_ref())))
+        .unwrap_or_default();
+    if actual != expected {
+        return Err(Error::NetworkError(NetworkError::CertificatePinMismatch {
+            host: host.to_owned(),
+            expected: expected.to_owned(),
+            actual,
+        }));
+    }
+    Ok(())
+}
+
+/// Performs TLS handshake over already connected (and, for STARTTLS, upgraded) stream
+pub fn handshake(host: &str, stream: TcpStream, cfg: &ServerTlsCfg) -> Result<TlsStream<TcpStream>> {
+    let connector = build_connector(cfg)?;
+    let tls_stream = connector.connect(host, stream).map_err(|error| {
+        Error::NetworkError(NetworkError::HandshakeError {
+            host: host.to_owned(),
+            error,
+        })
+    })?;
+    if let Some(pin) = &cfg.pin_sha256 {
+        verify_pin(host, &tls_stream, pin)?;
+    }
+    Ok(tls_stream)
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is the shared address space of carrier-grade NAT
+            let shared = a == 100 && (b & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || shared
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                // fc00::/7 unique local and fe80::/10 link-local addresses
+                let unique_local = (first & 0xfe00) == 0xfc00;
+                let link_local = (first & 0xffc0) == 0xfe80;
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || unique_local
+                    || link_local)
+            }
+        },
+    }
+}
+
+/// Resolves the host to the address to connect to. Unless `allow_private`, loopback, private
+/// and otherwise internal addresses are skipped, so servers given by users can not reach them.
+/// Connections go to the returned address, the host is not resolved again to rebind it
+pub fn resolve(host: &str, port: u16, allow_private: bool) -> Result<SocketAddr> {
+    let addresses: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        let error = NetworkError::NoAddresses(host.to_owned());
+        return Err(Error::NetworkError(error));
+    }
+    addresses
+        .into_iter()
+        .find(|address| allow_private || is_public(address.ip()))
+        .ok_or(Error::NetworkError(NetworkError::PrivateAddress(
+            host.to_owned(),
+        )))
+}
+
+/// Connects to the mail server, `allow_private` only for servers from the config
+pub fn connect(
+    host: &str,
+    port: u16,
+    protocol: MailProtocol,
+    cfg: &ServerTlsCfg,
+    allow_private: bool,
+) -> Result<TlsStream<TcpStream>> {
+    let address = resolve(host, port, allow_private)?;
+    let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    if cfg.mode == TlsMode::StartTls {
+        negotiate_starttls(protocol, &mut stream)?;
+    }
+    handshake(host, stream, cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} is internal", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[test]
+    fn user_hosts_may_not_resolve_to_internal_addresses() {
+        let error = resolve("127.0.0.1", 993, false).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::NetworkError(NetworkError::PrivateAddress(_))
+        ));
+        assert!(resolve("127.0.0.1", 993, true).is_ok());
+    }
+
+    #[test]
+    fn checker_may_not_connect_to_internal_addresses() {
+        let cfg = ServerTlsCfg {
+            mode: TlsMode::Implicit,
+            ca_file: None,
+            pin_sha256: None,
+        };
+        let error = connect("127.0.0.1", 993, MailProtocol::Imap, &cfg, false).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::NetworkError(NetworkError::PrivateAddress(_))
+        ));
+    }
+}
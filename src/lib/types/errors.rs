@@ -19,16 +19,41 @@ pub enum AuthError {
 
 #[derive(Error, Debug)]
 pub enum NetworkError {
-    #[error("Handshake error: {0}")]
-    HandshakeError(rustls_connector::HandshakeError<std::net::TcpStream>),
+    #[error("TLS handshake with {host} failed: {error}")]
+    HandshakeError {
+        host: String,
+        error: rustls_connector::HandshakeError<std::net::TcpStream>,
+    },
     #[error("Reqwest error: {0}")]
     ReqwestError(reqwest::Error),
+    #[error("{0} has no addresses")]
+    NoAddresses(String),
+    #[error("{0} resolves only to internal addresses")]
+    PrivateAddress(String),
+    #[error("STARTTLS negotiation failed: {0}")]
+    StartTlsError(String),
+    #[error("Could not load CA bundle {0}: {1}")]
+    CaBundleError(String, std::io::Error),
+    #[error("Certificate of {host} does not match pinned SHA-256 {expected}, got {actual}")]
+    CertificatePinMismatch {
+        host: String,
+        expected: String,
+        actual: String,
+    },
 }
 
 #[derive(Error, Debug)]
 pub enum MailCheckerError {
     #[error("Empty envelope")]
     EmptyEnvelope,
+    #[error("Could not connect to mail server: {0}")]
+    ConnectionFailed(String),
+    #[error("Could not login into {0}")]
+    LoginFailed(String),
+    #[error("POP3 server responded with error: {0}")]
+    Pop3Error(String),
+    #[error("JMAP server responded with error: {0}")]
+    JmapError(String),
 }
 
 #[derive(Error, Debug)]
@@ -43,6 +68,38 @@ pub enum TelegramBotError {
     RequestError(teloxide::RequestError),
 }
 
+#[derive(Error, Debug)]
+pub enum WireError {
+    #[error("Unsupported wire format version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Malformed message: {0}")]
+    Malformed(String),
+}
+
+#[derive(Error, Debug)]
+pub enum BrokerError {
+    #[error("Broker is busy: {0}")]
+    Busy(String),
+}
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("Unknown placeholder `{0}`")]
+    UnknownPlaceholder(String),
+    #[error("`{0}` at {1} is not closed")]
+    Unclosed(String, usize),
+    #[error("Unexpected `{0}` at {1}")]
+    Unexpected(String, usize),
+    #[error("Rendered text is not valid MarkdownV2: {0}")]
+    InvalidMarkdown(String),
+    #[error("Template is longer than {0} characters")]
+    TooLong(usize),
+    #[error("Rendered text is longer than {0} characters")]
+    MessageTooLong(usize),
+    #[error("Template renders to empty text")]
+    Empty,
+}
+
 #[derive(Error, Debug)]
 pub enum InternalError {
     #[error("RWLockPoisoned error: {0}")]
@@ -69,6 +126,8 @@ pub enum Error {
     NetworkError(NetworkError),
     #[error("MailChecker error: {0}")]
     MailCheckerError(MailCheckerError),
+    #[error("Template error: {0}")]
+    TemplateError(TemplateError),
     #[error("Internal error: {0}")]
     InternalError(InternalError),
     #[error("Internal error: {0}")]
@@ -118,15 +177,15 @@ impl std::convert::From<teloxide::RequestError> for Error {
     }
 }
 
-impl std::convert::From<std::io::Error> for Error {
-    fn from(io_error: std::io::Error) -> Self {
-        Error::IoError(io_error)
+impl std::convert::From<TemplateError> for Error {
+    fn from(template_error: TemplateError) -> Self {
+        Error::TemplateError(template_error)
     }
 }
 
-impl std::convert::From<rustls_connector::HandshakeError<std::net::TcpStream>> for Error {
-    fn from(hs_error: rustls_connector::HandshakeError<std::net::TcpStream>) -> Self {
-        Error::NetworkError(NetworkError::HandshakeError(hs_error))
+impl std::convert::From<std::io::Error> for Error {
+    fn from(io_error: std::io::Error) -> Self {
+        Error::IoError(io_error)
     }
 }
 
@@ -16,7 +16,7 @@ impl ImportanceChecker {
         }
     }
 
-    pub fn check(&self, email: &String, subject: &String) -> bool {
+    pub fn check(&self, email: &String, subject: &str) -> bool {
         let contain_important_email = self.important_emails.contains(email);
         let contain_important_tag = self.tags.iter().any(|tag| subject.contains(tag));
         contain_important_email || contain_important_tag
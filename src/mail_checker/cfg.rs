@@ -6,6 +6,8 @@ pub struct MailCheckerCfg {
     pub storage: StorageCfg,
     pub mail: MailCfg,
     pub broker: BrokerCfg,
+    /// Public address of the web app used in notifications about mailbox problems
+    pub web_app_url: Option<String>,
 }
 
 impl TryFrom<Config> for MailCheckerCfg {
@@ -15,10 +17,12 @@ impl TryFrom<Config> for MailCheckerCfg {
         let storage = StorageCfg::try_from(&cfg)?;
         let mail = MailCfg::try_from(&cfg)?;
         let broker = BrokerCfg::try_from(&cfg)?;
+        let web_app_url = cfg.get_string("web.public_url").ok();
         Ok(MailCheckerCfg {
             storage,
             mail,
             broker,
+            web_app_url,
         })
     }
 }
@@ -1,123 +1,134 @@
 use anyhow::{anyhow, Context};
 use chrono::Timelike;
-use common::cfg::BrokerCfg;
+use common::cfg::MailCfg;
 use common::sessions::WebAppUser;
-use imap;
-use rustls_connector::{RustlsConnector, TlsStream};
-use rustyknife::rfc2047::encoded_word;
-use std::iter::FromIterator;
-use std::net::TcpStream;
+use std::fmt::Display;
 use std::sync::Arc;
-use teloxide::utils::markdown::escape;
+use teloxide::utils::markdown::{escape, link};
 use teloxide_core::types::UserId;
 
-use common::queues::{BrokerClient, Tasks, TelegramMessageTask};
-use common::storage::{Cipher, MailAccount, Storage};
-use common::types::{Error, ImportanceChecker, MailCheckerError, Result};
+use common::i18n::{self, Language};
+use common::queues::{BrokerClient, NotificationUpdateTask, Tasks, TelegramMessageTask};
+use common::storage::{
+    ChatRoutes, Cipher, DeferredMail, DeliverySettings, MailAccount, MailProtocol, MailRef,
+    MailboxHealth, MailboxProblem, Storage,
+};
+use common::templates::{MailFields, Template};
+use common::types::{BrokerError, Error, ImportanceChecker, MailCheckerError};
 
 use crate::cfg::MailCheckerCfg;
+use crate::push::PushWatchers;
+use crate::sources::{self, IncomingMail, MailSource};
+
+/// Consecutive failed checks after which the user is told about the problem
+const NOTIFY_AFTER_FAILURES: u32 = 3;
+/// How long sent notifications are updated when their mail is read elsewhere.
+/// Telegram does not let bots delete messages older than 48 hours anyway
+const NOTIFIED_TRACK_SECS: i64 = 2 * 24 * 3600;
+
+/// Settings of the user notifications are built with, read once per check
+struct NotifySettings {
+    template: Template,
+    lang: Language,
+    routes: ChatRoutes,
+    delivery: DeliverySettings,
+    importance_checker: ImportanceChecker,
+}
 
 pub struct Checker {
-    host: String,
-    port: u16,
+    mail_cfg: MailCfg,
+    web_app_url: Option<String>,
     storage: Arc<Storage>,
     cipher: Cipher,
-    broker_cfg: BrokerCfg,
+    broker: BrokerClient,
+    push: Arc<PushWatchers>,
 }
 
 impl Checker {
-    pub async fn new(cfg: &MailCheckerCfg) -> anyhow::Result<Checker> {
-        let host = cfg.mail.address.clone();
-        let port = cfg.mail.port;
+    pub async fn new(
+        cfg: &MailCheckerCfg,
+        broker: BrokerClient,
+        push: Arc<PushWatchers>,
+    ) -> anyhow::Result<Checker> {
         let storage = Storage::new(&cfg.storage)
             .await
-            .with_context(|| "Could not connect to storage")?
-            .into();
+            .with_context(|| "Could not connect to storage")?;
         let cipher = Cipher::new(&cfg.storage);
         Ok(Checker {
-            host,
-            port,
-            storage,
+            mail_cfg: cfg.mail.clone(),
+            web_app_url: cfg.web_app_url.clone(),
+            storage: storage.into(),
             cipher,
-            broker_cfg: cfg.broker.clone(),
+            broker,
+            push,
         })
     }
 
-    fn build_stream(&self) -> Result<TlsStream<TcpStream>> {
-        let connector = RustlsConnector::new_with_native_certs()?;
-        let stream = TcpStream::connect((self.host.clone(), self.port))?;
-        let tls_stream = connector.connect(&self.host, stream)?;
-        Ok(tls_stream)
-    }
-
-    fn build_client(&self) -> Result<imap::Client<TlsStream<TcpStream>>> {
-        let stream = self.build_stream()?;
-        Ok(imap::Client::new(stream))
+    /// Template of the user notifications, the default preset if there is none or it is not valid anymore
+    async fn notification_template(&self, user: &WebAppUser) -> anyhow::Result<Template> {
+        let template = match self.storage.get_notification_template(user).await? {
+            Some(source) => Template::compile(&source).unwrap_or_else(|e| {
+                tracing::warn!("Template of user {} is not valid: {}", user.id, e);
+                Template::default_preset()
+            }),
+            None => Template::default_preset(),
+        };
+        Ok(template)
     }
 
-    fn decode_value(data: Option<&[u8]>) -> Option<String> {
-        if let Some(data) = data {
-            let value = String::from_utf8_lossy(data).into_owned();
-            let data_owner = value.clone();
-            let data = data_owner.as_bytes();
-            let (_, value) = encoded_word(data).unwrap_or((&[], value));
-            return Some(value);
-        }
-        None
+    async fn notify_settings(&self, user: &WebAppUser) -> anyhow::Result<NotifySettings> {
+        let importance_checker = ImportanceChecker::new(&self.storage, user).await;
+        tracing::debug!(
+            "ImportanceChecker for user {} was built: {:?}",
+            user.id,
+            importance_checker
+        );
+        Ok(NotifySettings {
+            template: self.notification_template(user).await?,
+            lang: self.storage.get_user_language(user).await?,
+            routes: self.storage.get_chat_routes(user).await?,
+            delivery: self.storage.get_delivery_settings(user).await?,
+            importance_checker,
+        })
     }
 
-    async fn process_message(
+    async fn build_task(
         &self,
-        message: &imap::types::Fetch,
+        message: &IncomingMail,
         user: &WebAppUser,
-        importance_checker: &ImportanceChecker,
-    ) -> anyhow::Result<()> {
-        let envelope = message.envelope();
-        if let None = envelope {
-            let error = Error::MailCheckerError(MailCheckerError::EmptyEnvelope);
-            return Err(anyhow!(error));
-        }
-        let envelope = envelope.unwrap();
-
-        let mut from_addr: Option<&[u8]> = None;
-        let mut host: Option<&[u8]> = None;
-        let mut mailbox: Option<&[u8]> = None;
-
-        if let Some(addresses) = &envelope.from.as_ref() {
-            if addresses.len() > 0 {
-                from_addr = addresses[0].name;
-                host = addresses[0].host;
-                mailbox = addresses[0].mailbox;
-            }
-        }
-
-        let from = Checker::decode_value(from_addr);
-        let subject = Checker::decode_value(envelope.subject);
-
-        let email = format!(
-            "{}@{}",
-            String::from_utf8_lossy(mailbox.unwrap_or("nobody".as_bytes())),
-            String::from_utf8_lossy(host.unwrap_or("nowhere".as_bytes()))
-        );
-
-        let subject = subject.unwrap_or("No subject".into());
-        let text = if let Some(from) = from {
-            format!(
-                "*{}*\n{}\n{}",
-                escape(from.as_str()),
-                escape(email.as_str()),
-                escape(subject.as_str())
-            )
-        } else {
-            format!("*{}*\n{}", escape(email.as_str()), escape(subject.as_str()))
-        };
-
-        let work_hours = self.storage.get_user_working_hours(&user).await?;
-
-        let moscow_offset = chrono::FixedOffset::east_opt(3 * 3600)
-            .ok_or(anyhow!("Could not create Moscow offset"))?;
-
-        let now = chrono::Utc::now().with_timezone(&moscow_offset);
+        account: &MailAccount,
+        settings: &NotifySettings,
+    ) -> anyhow::Result<TelegramMessageTask> {
+        let IncomingMail {
+            folder,
+            from,
+            email,
+            subject,
+            snippet,
+            date,
+            ..
+        } = message;
+
+        let subject = subject
+            .clone()
+            .unwrap_or(i18n::tr(settings.lang, "mail.no_subject").into());
+
+        let work_hours = self.storage.get_user_working_hours(user).await?;
+
+        let user_offset = self.storage.get_user_utc_offset(user).await?;
+
+        let now = chrono::Utc::now().with_timezone(&user_offset);
+        let received = date.map_or(now, |date| date.with_timezone(&user_offset));
+
+        let text = settings.template.render_message(&MailFields {
+            name: from.clone().unwrap_or_default(),
+            address: email.clone(),
+            subject: subject.clone(),
+            snippet: snippet.clone().unwrap_or_default(),
+            folder: folder.clone(),
+            account: account.email.clone(),
+            received: received.format("%d.%m.%Y %H:%M").to_string(),
+        });
 
         let from = now
             .with_hour(work_hours[0] as u32)
@@ -136,8 +147,9 @@ impl Checker {
 
         let mut send_after = chrono::Utc::now();
         let utc_offset = chrono::Utc {};
+        let off_hours = !(from <= now && now <= to);
 
-        if from <= now && now <= to {
+        if !off_hours {
             send_after = now.with_timezone(&utc_offset)
         } else if to < now {
             send_after = from
@@ -148,124 +160,466 @@ impl Checker {
             send_after = from.with_timezone(&utc_offset)
         }
 
+        let important = settings.importance_checker.check(email, &subject);
+        let delivery = &settings.delivery;
+        // Silent delivery makes waiting for working hours unnecessary
+        let deliver_now = !important && off_hours && delivery.deliver_off_hours;
+        if deliver_now {
+            send_after = chrono::Utc::now();
+        }
+        if !important {
+            // Routine mail arriving while the user asked for quiet waits until it is over
+            if let Some(until) = self.storage.get_quiet_until(user).await? {
+                if let Some(until) = chrono::DateTime::from_timestamp(until, 0) {
+                    send_after = send_after.max(until);
+                }
+            }
+        }
+
         tracing::warn!(
             "Now: {}, Calculated send_after: {}",
             now,
-            send_after.with_timezone(&moscow_offset)
+            send_after.with_timezone(&user_offset)
         );
 
+        let delivered_at = send_after
+            .max(chrono::Utc::now())
+            .with_timezone(&user_offset);
+        let silent = !important
+            && (delivery.silent_routine
+                || deliver_now
+                || delivery.is_soft_hour(delivered_at.hour()));
+
         let task = TelegramMessageTask {
             to: UserId(user.id as u64),
             text,
             send_after,
-            important: importance_checker.check(&email, &subject),
+            important,
+            chat: settings.routes.route(important),
+            silent,
+            mail: message.reference.clone(),
+            recheck: false,
         };
 
-        let payload = common::queues::BrokerRequestPayload::Tasks(Tasks::TelegramMessageTask(task));
-        let broker = BrokerClient::new(self.broker_cfg.clone())?;
-        if let Err(e) = broker.send(payload).await {
-            return Err(anyhow!(e));
+        Ok(task)
+    }
+
+    /// Looks up mails read or removed in the mailbox since they were notified about or queued.
+    /// Deferred notifications of such mails are cancelled, sent ones are asked to be updated by the bot.
+    /// Notifications older than the tracking window are forgotten, due deferred ones are left to `recheck_due`
+    async fn track_changes(
+        &self,
+        user: &WebAppUser,
+        account: &MailAccount,
+        source: &mut Box<dyn MailSource>,
+    ) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let notified = self.storage.get_notified_mails(user).await?;
+        let (notified, expired): (Vec<_>, Vec<_>) = notified
+            .into_iter()
+            .partition(|notified| notified.sent_at > now - NOTIFIED_TRACK_SECS);
+        let expired: Vec<&MailRef> = expired.iter().map(|notified| &notified.mail).collect();
+        self.storage.remove_notified_mails(user, &expired).await?;
+
+        let deferred: Vec<DeferredMail> = self
+            .storage
+            .get_deferred_mails(user)
+            .await?
+            .into_iter()
+            .filter(|deferred| deferred.send_after > now)
+            .collect();
+
+        // Mails of the account the user switched from can not be looked up anymore, they expire
+        let mails: Vec<&MailRef> = notified
+            .iter()
+            .map(|notified| &notified.mail)
+            .chain(deferred.iter().map(|deferred| &deferred.mail))
+            .filter(|mail| mail.account == account.email)
+            .collect();
+        if mails.is_empty() {
+            return Ok(());
+        }
+        let changes = source.changed(&self.storage, user, &mails).await?;
+
+        let cancelled: Vec<&MailRef> = changes
+            .iter()
+            .map(|(mail, _)| mail)
+            .filter(|mail| deferred.iter().any(|deferred| deferred.mail == **mail))
+            .collect();
+        if !cancelled.is_empty() {
+            tracing::info!(
+                "Cancelling {} deferred notifications of user {}, the mail was read or removed",
+                cancelled.len(),
+                user.id
+            );
+            self.storage.cancel_mails(user, &cancelled).await?;
+            self.storage.remove_deferred_mails(user, &cancelled).await?;
         }
+
+        let mut tasks = Vec::with_capacity(changes.len());
+        for (mail, change) in changes.iter() {
+            if let Some(notified) = notified.iter().find(|notified| notified.mail == *mail) {
+                tasks.push(Tasks::NotificationUpdateTask(NotificationUpdateTask {
+                    to: UserId(user.id as u64),
+                    notified: notified.clone(),
+                    change: *change,
+                }));
+            }
+        }
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        self.broker.publish_batch(tasks).await?;
+        let changed: Vec<&MailRef> = changes.iter().map(|(mail, _)| mail).collect();
+        self.storage.remove_notified_mails(user, &changed).await?;
         Ok(())
     }
 
-    async fn process_account(
+    /// Looks up mails whose deferred notifications are due, the bot holds them until then.
+    /// Notifications of mails read or removed meanwhile are cancelled, the others are confirmed.
+    /// Mails of the account the user switched from can not be looked up, they are confirmed as they are
+    async fn recheck_due(
         &self,
         user: &WebAppUser,
         account: &MailAccount,
+        source: &mut Box<dyn MailSource>,
     ) -> anyhow::Result<()> {
-        let MailAccount { email, password } = account;
-        let client = match self.build_client() {
-            Ok(client) => client,
-            Err(e) => {
-                return Err(anyhow!("Could not connect to mail server: {}", e));
-            }
+        let now = chrono::Utc::now().timestamp();
+        let due: Vec<DeferredMail> = self
+            .storage
+            .get_deferred_mails(user)
+            .await?
+            .into_iter()
+            .filter(|deferred| deferred.send_after <= now)
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+        let due: Vec<&MailRef> = due.iter().map(|deferred| &deferred.mail).collect();
+        let here: Vec<&MailRef> = due
+            .iter()
+            .copied()
+            .filter(|mail| mail.account == account.email)
+            .collect();
+        let changes = match here.is_empty() {
+            true => vec![],
+            false => source.verify(&self.storage, user, &here).await?,
         };
 
-        let mut session = match client.login(email, password).map_err(|e| e.0) {
-            Ok(session) => session,
+        let (cancelled, confirmed): (Vec<&MailRef>, Vec<&MailRef>) = due
+            .iter()
+            .copied()
+            .partition(|mail| changes.iter().any(|(changed, _)| changed == *mail));
+        if !cancelled.is_empty() {
+            tracing::info!(
+                "Cancelling {} due notifications of user {}, the mail was read or removed",
+                cancelled.len(),
+                user.id
+            );
+        }
+        self.storage.cancel_mails(user, &cancelled).await?;
+        self.storage.confirm_mails(user, &confirmed).await?;
+        self.storage.remove_deferred_mails(user, &due).await?;
+        Ok(())
+    }
+
+    /// Whether deferred notifications of the user wait to be looked up before they are sent
+    async fn has_due_mails(&self, user: &WebAppUser) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        match self.storage.get_deferred_mails(user).await {
+            Ok(deferred) => deferred.iter().any(|deferred| deferred.send_after <= now),
             Err(e) => {
-                return Err(anyhow!("Could not login into {}: {}", email, e));
+                tracing::error!("Failed to get deferred mails of user {}: {}", user.id, e);
+                false
             }
-        };
+        }
+    }
 
-        let importance_checker = ImportanceChecker::new(&*self.storage, user).await;
-        tracing::debug!(
-            "ImportanceChecker for user {} was built: {:?}",
-            user.id,
-            importance_checker
-        );
+    async fn send_task(&self, task: TelegramMessageTask) -> anyhow::Result<()> {
+        if let Err(e) = self.broker.publish(Tasks::TelegramMessageTask(task)).await {
+            return Err(anyhow!(e));
+        }
+        Ok(())
+    }
 
-        let folders = session.list(None, Some("INBOX*"))?;
-        for folder in folders.iter() {
-            let _mailbox = session.select(folder.name())?;
-            let unseen = session.search("UNSEEN")?;
+    /// Sends service message about the mailbox state right away, ignoring working hours.
+    /// `key` is the message in the catalog, it is sent in the user language
+    async fn notify(&self, user: &WebAppUser, key: &str, args: &[(&str, &(dyn Display + Sync))]) {
+        let lang = self
+            .storage
+            .get_user_language(user)
+            .await
+            .unwrap_or_default();
+        let mut text = escape(&i18n::trf(lang, key, args));
+        if let Some(url) = &self.web_app_url {
+            let settings = escape(i18n::tr(lang, "settings.link"));
+            text = format!("{}\n{}", text, link(url, &settings));
+        }
 
-            if unseen.len() == 0 {
-                continue;
-            }
+        let task = TelegramMessageTask {
+            to: UserId(user.id as u64),
+            text,
+            send_after: chrono::Utc::now(),
+            important: true,
+            chat: None,
+            silent: false,
+            mail: None,
+            recheck: false,
+        };
+        if let Err(e) = self.send_task(task).await {
+            tracing::error!("Failed to notify user {}: {}", user.id, e);
+        }
+    }
 
-            let available_uids = Vec::from_iter(unseen.iter());
-            let to_fetch_uids = self
-                .storage
-                .filter_unprocessed(user, available_uids.as_slice())
-                .await?;
+    /// Problem of the account behind the error, `None` for errors of the service itself
+    /// (broker, storage) which must not postpone checks or be reported to the user.
+    /// Servers which let the user in but then fail or answer nonsense are `Other`
+    fn classify_error(e: &anyhow::Error) -> Option<MailboxProblem> {
+        if let Some(error) = e.downcast_ref::<Error>() {
+            return match error {
+                Error::MailCheckerError(MailCheckerError::LoginFailed(_)) => {
+                    Some(MailboxProblem::AuthFailed)
+                }
+                Error::MailCheckerError(MailCheckerError::ConnectionFailed(_)) => {
+                    Some(MailboxProblem::Unreachable)
+                }
+                Error::MailCheckerError(_) => Some(MailboxProblem::Other),
+                _ => None,
+            };
+        }
+        // Raised by mail sources as they are, the service wraps its own errors into `Error`
+        if let Some(error) = e.downcast_ref::<reqwest::Error>() {
+            return match error.is_connect() || error.is_timeout() {
+                true => Some(MailboxProblem::Unreachable),
+                false => Some(MailboxProblem::Other),
+            };
+        }
+        if let Some(error) = e.downcast_ref::<::imap::error::Error>() {
+            return match error {
+                ::imap::error::Error::Io(_) | ::imap::error::Error::ConnectionLost => {
+                    Some(MailboxProblem::Unreachable)
+                }
+                _ => Some(MailboxProblem::Other),
+            };
+        }
+        if e.downcast_ref::<std::io::Error>().is_some() {
+            return Some(MailboxProblem::Unreachable);
+        }
+        None
+    }
 
-            let to_fetch = format!(
-                "{}",
-                Vec::from_iter(to_fetch_uids.iter().map(|x| x.to_string())).join(",")
-            );
-            tracing::debug!("User: \"{}\" To fetch {}", user.id, to_fetch);
+    /// Message telling the user about the problem, it takes the account `email`
+    fn describe_problem(problem: MailboxProblem) -> &'static str {
+        match problem {
+            MailboxProblem::AuthFailed => "problem.auth_failed",
+            MailboxProblem::Unreachable => "problem.unreachable",
+            MailboxProblem::Other => "problem.other",
+        }
+    }
 
-            let fetched = session.fetch(to_fetch, "ENVELOPE")?;
-            for message in fetched.iter() {
-                self.process_message(message, user, &importance_checker)
+    async fn record_result(
+        &self,
+        user: &WebAppUser,
+        account: &MailAccount,
+        mut health: MailboxHealth,
+        result: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        match result {
+            Ok(()) => {
+                self.storage
+                    .set_last_check(user, chrono::Utc::now().timestamp())
                     .await?;
+                if health.is_failing() {
+                    if health.notified {
+                        self.notify(user, "notify.working_again", &[("email", &account.email)])
+                            .await;
+                    }
+                    self.storage.reset_mailbox_health(user).await?;
+                }
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                if let Some(problem) = Checker::classify_error(&e) {
+                    health.record_failure(problem);
+                    if !health.notified && health.failures >= NOTIFY_AFTER_FAILURES {
+                        let key = Checker::describe_problem(problem);
+                        self.notify(user, key, &[("email", &account.email)]).await;
+                        health.notified = true;
+                    }
+                    self.storage.set_mailbox_health(user, &health).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_account(
+        &self,
+        user: &WebAppUser,
+        account: &MailAccount,
+    ) -> anyhow::Result<()> {
+        let mut source = sources::connect(account, &self.mail_cfg).await?;
+
+        let settings = self.notify_settings(user).await?;
+
+        let mails = source.fetch_new(&self.storage, user).await?;
+        let now = chrono::Utc::now();
+        let mut tasks = Vec::with_capacity(mails.len());
+        let mut deferred = Vec::new();
+        for mail in mails.iter() {
+            let mut task = self.build_task(mail, user, account, &settings).await?;
+            if let (Some(mail), Some(until)) = (task.mail.clone(), task.deferred_until()) {
+                if until > now {
+                    deferred.push(DeferredMail {
+                        mail,
+                        send_after: until.timestamp(),
+                    });
+                    task.recheck = true;
+                }
+            }
+            tasks.push(Tasks::TelegramMessageTask(task));
+        }
+        if !tasks.is_empty() {
+            if let Err(e) = self.broker.publish_batch(tasks).await {
+                // Mails stay unprocessed and are published again on the next check
+                if let Some(BrokerError::Busy(reason)) = e.downcast_ref::<BrokerError>() {
+                    tracing::warn!("Mails of user {} are put off: {}", user.id, reason);
+                    source.logout().await?;
+                    return Ok(());
+                }
+                return Err(e);
             }
+        }
+        source
+            .mark_processed(&self.storage, user, mails.as_slice())
+            .await?;
+        // Untracked ones are held by the bot for a while and delivered even if read meanwhile
+        if let Err(e) = self.storage.add_deferred_mails(user, &deferred).await {
+            tracing::warn!("Failed to track deferred mails of user {}: {}", user.id, e);
+        }
+        if let Err(e) = self.recheck_due(user, account, &mut source).await {
+            tracing::warn!("Failed to recheck due mails of user {}: {}", user.id, e);
+        }
 
-            self.storage
-                .add_processed_mails(user, to_fetch_uids.as_slice())
-                .await?
+        // Stale notifications are cosmetic, the check does not fail because of them
+        if let Err(e) = self.track_changes(user, account, &mut source).await {
+            tracing::warn!("Failed to update notifications of user {}: {}", user.id, e);
         }
 
-        session.logout()?;
+        source.logout().await?;
+
+        if account.protocol == MailProtocol::Jmap && !self.push.is_watching(user).await {
+            let client = sources::connect_jmap(account, &self.mail_cfg).await?;
+            self.push.watch(user, client).await;
+        }
 
         Ok(())
     }
 
+    pub async fn check_user(&self, user: &WebAppUser) {
+        let account = match self.storage.get_mail_account(user, &self.cipher).await {
+            Ok(account) => account,
+            Err(e) => {
+                tracing::error!("{}", e);
+                return;
+            }
+        };
+
+        if account.is_none() {
+            tracing::error!("There is no valid mail account for user {}", user.id);
+            if let Err(e) = self
+                .storage
+                .disable_checking(user)
+                .await
+                .with_context(|| "Failed to disable checking")
+            {
+                tracing::error!("{}", e);
+                return;
+            }
+            self.notify(user, "notify.no_account", &[]).await;
+            return;
+        }
+
+        let account = account.unwrap();
+        let health = match self.storage.get_mailbox_health(user).await {
+            Ok(health) => health,
+            Err(e) => {
+                tracing::error!("{}", e);
+                MailboxHealth::default()
+            }
+        };
+        if !health.can_check_now() {
+            return;
+        }
+
+        let result = self.process_account(user, &account).await;
+        if let Err(e) = self.record_result(user, &account, health, result).await {
+            tracing::error!("{}", e);
+        }
+    }
+
+    pub async fn check_on_push(&self, user: &WebAppUser) {
+        match self.storage.is_checking_enabled(user).await {
+            Ok(true) => self.check_user(user).await,
+            Ok(false) => self.push.unwatch(user).await,
+            Err(e) => tracing::error!("{}", e),
+        }
+    }
+
     pub async fn check_on_cron(&self) {
         let users = self.storage.get_users_for_checking().await;
 
         if let Ok(users) = &users {
             for user in users {
-                let account = match self.storage.get_mail_account(user, &self.cipher).await {
-                    Ok(account) => account,
-                    Err(e) => {
-                        tracing::error!("{}", e);
-                        continue;
-                    }
-                };
-
-                if account.is_none() {
-                    tracing::error!("There is no valid mail account for user {}", user.id);
-                    if let Err(e) = self
-                        .storage
-                        .disable_checking(user)
-                        .await
-                        .with_context(|| "Failed to disable checking")
-                    {
-                        tracing::error!("{}", e);
-                    }
+                // Pushed accounts are checked on changes, due notifications can not wait for one
+                if self.push.is_watching(user).await && !self.has_due_mails(user).await {
                     continue;
                 }
-
-                let account = account.unwrap();
-                if let Err(e) = self.process_account(user, &account).await {
-                    tracing::error!("{}", e);
-                }
+                self.check_user(user).await;
             }
         } else {
             tracing::error!("{}", users.unwrap_err());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_account_errors_are_mailbox_problems() {
+        let login = anyhow!(Error::MailCheckerError(MailCheckerError::LoginFailed(
+            "user@example.com".into()
+        )));
+        assert_eq!(Checker::classify_error(&login), Some(MailboxProblem::AuthFailed));
+
+        let connection = anyhow!(Error::MailCheckerError(MailCheckerError::ConnectionFailed(
+            "TLS handshake failed".into()
+        )));
+        assert_eq!(
+            Checker::classify_error(&connection),
+            Some(MailboxProblem::Unreachable)
+        );
+
+        let timeout = anyhow!(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert_eq!(
+            Checker::classify_error(&timeout),
+            Some(MailboxProblem::Unreachable)
+        );
+
+        let pop3 = anyhow!(Error::MailCheckerError(MailCheckerError::Pop3Error(
+            "-ERR maildrop locked".into()
+        )));
+        assert_eq!(Checker::classify_error(&pop3), Some(MailboxProblem::Other));
+
+        let imap = anyhow!(::imap::error::Error::Bad("internal server error".into()));
+        assert_eq!(Checker::classify_error(&imap), Some(MailboxProblem::Other));
+
+        let broker = anyhow!(BrokerError::Busy("high lane is full".into()));
+        assert_eq!(Checker::classify_error(&broker), None);
+
+        let storage = anyhow!(Error::IoError(std::io::Error::other("redis is down")));
+        assert_eq!(Checker::classify_error(&storage), None);
+    }
+}
@@ -1,5 +1,7 @@
 mod cfg;
 mod checker;
+mod push;
+mod sources;
 
 use anyhow::{anyhow, Context};
 use clokwerk::TimeUnits;
@@ -13,9 +15,11 @@ use checker::Checker;
 use common::cfg::build_config;
 use common::ctrlc_handler::set_ctrlc_handler;
 use common::heartbeat::HeartbeatService;
+use common::queues::BrokerClient;
 use common::storage::Storage;
 
 use crate::cfg::MailCheckerCfg;
+use crate::push::{CheckRequest, PushWatchers};
 
 async fn main_impl() -> anyhow::Result<()> {
     let running = Arc::new(AtomicBool::new(true));
@@ -25,6 +29,8 @@ async fn main_impl() -> anyhow::Result<()> {
     let cfg = Arc::new(build_config::<MailCheckerCfg>()?);
 
     let storage: Pin<Arc<Storage>> = Arc::pin(Storage::new(&cfg.storage).await?);
+    // One connection to the broker serves every check
+    let broker = BrokerClient::new(cfg.broker.clone(), Storage::clone(&storage))?;
 
     let heartbeat_service = HeartbeatService::new("MAIL_CHECKER".into(), storage);
     heartbeat_service.run();
@@ -36,8 +42,13 @@ async fn main_impl() -> anyhow::Result<()> {
 
     let mut scheduler = clokwerk::AsyncScheduler::with_tz(moscow_offset);
 
-    async fn task(cfg: Arc<MailCheckerCfg>) {
-        let checker = Checker::new(&cfg)
+    async fn task(
+        cfg: Arc<MailCheckerCfg>,
+        broker: BrokerClient,
+        push: Arc<PushWatchers>,
+        request: CheckRequest,
+    ) {
+        let checker = Checker::new(&cfg, broker, push)
             .await
             .with_context(|| "Cound not create checker");
         let checker = match checker {
@@ -47,32 +58,41 @@ async fn main_impl() -> anyhow::Result<()> {
                 return;
             }
         };
-        checker.check_on_cron().await;
+        match request {
+            CheckRequest::All => checker.check_on_cron().await,
+            CheckRequest::User(user) => checker.check_on_push(&user).await,
+        }
     }
-    let (tx, rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (tx, rx) = tokio::sync::mpsc::channel::<CheckRequest>(16);
+    let push = Arc::new(PushWatchers::new(tx.clone()));
 
-    async fn emit_task(tx: tokio::sync::mpsc::Sender<()>) {
-        if let Err(e) = tx.send(()).await {
+    async fn emit_task(tx: tokio::sync::mpsc::Sender<CheckRequest>) {
+        if let Err(e) = tx.send(CheckRequest::All).await {
             tracing::error!("tx.send() finished with error: {}", e);
         }
     }
 
     async fn receive_task(
-        mut rx: tokio::sync::mpsc::Receiver<()>,
+        mut rx: tokio::sync::mpsc::Receiver<CheckRequest>,
         running: Arc<AtomicBool>,
         cfg: Arc<MailCheckerCfg>,
+        broker: BrokerClient,
+        push: Arc<PushWatchers>,
     ) {
         while running.load(Ordering::Relaxed) {
-            match rx.recv().await {
-                Some(_) => {
-                    task(cfg.clone()).await;
-                }
-                None => {}
+            if let Some(request) = rx.recv().await {
+                task(cfg.clone(), broker.clone(), push.clone(), request).await;
             }
         }
     }
 
-    let handle = tokio::spawn(receive_task(rx, running.clone(), cfg.clone()));
+    let handle = tokio::spawn(receive_task(
+        rx,
+        running.clone(),
+        cfg.clone(),
+        broker,
+        push,
+    ));
 
     let tx = tx.clone();
     scheduler
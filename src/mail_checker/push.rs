@@ -0,0 +1,85 @@
+use futures::StreamExt;
+use reqwest_eventsource::Event;
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use common::sessions::WebAppUser;
+
+use crate::sources::JmapClient;
+
+#[derive(Debug)]
+pub enum CheckRequest {
+    /// Scheduled check of every user with enabled checking
+    All,
+    /// Check of a single user whose mailbox reported changes
+    User(WebAppUser),
+}
+
+/// Keeps JMAP EventSource connections open and requests a check when mailbox state changes
+pub struct PushWatchers {
+    watchers: Mutex<HashMap<i64, JoinHandle<()>>>,
+    tx: Sender<CheckRequest>,
+}
+
+impl PushWatchers {
+    pub fn new(tx: Sender<CheckRequest>) -> PushWatchers {
+        PushWatchers {
+            watchers: Default::default(),
+            tx,
+        }
+    }
+
+    /// Returns true while push connection for the user is alive, so polling may be skipped
+    pub async fn is_watching(&self, user: &WebAppUser) -> bool {
+        match self.watchers.lock().await.get(&user.id) {
+            Some(handle) => !handle.is_finished(),
+            None => false,
+        }
+    }
+
+    pub async fn watch(&self, user: &WebAppUser, client: JmapClient) {
+        let user_id = user.id;
+        let tx = self.tx.clone();
+        let handle = tokio::spawn(async move {
+            let mut event_source = match client.event_source() {
+                Ok(event_source) => event_source,
+                Err(e) => {
+                    tracing::error!("Could not open JMAP push for user {}: {}", user_id, e);
+                    return;
+                }
+            };
+
+            while let Some(event) = event_source.next().await {
+                match event {
+                    Ok(Event::Open) => {
+                        tracing::debug!("JMAP push for user {} connected", user_id);
+                    }
+                    Ok(Event::Message(message)) if message.event == "state" => {
+                        if let Err(e) = tx.send(CheckRequest::User(user_id.into())).await {
+                            tracing::error!("tx.send() finished with error: {}", e);
+                            break;
+                        }
+                    }
+                    Ok(Event::Message(_)) => {}
+                    Err(e) => {
+                        tracing::warn!("JMAP push for user {} closed: {}", user_id, e);
+                        break;
+                    }
+                }
+            }
+            event_source.close();
+        });
+
+        if let Some(previous) = self.watchers.lock().await.insert(user_id, handle) {
+            previous.abort();
+        }
+    }
+
+    pub async fn unwatch(&self, user: &WebAppUser) {
+        if let Some(handle) = self.watchers.lock().await.remove(&user.id) {
+            handle.abort();
+        }
+    }
+}
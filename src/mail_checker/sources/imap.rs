@@ -0,0 +1,316 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rustls_connector::TlsStream;
+use rustyknife::rfc2047::encoded_word;
+use std::collections::HashMap;
+use std::iter::FromIterator;
+use std::net::TcpStream;
+
+use ::imap::types::Flag;
+use common::cfg::ServerTlsCfg;
+use common::sessions::WebAppUser;
+use common::storage::{MailAccount, MailChange, MailProtocol, MailRef, Storage};
+use common::types::{Error, MailCheckerError};
+
+use super::{IncomingMail, MailSource};
+
+pub struct ImapSource {
+    session: ::imap::Session<TlsStream<TcpStream>>,
+    /// Login of the account, references to the mails carry it
+    account: String,
+}
+
+impl ImapSource {
+    pub fn connect(
+        host: &str,
+        port: u16,
+        tls: &ServerTlsCfg,
+        allow_private: bool,
+        account: &MailAccount,
+    ) -> anyhow::Result<ImapSource> {
+        let stream = common::tls::connect(host, port, MailProtocol::Imap, tls, allow_private);
+        let client = match stream {
+            Ok(stream) => ::imap::Client::new(stream),
+            Err(e) => {
+                let error = MailCheckerError::ConnectionFailed(e.to_string());
+                return Err(anyhow!(Error::MailCheckerError(error)));
+            }
+        };
+
+        let session = match client
+            .login(&account.email, &account.password)
+            .map_err(|e| e.0)
+        {
+            Ok(session) => session,
+            Err(e) => {
+                let error = MailCheckerError::LoginFailed(format!("{}: {}", account.email, e));
+                return Err(anyhow!(Error::MailCheckerError(error)));
+            }
+        };
+
+        Ok(ImapSource {
+            session,
+            account: account.email.clone(),
+        })
+    }
+
+    fn decode_value(data: Option<&[u8]>) -> Option<String> {
+        if let Some(data) = data {
+            let value = String::from_utf8_lossy(data).into_owned();
+            let data_owner = value.clone();
+            let data = data_owner.as_bytes();
+            let (_, value) = encoded_word(data).unwrap_or((&[], value));
+            return Some(value);
+        }
+        None
+    }
+
+    /// Looks the mails up by UID. If `cached` and the server has CONDSTORE, folders whose
+    /// HIGHESTMODSEQ did not move since the last time are skipped without selecting them
+    async fn look_up(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[&MailRef],
+        cached: bool,
+    ) -> anyhow::Result<Vec<(MailRef, MailChange)>> {
+        let condstore = cached && self.session.capabilities()?.has_str("CONDSTORE");
+        let mut folders: HashMap<&str, Vec<&MailRef>> = HashMap::new();
+        for mail in mails {
+            folders.entry(mail.folder.as_str()).or_default().push(mail);
+        }
+
+        let mut changes = vec![];
+        for (folder, mails) in folders {
+            let modseq = match condstore {
+                true => self.highest_modseq(folder).unwrap_or_else(|e| {
+                    tracing::warn!("Could not get HIGHESTMODSEQ of {}: {}", folder, e);
+                    None
+                }),
+                false => None,
+            };
+            if modseq.is_some() && storage.get_folder_modseq(user, folder).await? == modseq {
+                continue;
+            }
+
+            let mailbox = self.session.examine(folder)?;
+            // UIDs of another UIDVALIDITY point to other mails, these are left to expire
+            let mails: Vec<(&MailRef, u32)> = mails
+                .into_iter()
+                .filter(|mail| mail.uid_validity == mailbox.uid_validity)
+                .filter_map(|mail| mail.id.parse().ok().map(|uid| (mail, uid)))
+                .collect();
+            if !mails.is_empty() {
+                let uids: Vec<String> = mails.iter().map(|(_, uid)| uid.to_string()).collect();
+                let fetched = self.session.uid_fetch(uids.join(","), "(UID FLAGS)")?;
+                let mut present: HashMap<u32, &[Flag]> = HashMap::new();
+                for message in fetched.iter() {
+                    if let Some(uid) = message.uid {
+                        present.insert(uid, message.flags());
+                    }
+                }
+                for (mail, uid) in mails {
+                    if let Some(change) = ImapSource::change_of(present.get(&uid).copied()) {
+                        changes.push((mail.clone(), change));
+                    }
+                }
+            }
+
+            if let Some(modseq) = modseq {
+                storage.set_folder_modseq(user, folder, modseq).await?;
+            }
+        }
+        Ok(changes)
+    }
+
+    /// What happened to the mail going by its flags, `None` flags mean it was expunged
+    fn change_of(flags: Option<&[Flag]>) -> Option<MailChange> {
+        match flags {
+            None => Some(MailChange::Gone),
+            Some(flags) if flags.contains(&Flag::Deleted) => Some(MailChange::Gone),
+            Some(flags) if flags.contains(&Flag::Seen) => Some(MailChange::Seen),
+            Some(_) => None,
+        }
+    }
+
+    fn parse_message(
+        account: &str,
+        folder: &str,
+        uid_validity: Option<u32>,
+        message: &::imap::types::Fetch,
+    ) -> anyhow::Result<IncomingMail> {
+        let envelope = message.envelope();
+        if envelope.is_none() {
+            let error = Error::MailCheckerError(MailCheckerError::EmptyEnvelope);
+            return Err(anyhow!(error));
+        }
+        let envelope = envelope.unwrap();
+
+        let mut from_addr: Option<&[u8]> = None;
+        let mut host: Option<&[u8]> = None;
+        let mut mailbox: Option<&[u8]> = None;
+
+        if let Some(addresses) = &envelope.from.as_ref() {
+            if !addresses.is_empty() {
+                from_addr = addresses[0].name;
+                host = addresses[0].host;
+                mailbox = addresses[0].mailbox;
+            }
+        }
+
+        let email = format!(
+            "{}@{}",
+            String::from_utf8_lossy(mailbox.unwrap_or("nobody".as_bytes())),
+            String::from_utf8_lossy(host.unwrap_or("nowhere".as_bytes()))
+        );
+
+        Ok(IncomingMail {
+            id: message.message.to_string(),
+            folder: folder.to_owned(),
+            from: ImapSource::decode_value(from_addr),
+            email,
+            subject: ImapSource::decode_value(envelope.subject),
+            snippet: None,
+            date: message.internal_date().map(|date| date.to_utc()),
+            reference: message.uid.map(|uid| MailRef {
+                account: account.to_owned(),
+                folder: folder.to_owned(),
+                id: uid.to_string(),
+                uid_validity,
+            }),
+        })
+    }
+
+    fn quote(folder: &str) -> String {
+        format!("\"{}\"", folder.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    /// HIGHESTMODSEQ of the folder, CONDSTORE extension must be supported.
+    /// The imap crate does not parse it, so the response is read as is
+    fn highest_modseq(&mut self, folder: &str) -> anyhow::Result<Option<u64>> {
+        let response = self.session.run_command_and_read_response(format!(
+            "STATUS {} (HIGHESTMODSEQ)",
+            ImapSource::quote(folder)
+        ))?;
+        let response = String::from_utf8_lossy(&response);
+        let modseq = response
+            .split("HIGHESTMODSEQ ")
+            .nth(1)
+            .map(|rest| {
+                rest.chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect::<String>()
+            })
+            .and_then(|digits| digits.parse().ok());
+        Ok(modseq)
+    }
+}
+
+#[async_trait]
+impl MailSource for ImapSource {
+    async fn fetch_new(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+    ) -> anyhow::Result<Vec<IncomingMail>> {
+        let mut mails = Vec::new();
+
+        let folders: Vec<String> = self
+            .session
+            .list(None, Some("INBOX*"))?
+            .iter()
+            .map(|folder| folder.name().to_owned())
+            .collect();
+
+        for folder in folders.iter() {
+            let mailbox = self.session.select(folder)?;
+            let unseen = self.session.search("UNSEEN")?;
+
+            if unseen.is_empty() {
+                continue;
+            }
+
+            let available_uids = Vec::from_iter(unseen.iter());
+            let to_fetch_uids = storage
+                .filter_unprocessed(user, available_uids.as_slice())
+                .await?;
+
+            if to_fetch_uids.is_empty() {
+                continue;
+            }
+
+            let to_fetch = Vec::from_iter(to_fetch_uids.iter().map(|x| x.to_string())).join(",");
+            tracing::debug!("User: \"{}\" To fetch {}", user.id, to_fetch);
+
+            let fetched = self
+                .session
+                .fetch(to_fetch, "(UID ENVELOPE INTERNALDATE)")?;
+            for message in fetched.iter() {
+                mails.push(ImapSource::parse_message(
+                    &self.account,
+                    folder,
+                    mailbox.uid_validity,
+                    message,
+                )?);
+            }
+        }
+
+        Ok(mails)
+    }
+
+    async fn mark_processed(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[IncomingMail],
+    ) -> anyhow::Result<()> {
+        let uids: Vec<u32> = mails
+            .iter()
+            .filter_map(|mail| mail.id.parse::<u32>().ok())
+            .collect();
+        storage.add_processed_mails(user, uids.as_slice()).await
+    }
+
+    async fn changed(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[&MailRef],
+    ) -> anyhow::Result<Vec<(MailRef, MailChange)>> {
+        self.look_up(storage, user, mails, true).await
+    }
+
+    async fn verify(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[&MailRef],
+    ) -> anyhow::Result<Vec<(MailRef, MailChange)>> {
+        self.look_up(storage, user, mails, false).await
+    }
+
+    async fn logout(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.session.logout()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_tell_what_happened_to_the_mail() {
+        assert_eq!(ImapSource::change_of(None), Some(MailChange::Gone));
+        assert_eq!(ImapSource::change_of(Some(&[])), None);
+        assert_eq!(
+            ImapSource::change_of(Some(&[Flag::Answered, Flag::Seen])),
+            Some(MailChange::Seen)
+        );
+        assert_eq!(
+            ImapSource::change_of(Some(&[Flag::Seen, Flag::Deleted])),
+            Some(MailChange::Gone)
+        );
+        assert_eq!(ImapSource::change_of(Some(&[Flag::Flagged])), None);
+    }
+}
@@ -0,0 +1,670 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use reqwest_eventsource::EventSource;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::sessions::WebAppUser;
+use common::storage::{MailAccount, MailChange, MailRef, Storage};
+use common::types::{Error, MailCheckerError};
+
+use super::{IncomingMail, MailSource};
+
+const CORE_CAPABILITY: &str = "urn:ietf:params:jmap:core";
+const MAIL_CAPABILITY: &str = "urn:ietf:params:jmap:mail";
+const ID_NAMESPACE: &str = "JMAP_EMAIL";
+const INBOX: &str = "INBOX";
+const FETCH_LIMIT: u32 = 100;
+const HTTPS_PORT: u16 = 443;
+/// A stalled server must not hold up checks of other users. Requests are limited one by one,
+/// push channel stays open for as long as the server keeps it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JmapSession {
+    api_url: String,
+    event_source_url: String,
+    primary_accounts: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct JmapAddress {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JmapEmail {
+    id: String,
+    from: Option<Vec<JmapAddress>>,
+    subject: Option<String>,
+    preview: Option<String>,
+    #[serde(rename = "receivedAt")]
+    received_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Clone)]
+pub struct JmapClient {
+    http: reqwest::Client,
+    email: String,
+    password: String,
+    api_url: String,
+    event_source_url: String,
+    account_id: String,
+}
+
+impl JmapClient {
+    /// Performs session discovery through `/.well-known/jmap` of `base_url`. Its host may resolve
+    /// to internal addresses only if `allow_private`. API and push URLs of the session on other
+    /// servers must resolve to public ones, the credentials are sent there
+    pub async fn discover(
+        base_url: &str,
+        account: &MailAccount,
+        allow_private: bool,
+    ) -> anyhow::Result<JmapClient> {
+        let base = reqwest::Url::parse(base_url)?;
+        let mut pinned = vec![JmapClient::pin(&base, allow_private).await?];
+        let session: JmapSession = JmapClient::http(&pinned)?
+            .get(format!("{}/.well-known/jmap", base_url.trim_end_matches('/')))
+            .timeout(REQUEST_TIMEOUT)
+            .basic_auth(&account.email, Some(&account.password))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        for url in [&session.api_url, &session.event_source_url] {
+            let url = reqwest::Url::parse(url)?;
+            if url.origin() != base.origin() {
+                pinned.push(JmapClient::pin(&url, false).await?);
+            }
+        }
+        let http = JmapClient::http(&pinned)?;
+
+        let account_id = session
+            .primary_accounts
+            .get(MAIL_CAPABILITY)
+            .cloned()
+            .ok_or(anyhow!("JMAP server has no mail account for {}", account.email))?;
+
+        Ok(JmapClient {
+            http,
+            email: account.email.clone(),
+            password: account.password.clone(),
+            api_url: session.api_url,
+            event_source_url: session.event_source_url,
+            account_id,
+        })
+    }
+
+    /// Resolves host of the URL once, requests go to that address so it can not be rebound
+    async fn pin(url: &reqwest::Url, allow_private: bool) -> anyhow::Result<(String, SocketAddr)> {
+        let host = url
+            .host_str()
+            .ok_or(anyhow!("{} has no host", url))?
+            .to_owned();
+        let port = url.port_or_known_default().unwrap_or(HTTPS_PORT);
+        let resolved = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let address = tokio::task::spawn_blocking(move || {
+            common::tls::resolve(&resolved, port, allow_private)
+        })
+        .await??;
+        Ok((host, address))
+    }
+
+    fn http(pinned: &[(String, SocketAddr)]) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            // Redirects would lead past the pinned addresses
+            .redirect(reqwest::redirect::Policy::none());
+        for (host, address) in pinned {
+            builder = builder.resolve(host, *address);
+        }
+        Ok(builder.build()?)
+    }
+
+    async fn call(&self, method_calls: Value) -> anyhow::Result<Vec<Value>> {
+        let request = json!({
+            "using": [CORE_CAPABILITY, MAIL_CAPABILITY],
+            "methodCalls": method_calls,
+        });
+        let response: Value = self
+            .http
+            .post(&self.api_url)
+            .timeout(REQUEST_TIMEOUT)
+            .basic_auth(&self.email, Some(&self.password))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let responses = response["methodResponses"]
+            .as_array()
+            .cloned()
+            .ok_or(anyhow!("JMAP response has no `methodResponses`"))?;
+
+        let mut results = Vec::new();
+        for response in responses {
+            if response[0] == "error" {
+                let error = Error::MailCheckerError(MailCheckerError::JmapError(
+                    response[1]["type"].as_str().unwrap_or("unknown").to_owned(),
+                ));
+                return Err(anyhow!(error));
+            }
+            results.push(response[1].clone());
+        }
+        Ok(results)
+    }
+
+    async fn inbox_id(&self) -> anyhow::Result<String> {
+        let responses = self
+            .call(json!([[
+                "Mailbox/query",
+                { "accountId": self.account_id, "filter": { "role": "inbox" } },
+                "0"
+            ]]))
+            .await?;
+        responses
+            .first()
+            .and_then(|response| response["ids"][0].as_str())
+            .map(|id| id.to_owned())
+            .ok_or(anyhow!("JMAP account {} has no inbox", self.email))
+    }
+
+    /// Newest unseen emails of the inbox and whether these are all of them
+    async fn unseen_emails(&self, inbox_id: &str) -> anyhow::Result<(Vec<JmapEmail>, bool)> {
+        let responses = self
+            .call(json!([
+                [
+                    "Email/query",
+                    {
+                        "accountId": self.account_id,
+                        "filter": { "inMailbox": inbox_id, "notKeyword": "$seen" },
+                        "sort": [{ "property": "receivedAt", "isAscending": false }],
+                        "limit": FETCH_LIMIT,
+                        "calculateTotal": true
+                    },
+                    "0"
+                ],
+                [
+                    "Email/get",
+                    {
+                        "accountId": self.account_id,
+                        "#ids": { "resultOf": "0", "name": "Email/query", "path": "/ids" },
+                        "properties": ["id", "from", "subject", "preview", "receivedAt"]
+                    },
+                    "1"
+                ]
+            ]))
+            .await?;
+
+        let list = responses
+            .get(1)
+            .map(|response| response["list"].clone())
+            .unwrap_or(Value::Array(vec![]));
+        let emails: Vec<JmapEmail> = serde_json::from_value(list)?;
+        // Servers may not calculate the total, then only a short page is known to be complete
+        let complete = match responses.first().and_then(|query| query["total"].as_u64()) {
+            Some(total) => total <= FETCH_LIMIT as u64,
+            None => emails.len() < FETCH_LIMIT as usize,
+        };
+        Ok((emails, complete))
+    }
+
+    /// Seen state of the emails, `None` for the ones which do not exist anymore
+    async fn seen_states(&self, ids: &[&str]) -> anyhow::Result<HashMap<String, Option<bool>>> {
+        let responses = self
+            .call(json!([[
+                "Email/get",
+                {
+                    "accountId": self.account_id,
+                    "ids": ids,
+                    "properties": ["id", "keywords"]
+                },
+                "0"
+            ]]))
+            .await?;
+
+        let mut states = HashMap::new();
+        if let Some(response) = responses.first() {
+            for email in response["list"].as_array().cloned().unwrap_or_default() {
+                if let Some(id) = email["id"].as_str() {
+                    let seen = email["keywords"]["$seen"].as_bool().unwrap_or(false);
+                    states.insert(id.to_owned(), Some(seen));
+                }
+            }
+            for id in response["notFound"].as_array().cloned().unwrap_or_default() {
+                if let Some(id) = id.as_str() {
+                    states.insert(id.to_owned(), None);
+                }
+            }
+        }
+        Ok(states)
+    }
+
+    /// Opens push channel which emits `state` events when emails of the account change
+    pub fn event_source(&self) -> anyhow::Result<EventSource> {
+        let url = self
+            .event_source_url
+            .replace("{types}", "Email")
+            .replace("{closeafter}", "no")
+            .replace("{ping}", "60");
+        let request = self
+            .http
+            .get(url)
+            .basic_auth(&self.email, Some(&self.password));
+        Ok(EventSource::new(request)?)
+    }
+}
+
+pub struct JmapSource {
+    client: JmapClient,
+}
+
+impl JmapSource {
+    pub fn new(client: JmapClient) -> JmapSource {
+        JmapSource { client }
+    }
+}
+
+#[async_trait]
+impl MailSource for JmapSource {
+    async fn fetch_new(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+    ) -> anyhow::Result<Vec<IncomingMail>> {
+        let inbox_id = self.client.inbox_id().await?;
+        let (emails, complete) = self.client.unseen_emails(&inbox_id).await?;
+        let present: Vec<String> = emails.iter().map(|email| email.id.clone()).collect();
+
+        // Ids past the fetched page are still unseen, forgotten they would be notified again
+        if complete {
+            storage
+                .forget_missing_ids(user, ID_NAMESPACE, present.as_slice())
+                .await?;
+        }
+        let unknown = storage
+            .filter_unknown_ids(user, ID_NAMESPACE, present.as_slice())
+            .await?;
+        tracing::debug!("User: \"{}\" To fetch {:?}", user.id, unknown);
+
+        let mails = emails
+            .into_iter()
+            .filter(|email| unknown.contains(&email.id))
+            .map(|email| {
+                let sender = email.from.and_then(|from| from.into_iter().next());
+                let (from, address) = match sender {
+                    Some(sender) => (sender.name, sender.email),
+                    None => (None, None),
+                };
+                IncomingMail {
+                    reference: Some(MailRef {
+                        account: self.client.email.clone(),
+                        folder: INBOX.to_owned(),
+                        id: email.id.clone(),
+                        uid_validity: None,
+                    }),
+                    id: email.id,
+                    folder: INBOX.to_owned(),
+                    from: from.filter(|name| !name.is_empty()),
+                    email: address.unwrap_or("nobody@nowhere".into()),
+                    subject: email.subject,
+                    snippet: email.preview.filter(|preview| !preview.is_empty()),
+                    date: email.received_at,
+                }
+            })
+            .collect();
+
+        Ok(mails)
+    }
+
+    async fn mark_processed(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[IncomingMail],
+    ) -> anyhow::Result<()> {
+        let ids: Vec<String> = mails.iter().map(|mail| mail.id.clone()).collect();
+        storage
+            .add_known_ids(user, ID_NAMESPACE, ids.as_slice())
+            .await
+    }
+
+    async fn changed(
+        &mut self,
+        _storage: &Storage,
+        _user: &WebAppUser,
+        mails: &[&MailRef],
+    ) -> anyhow::Result<Vec<(MailRef, MailChange)>> {
+        let ids: Vec<&str> = mails.iter().map(|mail| mail.id.as_str()).collect();
+        let states = self.client.seen_states(ids.as_slice()).await?;
+        let changes = mails
+            .iter()
+            .filter_map(|mail| match states.get(&mail.id) {
+                Some(None) => Some(((*mail).clone(), MailChange::Gone)),
+                Some(Some(true)) => Some(((*mail).clone(), MailChange::Seen)),
+                _ => None,
+            })
+            .collect();
+        Ok(changes)
+    }
+
+    async fn logout(self: Box<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{Query, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::sse::{Event, Sse};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use common::storage::{MailAccount, MailProtocol};
+    use common::types::{Error, NetworkError};
+
+    use super::JmapClient;
+    use crate::push::{CheckRequest, PushWatchers};
+
+    const EMAIL: &str = "user@example.com";
+    const PASSWORD: &str = "secret";
+    // base64 of "user@example.com:secret"
+    const AUTHORIZATION: &str = "Basic dXNlckBleGFtcGxlLmNvbTpzZWNyZXQ=";
+
+    #[derive(Clone)]
+    struct MockJmap {
+        base_url: String,
+        /// Where the session points API calls, the mock itself if not set
+        api_base: Option<String>,
+        requests: Arc<Mutex<Vec<Value>>>,
+        event_queries: Arc<Mutex<Vec<HashMap<String, String>>>>,
+    }
+
+    fn authorized(headers: &HeaderMap) -> bool {
+        headers
+            .get("authorization")
+            .is_some_and(|value| value == AUTHORIZATION)
+    }
+
+    async fn session(State(mock): State<MockJmap>, headers: HeaderMap) -> Response {
+        if !authorized(&headers) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        Json(json!({
+            "apiUrl": format!("{}/api", mock.api_base.as_ref().unwrap_or(&mock.base_url)),
+            "eventSourceUrl": format!(
+                "{}/events?types={{types}}&closeafter={{closeafter}}&ping={{ping}}",
+                mock.base_url
+            ),
+            "primaryAccounts": {
+                "urn:ietf:params:jmap:core": "acc1",
+                "urn:ietf:params:jmap:mail": "acc1",
+            },
+        }))
+        .into_response()
+    }
+
+    async fn api(
+        State(mock): State<MockJmap>,
+        headers: HeaderMap,
+        Json(request): Json<Value>,
+    ) -> Response {
+        if !authorized(&headers) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        mock.requests.lock().unwrap().push(request.clone());
+
+        let mut responses = vec![];
+        for call in request["methodCalls"].as_array().unwrap() {
+            let (method, args, id) = (call[0].as_str().unwrap(), &call[1], &call[2]);
+            let response = match method {
+                "Mailbox/query" => json!(["Mailbox/query", { "ids": ["mb-inbox"] }, id]),
+                "Email/query" => json!(["Email/query", { "ids": ["e1", "e2"], "total": 2 }, id]),
+                "Email/get" if args["#ids"]["resultOf"] == "0" => json!([
+                    "Email/get",
+                    {
+                        "list": [
+                            {
+                                "id": "e1",
+                                "from": [{ "name": "Alice", "email": "alice@example.com" }],
+                                "subject": "Hello",
+                                "preview": "How are you?",
+                                "receivedAt": "2024-04-01T06:30:00Z"
+                            },
+                            { "id": "e2", "from": null, "subject": null, "preview": "" }
+                        ]
+                    },
+                    id
+                ]),
+                "Email/get" => json!([
+                    "Email/get",
+                    {
+                        "list": [{ "id": "e1", "keywords": { "$seen": true } }],
+                        "notFound": ["e3"]
+                    },
+                    id
+                ]),
+                _ => json!(["error", { "type": "unknownMethod" }, id]),
+            };
+            responses.push(response);
+        }
+        Json(json!({ "methodResponses": responses })).into_response()
+    }
+
+    async fn events(
+        State(mock): State<MockJmap>,
+        headers: HeaderMap,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        if !authorized(&headers) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        mock.event_queries.lock().unwrap().push(query);
+        let stream = futures::stream::iter(vec![Ok::<_, Infallible>(
+            Event::default()
+                .event("state")
+                .data(r#"{"@type":"StateChange","changed":{"acc1":{"Email":"s2"}}}"#),
+        )])
+        .chain(futures::stream::pending());
+        Sse::new(stream).into_response()
+    }
+
+    async fn start_mock() -> MockJmap {
+        start_mock_with_api(None).await
+    }
+
+    async fn start_mock_with_api(api_base: Option<&str>) -> MockJmap {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mock = MockJmap {
+            base_url: format!("http://{}", listener.local_addr().unwrap()),
+            api_base: api_base.map(str::to_owned),
+            requests: Default::default(),
+            event_queries: Default::default(),
+        };
+        let app = Router::new()
+            .route("/.well-known/jmap", get(session))
+            .route("/api", post(api))
+            .route("/events", get(events))
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        mock
+    }
+
+    fn account(password: &str) -> MailAccount {
+        MailAccount {
+            email: EMAIL.to_owned(),
+            password: password.to_owned(),
+            protocol: MailProtocol::Jmap,
+            host: None,
+            port: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn discovers_session() {
+        let mock = start_mock().await;
+        let client = JmapClient::discover(&format!("{}/", mock.base_url), &account(PASSWORD), true)
+            .await
+            .unwrap();
+        assert_eq!(client.account_id, "acc1");
+        assert_eq!(client.api_url, format!("{}/api", mock.base_url));
+    }
+
+    #[tokio::test]
+    async fn api_on_another_server_must_be_public() {
+        // Trusted as the configured server, the mock still may not send API calls elsewhere
+        let mock = start_mock_with_api(Some("http://127.0.0.1:9")).await;
+        let error = JmapClient::discover(&mock.base_url, &account(PASSWORD), true)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::NetworkError(NetworkError::PrivateAddress(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn discovery_fails_with_wrong_password() {
+        let mock = start_mock().await;
+        let error = JmapClient::discover(&mock.base_url, &account("wrong"), true)
+            .await
+            .err()
+            .unwrap();
+        let status = error
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status());
+        assert_eq!(status, Some(reqwest::StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn queries_unseen_emails_of_inbox() {
+        let mock = start_mock().await;
+        let client = JmapClient::discover(&mock.base_url, &account(PASSWORD), true)
+            .await
+            .unwrap();
+
+        let inbox_id = client.inbox_id().await.unwrap();
+        assert_eq!(inbox_id, "mb-inbox");
+
+        let (emails, complete) = client.unseen_emails(&inbox_id).await.unwrap();
+        assert!(complete);
+        assert_eq!(emails.len(), 2);
+        let sender = &emails[0].from.as_ref().unwrap()[0];
+        assert_eq!(sender.name.as_deref(), Some("Alice"));
+        assert_eq!(sender.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(emails[0].subject.as_deref(), Some("Hello"));
+        assert_eq!(emails[0].preview.as_deref(), Some("How are you?"));
+        let received = emails[0].received_at.unwrap();
+        assert_eq!(received.to_rfc3339(), "2024-04-01T06:30:00+00:00");
+        assert!(emails[1].received_at.is_none());
+        assert!(emails[1].from.is_none());
+
+        let requests = mock.requests.lock().unwrap();
+        let calls = &requests.last().unwrap()["methodCalls"];
+        assert_eq!(calls[0][0], "Email/query");
+        assert_eq!(calls[0][1]["accountId"], "acc1");
+        assert_eq!(calls[0][1]["filter"]["inMailbox"], "mb-inbox");
+        assert_eq!(calls[0][1]["filter"]["notKeyword"], "$seen");
+        assert_eq!(calls[0][1]["calculateTotal"], true);
+        assert_eq!(calls[1][0], "Email/get");
+        assert_eq!(calls[1][1]["#ids"]["resultOf"], "0");
+        assert_eq!(calls[1][1]["#ids"]["path"], "/ids");
+    }
+
+    #[tokio::test]
+    async fn reads_seen_states() {
+        let mock = start_mock().await;
+        let client = JmapClient::discover(&mock.base_url, &account(PASSWORD), true)
+            .await
+            .unwrap();
+
+        let states = client.seen_states(&["e1", "e3"]).await.unwrap();
+        assert_eq!(states.get("e1"), Some(&Some(true)));
+        assert_eq!(states.get("e3"), Some(&None));
+    }
+
+    #[tokio::test]
+    async fn reports_method_errors() {
+        let mock = start_mock().await;
+        let client = JmapClient::discover(&mock.base_url, &account(PASSWORD), true)
+            .await
+            .unwrap();
+
+        let error = client
+            .call(json!([["Thread/get", {}, "0"]]))
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("unknownMethod"));
+    }
+
+    #[tokio::test]
+    async fn receives_push_state_changes() {
+        let mock = start_mock().await;
+        let client = JmapClient::discover(&mock.base_url, &account(PASSWORD), true)
+            .await
+            .unwrap();
+
+        let mut event_source = client.event_source().unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(event) = event_source.next().await {
+                if let reqwest_eventsource::Event::Message(message) = event.unwrap() {
+                    return message;
+                }
+            }
+            panic!("Event source closed without messages");
+        })
+        .await
+        .unwrap();
+        event_source.close();
+        assert_eq!(message.event, "state");
+
+        let queries = mock.event_queries.lock().unwrap();
+        assert_eq!(queries[0].get("types").map(String::as_str), Some("Email"));
+        assert_eq!(queries[0].get("closeafter").map(String::as_str), Some("no"));
+    }
+
+    #[tokio::test]
+    async fn push_watcher_requests_user_check() {
+        let mock = start_mock().await;
+        let client = JmapClient::discover(&mock.base_url, &account(PASSWORD), true)
+            .await
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let watchers = PushWatchers::new(tx);
+        watchers.watch(&42.into(), client).await;
+
+        let request = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap();
+        match request {
+            Some(CheckRequest::User(user)) => assert_eq!(user.id, 42),
+            other => panic!("Unexpected request {:?}", other),
+        }
+        assert!(watchers.is_watching(&42.into()).await);
+        watchers.unwatch(&42.into()).await;
+    }
+}
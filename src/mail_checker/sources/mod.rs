@@ -0,0 +1,113 @@
+mod imap;
+mod jmap;
+mod pop3;
+
+use async_trait::async_trait;
+use common::cfg::MailCfg;
+use common::sessions::WebAppUser;
+use common::storage::{MailAccount, MailChange, MailProtocol, MailRef, Storage};
+use common::types::{Error, MailCheckerError};
+
+pub use self::imap::ImapSource;
+pub use self::jmap::{JmapClient, JmapSource};
+pub use self::pop3::Pop3Source;
+
+/// Message which was not reported to the user yet
+#[derive(Debug, Clone)]
+pub struct IncomingMail {
+    /// Source specific identifier used for deduplication
+    pub id: String,
+    pub folder: String,
+    pub from: Option<String>,
+    pub email: String,
+    pub subject: Option<String>,
+    /// Beginning of the text, for sources which give it without fetching the body
+    pub snippet: Option<String>,
+    /// When the mail was received, `None` if the source does not tell
+    pub date: Option<chrono::DateTime<chrono::Utc>>,
+    /// Where the mail is found again, `None` for sources which can not look it up
+    pub reference: Option<MailRef>,
+}
+
+#[async_trait]
+pub trait MailSource: Send {
+    /// Returns messages which were not marked as processed yet
+    async fn fetch_new(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+    ) -> anyhow::Result<Vec<IncomingMail>>;
+
+    /// Remembers messages so that following `fetch_new` calls skip them
+    async fn mark_processed(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[IncomingMail],
+    ) -> anyhow::Result<()>;
+
+    /// Finds which of the mails were read or removed since, sources which can not tell report nothing
+    async fn changed(
+        &mut self,
+        _storage: &Storage,
+        _user: &WebAppUser,
+        _mails: &[&MailRef],
+    ) -> anyhow::Result<Vec<(MailRef, MailChange)>> {
+        Ok(vec![])
+    }
+
+    /// Like `changed`, but looks every mail up in the mailbox right now instead of relying on
+    /// what is remembered from earlier lookups. Done right before a deferred notification is sent
+    async fn verify(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[&MailRef],
+    ) -> anyhow::Result<Vec<(MailRef, MailChange)>> {
+        self.changed(storage, user, mails).await
+    }
+
+    async fn logout(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+/// Discovers JMAP session of the account
+pub async fn connect_jmap(account: &MailAccount, cfg: &MailCfg) -> anyhow::Result<JmapClient> {
+    let base_url = account.jmap_base_url(cfg);
+    let trusted = reqwest::Url::parse(&base_url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| cfg.is_trusted_host(host)))
+        .unwrap_or(false);
+    JmapClient::discover(&base_url, account, trusted).await
+}
+
+pub async fn connect(
+    account: &MailAccount,
+    cfg: &MailCfg,
+) -> anyhow::Result<Box<dyn MailSource>> {
+    let (host, port) = account.server(cfg);
+    let tls = cfg.tls_for(&host);
+    let trusted = cfg.is_trusted_host(&host);
+    match account.protocol {
+        MailProtocol::Imap => Ok(Box::new(ImapSource::connect(
+            &host, port, tls, trusted, account,
+        )?)),
+        MailProtocol::Pop3 => Ok(Box::new(Pop3Source::connect(
+            &host, port, tls, trusted, account,
+        )?)),
+        MailProtocol::Jmap => {
+            let client = connect_jmap(account, cfg).await.map_err(|e| {
+                let status = e.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+                let error = match status {
+                    Some(reqwest::StatusCode::UNAUTHORIZED) => {
+                        MailCheckerError::LoginFailed(format!("{}: {}", account.email, e))
+                    }
+                    // The server answered, it just failed to give the session
+                    Some(_) => MailCheckerError::JmapError(e.to_string()),
+                    None => MailCheckerError::ConnectionFailed(e.to_string()),
+                };
+                anyhow::anyhow!(Error::MailCheckerError(error))
+            })?;
+            Ok(Box::new(JmapSource::new(client)))
+        }
+    }
+}
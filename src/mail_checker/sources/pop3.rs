@@ -0,0 +1,258 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rustls_connector::TlsStream;
+use rustyknife::behaviour::Intl;
+use rustyknife::headersection::header_section;
+use rustyknife::rfc5322::{from, unstructured, Address};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+use common::cfg::{ServerTlsCfg, TlsMode};
+use common::sessions::WebAppUser;
+use common::storage::{MailAccount, MailProtocol, Storage};
+use common::types::{Error, MailCheckerError};
+
+use super::{IncomingMail, MailSource};
+
+/// POP3 has no folders, every message lives in the maildrop
+const MAILDROP: &str = "INBOX";
+const UIDL_NAMESPACE: &str = "POP3_UIDL";
+
+pub struct Pop3Source {
+    stream: BufReader<TlsStream<TcpStream>>,
+}
+
+impl Pop3Source {
+    pub fn connect(
+        host: &str,
+        port: u16,
+        tls: &ServerTlsCfg,
+        allow_private: bool,
+        account: &MailAccount,
+    ) -> anyhow::Result<Pop3Source> {
+        let stream = common::tls::connect(host, port, MailProtocol::Pop3, tls, allow_private);
+        let mut source = match stream {
+            Ok(stream) => Pop3Source {
+                stream: BufReader::new(stream),
+            },
+            Err(e) => {
+                let error = MailCheckerError::ConnectionFailed(e.to_string());
+                return Err(anyhow!(Error::MailCheckerError(error)));
+            }
+        };
+
+        // With STLS the greeting was consumed before the handshake
+        if tls.mode == TlsMode::Implicit {
+            if let Err(e) = source.read_status() {
+                let error = MailCheckerError::ConnectionFailed(e.to_string());
+                return Err(anyhow!(Error::MailCheckerError(error)));
+            }
+        }
+
+        let login = source
+            .command(&format!("USER {}", account.email))
+            .and_then(|_| source.command(&format!("PASS {}", account.password)));
+        if let Err(e) = login {
+            let error = MailCheckerError::LoginFailed(format!("{}: {}", account.email, e));
+            return Err(anyhow!(Error::MailCheckerError(error)));
+        }
+
+        Ok(source)
+    }
+
+    fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(anyhow!("Connection closed by POP3 server"));
+        }
+        Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    }
+
+    fn read_status(&mut self) -> anyhow::Result<String> {
+        let line = self.read_line()?;
+        if let Some(status) = line.strip_prefix("+OK") {
+            return Ok(status.trim().to_owned());
+        }
+        let error = Error::MailCheckerError(MailCheckerError::Pop3Error(line));
+        Err(anyhow!(error))
+    }
+
+    /// Reads multi-line response body until the terminating dot, undoing dot-stuffing
+    fn read_multiline(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            if line == "." {
+                break;
+            }
+            match line.strip_prefix('.') {
+                Some(unstuffed) => lines.push(unstuffed.to_owned()),
+                None => lines.push(line),
+            }
+        }
+        Ok(lines)
+    }
+
+    fn command(&mut self, command: &str) -> anyhow::Result<String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.read_status()
+    }
+
+    /// Returns pairs of message number and its unique id
+    fn uidl(&mut self) -> anyhow::Result<Vec<(u32, String)>> {
+        self.command("UIDL")?;
+        let mut uidls = Vec::new();
+        for line in self.read_multiline()? {
+            let mut parts = line.split_whitespace();
+            if let (Some(number), Some(uidl)) = (parts.next(), parts.next()) {
+                uidls.push((number.parse::<u32>()?, uidl.to_owned()));
+            }
+        }
+        Ok(uidls)
+    }
+
+    fn headers(&mut self, number: u32) -> anyhow::Result<Vec<u8>> {
+        self.command(&format!("TOP {} 0", number))?;
+        let mut headers = self.read_multiline()?.join("\r\n");
+        headers.push_str("\r\n\r\n");
+        Ok(headers.into_bytes())
+    }
+
+    /// RFC 5322 date, a trailing comment like `(UTC)` is ignored
+    fn parse_date(value: &[u8]) -> Option<chrono::DateTime<chrono::Utc>> {
+        let value = String::from_utf8_lossy(value);
+        let value = value.trim();
+        let value = match value.ends_with(')') {
+            true => value
+                .rsplit_once('(')
+                .map_or(value, |(date, _)| date.trim_end()),
+            false => value,
+        };
+        chrono::DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.to_utc())
+    }
+
+    fn parse_message(uidl: &str, headers: &[u8]) -> IncomingMail {
+        let mut from_name: Option<String> = None;
+        let mut email = String::from("nobody@nowhere");
+        let mut subject: Option<String> = None;
+        let mut date: Option<chrono::DateTime<chrono::Utc>> = None;
+
+        let (_, fields) = header_section(headers).unwrap_or((&[], vec![]));
+        for (name, value) in fields.into_iter().filter_map(|field| field.ok()) {
+            let mut value = value.to_vec();
+            value.extend_from_slice(b"\r\n");
+
+            if name.eq_ignore_ascii_case(b"From") {
+                if let Ok((_, addresses)) = from::<Intl>(&value) {
+                    if let Some(Address::Mailbox(mailbox)) = addresses.into_iter().next() {
+                        from_name = mailbox.dname;
+                        email = mailbox.address.to_string();
+                    }
+                }
+            } else if name.eq_ignore_ascii_case(b"Subject") {
+                subject = unstructured::<Intl>(&value)
+                    .map(|(_, subject)| subject.trim().to_owned())
+                    .ok();
+            } else if name.eq_ignore_ascii_case(b"Date") {
+                date = Pop3Source::parse_date(&value);
+            }
+        }
+
+        IncomingMail {
+            id: uidl.to_owned(),
+            folder: MAILDROP.to_owned(),
+            from: from_name,
+            email,
+            subject,
+            snippet: None,
+            date,
+            // Seen state is not kept by POP3, so mail can not be looked up again: notifications
+            // are not updated and deferred ones are sent even if the mail was read meanwhile
+            reference: None,
+        }
+    }
+}
+
+#[async_trait]
+impl MailSource for Pop3Source {
+    async fn fetch_new(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+    ) -> anyhow::Result<Vec<IncomingMail>> {
+        let listing = self.uidl()?;
+        let present: Vec<String> = listing.iter().map(|(_, uidl)| uidl.clone()).collect();
+
+        // Mail stored before the account was added is not new, only what arrives after is notified
+        if !storage.is_synced(user, UIDL_NAMESPACE).await? {
+            tracing::debug!(
+                "User: \"{}\" First sync, {} mails known",
+                user.id,
+                present.len()
+            );
+            storage
+                .add_known_ids(user, UIDL_NAMESPACE, present.as_slice())
+                .await?;
+            storage.mark_synced(user, UIDL_NAMESPACE).await?;
+            return Ok(vec![]);
+        }
+        storage
+            .forget_missing_ids(user, UIDL_NAMESPACE, present.as_slice())
+            .await?;
+        let unknown = storage
+            .filter_unknown_ids(user, UIDL_NAMESPACE, present.as_slice())
+            .await?;
+        tracing::debug!("User: \"{}\" To fetch {:?}", user.id, unknown);
+
+        let mut mails = Vec::new();
+        for (number, uidl) in listing.iter().filter(|(_, uidl)| unknown.contains(uidl)) {
+            let headers = self.headers(*number)?;
+            mails.push(Pop3Source::parse_message(uidl, &headers));
+        }
+
+        Ok(mails)
+    }
+
+    async fn mark_processed(
+        &mut self,
+        storage: &Storage,
+        user: &WebAppUser,
+        mails: &[IncomingMail],
+    ) -> anyhow::Result<()> {
+        let uidls: Vec<String> = mails.iter().map(|mail| mail.id.clone()).collect();
+        storage
+            .add_known_ids(user, UIDL_NAMESPACE, uidls.as_slice())
+            .await
+    }
+
+    async fn logout(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.command("QUIT")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headers() {
+        let headers = b"From: Jane Doe <jane@example.com>\r\n\
+                        Subject: Report\r\n\
+                        Date: Mon, 1 Apr 2024 09:30:00 +0300 (MSK)\r\n\r\n";
+        let mail = Pop3Source::parse_message("uid-1", headers);
+        assert_eq!(mail.id, "uid-1");
+        assert_eq!(mail.from.as_deref(), Some("Jane Doe"));
+        assert_eq!(mail.email, "jane@example.com");
+        assert_eq!(mail.subject.as_deref(), Some("Report"));
+        assert_eq!(mail.date.unwrap().to_rfc3339(), "2024-04-01T06:30:00+00:00");
+
+        let mail = Pop3Source::parse_message("uid-2", b"Date: yesterday\r\n\r\n");
+        assert!(mail.date.is_none());
+    }
+}
@@ -1,12 +1,26 @@
-use axum::{extract::Extension, response::IntoResponse, routing::get, Json, Router};
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 use common::{
-    storage::{Storage, Cipher},
+    mail_diagnostics::{diagnose, ConnectionReport},
+    storage::{Storage, Cipher, MailAccount, MailProtocol},
     types::Result, sessions::WebAppUser,
 };
 
+use crate::cfg::WebServerCfg;
+
+const CONNECTION_TEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+/// Connection tests a user may run per window, each one opens connections to a host of their choice
+const CONNECTION_TESTS_PER_WINDOW: i64 = 10;
+const CONNECTION_TEST_WINDOW_SECONDS: i64 = 600;
+
 async fn get_account_settings(
     user: WebAppUser,
     Extension(storage): Extension<Arc<Storage>>,
@@ -20,21 +34,84 @@ async fn get_account_settings(
 struct SetAccountParams {
     pub email: String,
     pub password: String,
+    #[serde(default)]
+    pub protocol: MailProtocol,
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+impl From<SetAccountParams> for MailAccount {
+    fn from(params: SetAccountParams) -> Self {
+        MailAccount {
+            email: params.email,
+            password: params.password,
+            protocol: params.protocol,
+            host: params.host,
+            port: params.port,
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
 struct SetAccountResponse {
     changed: bool,
+    connection: ConnectionReport,
+}
+
+async fn test_account(account: MailAccount, cfg: &WebServerCfg) -> ConnectionReport {
+    diagnose(account, &cfg.mail, CONNECTION_TEST_TIMEOUT).await
+}
+
+async fn connection_tests_exhausted(user: &WebAppUser, storage: &Storage) -> Result<bool> {
+    let count = storage
+        .count_connection_test(user, CONNECTION_TEST_WINDOW_SECONDS)
+        .await?;
+    Ok(count > CONNECTION_TESTS_PER_WINDOW)
+}
+
+async fn test_account_settings(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+    Extension(cfg): Extension<Arc<WebServerCfg>>,
+    Json(params): Json<SetAccountParams>,
+) -> Result<Response> {
+    if connection_tests_exhausted(&user, &storage).await? {
+        return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
+    }
+    let report = test_account(params.into(), &cfg).await;
+    Ok(Json(report).into_response())
 }
 
 async fn set_account_settings(
     user: WebAppUser,
     Extension(storage): Extension<Arc<Storage>>,
     Extension(cipher): Extension<Arc<Cipher>>,
+    Extension(cfg): Extension<Arc<WebServerCfg>>,
     Json(params): Json<SetAccountParams>,
-) -> Result<impl IntoResponse> {
-    storage.set_mail_account(&user, &params.email, &params.password, &cipher).await?;
-    Ok(Json(SetAccountResponse { changed: true }))
+) -> Result<Response> {
+    if connection_tests_exhausted(&user, &storage).await? {
+        return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
+    }
+    let account: MailAccount = params.into();
+    let report = test_account(account.clone(), &cfg).await;
+
+    if !report.ok {
+        let response = SetAccountResponse {
+            changed: false,
+            connection: report,
+        };
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(response)).into_response());
+    }
+
+    storage.set_mail_account(&user, account, &cipher).await?;
+    storage.reset_mailbox_health(&user).await?;
+    let response = SetAccountResponse {
+        changed: true,
+        connection: report,
+    };
+    Ok(Json(response).into_response())
 }
 
 async fn get_checking_state(
@@ -56,9 +133,9 @@ async fn set_checking(
     Json(params): Json<SetCheckingParams>,
 ) -> Result<impl IntoResponse> {
     if params.state {
-        let _ = storage.enable_checking(&user).await?;
+        storage.enable_checking(&user).await?;
     } else {
-        let _ = storage.disable_checking(&user).await?;
+        storage.disable_checking(&user).await?;
     }
     Ok(())
 }
@@ -69,5 +146,6 @@ pub fn account_routes() -> Router {
             "/account",
             get(get_account_settings).post(set_account_settings),
         )
+        .route("/account/test", post(test_account_settings))
         .route("/checking", get(get_checking_state).post(set_checking))
 }
@@ -34,17 +34,14 @@ async fn auth(
     init_data.validate(bot_token)
         .map_err(|e| Error::InternalError(common::types::InternalError::RuntimeError(format!("`init_data` is not valid: {}", e))))?;
 
-    let ref user = match init_data.user {
+    let user = match init_data.user {
         Some(user) => user,
         _ => return Err(Error::InternalError(common::types::InternalError::RuntimeError("no user in `init_data`".into())))
     };
 
-    match sm.auth_v2(user).await {
-        Err(e) => {
-            return Err(Error::InternalError(common::types::InternalError::RuntimeError(format!("failed to auth_v2: {}", e))))
-        },
-        _ => {}
-    };
+    if let Err(e) = sm.auth_v2(&user).await {
+        return Err(Error::InternalError(common::types::InternalError::RuntimeError(format!("failed to auth_v2: {}", e))))
+    }
 
     Ok(())
 }
@@ -6,6 +6,7 @@ pub struct WebServerCfg {
     pub web: WebCfg,
     pub storage: StorageCfg,
     pub bot: BotCfg,
+    pub mail: MailCfg,
 }
 
 impl TryFrom<Config> for WebServerCfg {
@@ -15,6 +16,12 @@ impl TryFrom<Config> for WebServerCfg {
         let web = WebCfg::try_from(&cfg)?;
         let storage = StorageCfg::try_from(&cfg)?;
         let bot = BotCfg::try_from(&cfg)?;
-        Ok(WebServerCfg { web, storage, bot })
+        let mail = MailCfg::try_from(&cfg)?;
+        Ok(WebServerCfg {
+            web,
+            storage,
+            bot,
+            mail,
+        })
     }
 }
@@ -0,0 +1,40 @@
+use axum::{
+    extract::Extension,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use common::sessions::WebAppUser;
+use common::storage::{ChatRoutes, RouteScope, Storage};
+use common::types::Result;
+
+#[derive(Debug, Deserialize)]
+struct UnlinkParams {
+    pub scope: RouteScope,
+}
+
+/// Chats the mail is routed to, they are linked with /link sent by the user in the chat
+async fn get_chat_routes(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+) -> Result<Json<ChatRoutes>> {
+    let routes = storage.get_chat_routes(&user).await?;
+    Ok(Json(routes))
+}
+
+async fn unlink_chat(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(params): Json<UnlinkParams>,
+) -> Result<()> {
+    storage.set_chat_route(&user, params.scope, None).await?;
+    Ok(())
+}
+
+pub fn chat_route_routes() -> Router {
+    Router::new()
+        .route("/chat_routes", get(get_chat_routes))
+        .route("/chat_routes/unlink", post(unlink_chat))
+}
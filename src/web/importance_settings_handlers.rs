@@ -23,8 +23,7 @@ async fn get_important_tags(
 ) -> Result<impl IntoResponse> {
     let tags: Vec<String> = storage
         .get_important_tags(&user).await
-        .unwrap_or(vec![])
-        .into();
+        .unwrap_or(vec![]);
     Ok(Json(tags))
 }
 
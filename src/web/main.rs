@@ -1,11 +1,14 @@
 mod account_handlers;
 mod auth_handlers;
+mod chat_route_handlers;
 mod cfg;
 mod healthcheck_handlers;
 mod heartbeat_handlers;
 mod importance_settings_handlers;
+mod notification_template_handlers;
 mod notify_settings_handlers;
 mod server;
+mod telegram_webhook_handlers;
 
 use anyhow::Result;
 use axum::Extension;
@@ -31,10 +34,10 @@ async fn main_impl() -> Result<()> {
 
     let sql = SQLMigration::get("pg_init.sql").expect("There is no pg migration file");
     storage
-        .migrate_pg(&std::str::from_utf8(sql.data.as_ref())?)
+        .migrate_pg(std::str::from_utf8(sql.data.as_ref())?)
         .await?;
 
-    let (router, address) = init_server_instance(&cfg.web).await;
+    let (router, address) = init_server_instance(&cfg).await;
     let app = router
         .layer(Extension(storage))
         .layer(Extension(cipher))
@@ -0,0 +1,81 @@
+use axum::{
+    extract::Extension,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use common::sessions::WebAppUser;
+use common::storage::Storage;
+use common::templates::{MailFields, Preset, Template, PRESETS};
+use common::types::Result;
+
+#[derive(Debug, Deserialize)]
+struct TemplateParams {
+    pub template: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Preview {
+    /// MarkdownV2 the sample mail is rendered to, as it is sent
+    pub text: Option<String>,
+    pub error: Option<String>,
+}
+
+async fn get_notification_template(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+) -> Result<Json<String>> {
+    let template = storage
+        .get_notification_template(&user)
+        .await?
+        .unwrap_or(PRESETS[0].template.into());
+    Ok(Json(template))
+}
+
+/// Saves the template if it is valid, an empty one brings the default preset back
+async fn set_notification_template(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(params): Json<TemplateParams>,
+) -> Result<()> {
+    let template = match params.template.trim().is_empty() {
+        true => None,
+        false => {
+            Template::compile(&params.template)?;
+            Some(params.template.as_str())
+        }
+    };
+    storage.set_notification_template(&user, template).await?;
+    Ok(())
+}
+
+async fn get_presets() -> Json<&'static [Preset]> {
+    Json(PRESETS)
+}
+
+/// Renders a sample mail with the template being edited, not saving it
+async fn preview(_user: WebAppUser, Json(params): Json<TemplateParams>) -> Json<Preview> {
+    let preview = match Template::compile(&params.template) {
+        Ok(template) => Preview {
+            text: Some(template.render(&MailFields::sample())),
+            error: None,
+        },
+        Err(e) => Preview {
+            text: None,
+            error: Some(e.to_string()),
+        },
+    };
+    Json(preview)
+}
+
+pub fn notification_template_routes() -> Router {
+    Router::new()
+        .route(
+            "/notification_template",
+            get(get_notification_template).post(set_notification_template),
+        )
+        .route("/notification_template/presets", get(get_presets))
+        .route("/notification_template/preview", post(preview))
+}
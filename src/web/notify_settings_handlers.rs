@@ -1,8 +1,15 @@
 use std::sync::Arc;
-use axum::{extract::Extension, routing::get, Json, Router};
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
 
 use common::{
-    storage::Storage,
+    i18n::Language,
+    storage::{DeliverySettings, Storage},
     types::Result, sessions::WebAppUser,
 };
 
@@ -23,9 +30,79 @@ async fn set_working_hours(
     Ok(())
 }
 
+/// Offset of the user time zone, in minutes east of UTC
+async fn get_utc_offset(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+) -> Result<Json<i32>> {
+    let offset = storage.get_user_utc_offset(&user).await?;
+    Ok(Json(offset.local_minus_utc() / 60))
+}
+
+async fn set_utc_offset(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(minutes): Json<i32>,
+) -> Result<Response> {
+    // Time zones in use range from UTC-12:00 to UTC+14:00
+    if !(-12 * 60..=14 * 60).contains(&minutes) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    storage.set_user_utc_offset(&user, minutes).await?;
+    Ok(().into_response())
+}
+
+/// Language chosen by the user, `null` if it follows the Telegram app
+async fn get_language(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+) -> Result<Json<Option<Language>>> {
+    let res = storage.get_language_override(&user).await?;
+    Ok(Json(res))
+}
+
+async fn set_language(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(language): Json<Option<Language>>,
+) -> Result<()> {
+    storage.set_language_override(&user, language).await?;
+    Ok(())
+}
+
+async fn get_delivery_settings(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+) -> Result<Json<DeliverySettings>> {
+    let res = storage.get_delivery_settings(&user).await?;
+    Ok(Json(res))
+}
+
+/// Silent delivery of the mail account, it must be set up first
+async fn set_delivery_settings(
+    user: WebAppUser,
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(settings): Json<DeliverySettings>,
+) -> Result<Response> {
+    if !settings.is_valid() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    if !storage.set_delivery_settings(&user, &settings).await? {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+    Ok(().into_response())
+}
+
 pub fn notify_settings_routes() -> Router {
-    Router::new().route(
-        "/working_hours",
-        get(get_working_hours).post(set_working_hours),
-    )
+    Router::new()
+        .route(
+            "/working_hours",
+            get(get_working_hours).post(set_working_hours),
+        )
+        .route("/utc_offset", get(get_utc_offset).post(set_utc_offset))
+        .route("/language", get(get_language).post(set_language))
+        .route(
+            "/delivery_settings",
+            get(get_delivery_settings).post(set_delivery_settings),
+        )
 }
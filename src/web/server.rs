@@ -2,30 +2,39 @@ use axum::{
     routing::{get_service, MethodRouter},
     Router,
 };
-use common::cfg::WebCfg;
+use common::cfg::WebhookMode;
 use tower_http::services::ServeDir;
 
 use crate::account_handlers::account_routes;
 use crate::auth_handlers::auth_routes;
+use crate::cfg::WebServerCfg;
+use crate::chat_route_handlers::chat_route_routes;
 use crate::healthcheck_handlers::heartbeat_handlers as healthcheck_handlers;
 use crate::heartbeat_handlers::heartbeat_handlers;
 use crate::importance_settings_handlers::importance_settings_routes;
+use crate::notification_template_handlers::notification_template_routes;
 use crate::notify_settings_handlers::notify_settings_routes;
+use crate::telegram_webhook_handlers::telegram_webhook_routes;
 
-pub async fn init_server_instance(cfg: &WebCfg) -> (axum::Router, std::net::SocketAddr) {
-    let static_service: MethodRouter = get_service(ServeDir::new(&cfg.static_path));
+pub async fn init_server_instance(cfg: &WebServerCfg) -> (axum::Router, std::net::SocketAddr) {
+    let static_service: MethodRouter = get_service(ServeDir::new(&cfg.web.static_path));
 
     let api_router = Router::new()
         .merge(heartbeat_handlers())
         .merge(account_routes())
         .merge(notify_settings_routes())
         .merge(importance_settings_routes())
+        .merge(notification_template_routes())
+        .merge(chat_route_routes())
         .merge(healthcheck_handlers());
 
-    let router = Router::new()
-        .merge(auth_routes())
-        .nest("/api", api_router)
-        .fallback_service(static_service);
+    let mut router = Router::new().merge(auth_routes()).nest("/api", api_router);
+    if let Some(webhook) = &cfg.bot.webhook {
+        if webhook.mode == WebhookMode::WebServer {
+            router = router.merge(telegram_webhook_routes(webhook));
+        }
+    }
+    let router = router.fallback_service(static_service);
 
-    (router, cfg.address.clone())
+    (router, cfg.web.address)
 }